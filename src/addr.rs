@@ -24,7 +24,13 @@ pub type GuestPhysAddrRange = AddrRange<GuestPhysAddr>;
 
 #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
 impl page_table_multiarch::riscv::SvVirtAddr for GuestPhysAddr {
-    fn flush_tlb(_vaddr: Option<Self>) {
-        todo!()
+    fn flush_tlb(vaddr: Option<Self>) {
+        use crate::npt::{NestedTlbFlush, flush_nested_tlb};
+
+        // The VMID of the table is not known here, all VMIDs are invalidated.
+        flush_nested_tlb(match vaddr {
+            Some(gpa) => NestedTlbFlush::Page { context: None, gpa },
+            None => NestedTlbFlush::All,
+        });
     }
 }
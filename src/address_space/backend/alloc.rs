@@ -1,14 +1,56 @@
-use memory_addr::{PageIter4K, PhysAddr};
-use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError, PagingHandler};
 
-use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use super::{Backend, split_huge_page};
+use crate::{
+    AxMmHal, FaultAreaInfo, GuestPhysAddr, GuestPhysAddrRange, HostPhysAddr, NestedFormat,
+    PageFaultOutcome, npt::NestedPageTable as PageTable,
+};
 
-impl<H: PagingHandler> Backend<H> {
+/// The allocator of the contiguous frames backing huge pages.
+///
+/// It is obtained from the [`AxMmHal`] implementation, see
+/// [`AxMmHal::alloc_contiguous_frames`].
+#[derive(Clone, Copy)]
+pub struct HugeFrameAllocator {
+    page_size: PageSize,
+    alloc: fn(usize, usize) -> Option<HostPhysAddr>,
+    dealloc: fn(HostPhysAddr, usize),
+}
+
+impl HugeFrameAllocator {
+    /// Creates an allocator of huge pages up to `page_size` from the
+    /// [`AxMmHal`] implementation `H`.
+    pub fn new<H: AxMmHal>(page_size: PageSize) -> Self {
+        Self {
+            page_size,
+            alloc: H::alloc_contiguous_frames,
+            dealloc: H::dealloc_contiguous_frames,
+        }
+    }
+
+    /// Returns the largest page size that is allocated.
+    pub const fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+}
+
+impl<H: PagingHandler, F: NestedFormat> Backend<H, F> {
     /// Creates a new allocation mapping backend.
     pub const fn new_alloc(populate: bool) -> Self {
         Self::Alloc {
             populate,
+            huge: None,
+            _phantom: core::marker::PhantomData,
+        }
+    }
+
+    /// Creates a new allocation mapping backend that maps huge pages backed by
+    /// contiguous frames whenever possible.
+    pub const fn new_alloc_huge(populate: bool, huge: HugeFrameAllocator) -> Self {
+        Self::Alloc {
+            populate,
+            huge: Some(huge),
             _phantom: core::marker::PhantomData,
         }
     }
@@ -18,8 +60,9 @@ impl<H: PagingHandler> Backend<H> {
         start: GuestPhysAddr,
         size: usize,
         flags: MappingFlags,
-        pt: &mut PageTable<H>,
+        pt: &mut PageTable<H, F>,
         populate: bool,
+        huge: Option<HugeFrameAllocator>,
     ) -> bool {
         debug!(
             "map_alloc: [{:#x}, {:#x}) {:?} (populate={})",
@@ -28,7 +71,22 @@ impl<H: PagingHandler> Backend<H> {
             flags,
             populate
         );
-        if populate {
+        if let Some(huge) = huge {
+            if !populate {
+                // Leave the entries unused, so that a huge page can be mapped
+                // at the first page fault.
+                return true;
+            }
+            let range = GuestPhysAddrRange::from_start_size(start, size);
+            let mut addr = start;
+            while addr < range.end {
+                match Self::map_alloc_huge_page(addr, range, flags, pt, huge) {
+                    Some(page_size) => addr += page_size as usize,
+                    None => return false,
+                }
+            }
+            true
+        } else if populate {
             // allocate all possible physical frames for populated mapping.
             for addr in PageIter4K::new(start, start + size).unwrap() {
                 if H::alloc_frame()
@@ -47,7 +105,7 @@ impl<H: PagingHandler> Backend<H> {
                 size,
                 MappingFlags::empty(),
                 false,
-                false,
+                None,
             )
             .is_ok()
         }
@@ -57,20 +115,76 @@ impl<H: PagingHandler> Backend<H> {
         &self,
         start: GuestPhysAddr,
         size: usize,
-        pt: &mut PageTable<H>,
+        pt: &mut PageTable<H, F>,
         _populate: bool,
+        huge: Option<HugeFrameAllocator>,
     ) -> bool {
         debug!("unmap_alloc: [{:#x}, {:#x})", start, start + size);
-        for addr in PageIter4K::new(start, start + size).unwrap() {
-            if let Ok((frame, page_size, _)) = pt.unmap(addr) {
-                // Deallocate the physical frame if there is a mapping in the
-                // page table.
+        let Some(huge) = huge else {
+            for addr in PageIter4K::new(start, start + size).unwrap() {
+                if let Ok((frame, page_size)) = pt.unmap(addr) {
+                    // Deallocate the physical frame if there is a mapping in the
+                    // page table.
+                    if page_size.is_huge() {
+                        return false;
+                    }
+                    H::dealloc_frame(frame);
+                } else {
+                    // It's fine if the page is not mapped.
+                }
+            }
+            return true;
+        };
+
+        // Huge pages crossing the boundaries are split, the contiguous frames
+        // outside the range are kept and released one by one later.
+        if !split_huge_page(pt, start) || !split_huge_page(pt, start + size) {
+            return false;
+        }
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            if let Ok((frame, page_size)) = pt.unmap(addr) {
                 if page_size.is_huge() {
-                    return false;
+                    (huge.dealloc)(frame, page_size as usize / PAGE_SIZE_4K);
+                } else {
+                    H::dealloc_frame(frame);
                 }
-                H::dealloc_frame(frame);
+                addr += page_size as usize;
             } else {
-                // It's fine if the page is not mapped.
+                addr += PAGE_SIZE_4K;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn protect_alloc(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
+        _populate: bool,
+        huge: Option<HugeFrameAllocator>,
+    ) -> bool {
+        debug!(
+            "protect_alloc: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            new_flags
+        );
+        if huge.is_some() && (!split_huge_page(pt, start) || !split_huge_page(pt, start + size)) {
+            return false;
+        }
+        let end = start + size;
+        let mut addr = start;
+        while addr < end {
+            match pt.protect(addr, new_flags) {
+                Ok(page_size) => addr += page_size as usize,
+                // Pages that have not been faulted in yet keep their empty
+                // entry, they will be mapped with the new flags of the area.
+                Err(PagingError::NotMapped) => addr += PAGE_SIZE_4K,
+                Err(_) => return false,
             }
         }
         true
@@ -79,19 +193,76 @@ impl<H: PagingHandler> Backend<H> {
     pub(crate) fn handle_page_fault_alloc(
         &self,
         vaddr: GuestPhysAddr,
-        orig_flags: MappingFlags,
-        pt: &mut PageTable<H>,
+        area: FaultAreaInfo,
+        pt: &mut PageTable<H, F>,
         populate: bool,
-    ) -> bool {
-        if populate {
-            false // Populated mappings should not trigger page faults.
-        } else {
-            // Allocate a physical frame lazily and map it to the fault address.
-            // `vaddr` does not need to be aligned. It will be automatically
-            // aligned during `pt.remap` regardless of the page size.
-            H::alloc_frame()
-                .and_then(|frame| pt.remap(vaddr, frame, orig_flags).ok())
-                .is_some()
+        huge: Option<HugeFrameAllocator>,
+    ) -> PageFaultOutcome {
+        if populate || pt.query(vaddr).is_ok() {
+            // Populated mappings should not trigger page faults, and faults on
+            // mapped pages are not caused by lazy allocation.
+            return PageFaultOutcome::Unexpected { area };
+        }
+        if let Some(huge) = huge {
+            return match Self::map_alloc_huge_page(vaddr, area.range, area.flags, pt, huge) {
+                Some(_) => PageFaultOutcome::Handled,
+                None => PageFaultOutcome::NoMemory { area },
+            };
+        }
+        // Allocate a physical frame lazily and map it to the fault address.
+        // `vaddr` does not need to be aligned. It will be automatically
+        // aligned during `pt.remap` regardless of the page size.
+        let Some(frame) = H::alloc_frame() else {
+            return PageFaultOutcome::NoMemory { area };
+        };
+        match pt.remap(vaddr, frame, area.flags) {
+            Ok(_) => PageFaultOutcome::Handled,
+            Err(_) => {
+                H::dealloc_frame(frame);
+                PageFaultOutcome::Unexpected { area }
+            }
+        }
+    }
+
+    /// Maps the largest page containing `vaddr` that fits in `range`.
+    ///
+    /// Huge pages are backed by contiguous frames from `huge`, it falls back
+    /// to smaller pages if they cannot be allocated or mapped. Returns the
+    /// size of the mapped page.
+    fn map_alloc_huge_page(
+        vaddr: GuestPhysAddr,
+        range: GuestPhysAddrRange,
+        flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
+        huge: HugeFrameAllocator,
+    ) -> Option<PageSize> {
+        for page_size in [PageSize::Size1G, PageSize::Size2M] {
+            if page_size as usize > huge.page_size as usize || !pt.huge_page_supported(page_size) {
+                continue;
+            }
+            let start = vaddr.align_down(page_size);
+            let page_range = GuestPhysAddrRange::try_from_start_size(start, page_size as usize);
+            if !page_range.is_some_and(|page_range| range.contains_range(page_range)) {
+                continue;
+            }
+            let num_frames = page_size as usize / PAGE_SIZE_4K;
+            let Some(frame) = (huge.alloc)(num_frames, page_size as usize) else {
+                continue;
+            };
+            match pt.map(start, frame, page_size, flags) {
+                Ok(()) => return Some(page_size),
+                // Some smaller pages are already mapped in the range.
+                Err(_) => (huge.dealloc)(frame, num_frames),
+            }
+        }
+
+        let frame = H::alloc_frame()?;
+        match pt.map(vaddr.align_down_4k(), frame, PageSize::Size4K, flags) {
+            Ok(()) => Some(PageSize::Size4K),
+            Err(_) => {
+                H::dealloc_frame(frame);
+                None
+            }
         }
     }
 }
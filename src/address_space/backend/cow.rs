@@ -0,0 +1,541 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError, PagingHandler};
+
+use super::{Backend, split_huge_page_4k};
+use crate::{
+    FaultAreaInfo, GuestPhysAddr, NestedFormat, PageFaultOutcome, npt::NestedPageTable as PageTable,
+};
+
+/// A frame shared by the owners of a copy-on-write area.
+struct CowSlot {
+    /// The shared host frame, or `0` if the page was not mapped when forking.
+    paddr: PhysAddr,
+    /// The number of address spaces still mapping `paddr`.
+    refs: AtomicUsize,
+    /// Whether `paddr` is a reference borrowed from the parent table.
+    inherited: bool,
+}
+
+impl CowSlot {
+    /// Whether the caller is the only owner of the shared frame.
+    fn is_exclusive(&self) -> bool {
+        self.refs.load(Ordering::Acquire) == 1 && !self.inherited
+    }
+}
+
+/// The host frames shared between the address spaces created by
+/// [`AddrSpace::fork`](crate::AddrSpace::fork).
+///
+/// Each 4K page of the forked area has a slot recording the frame that was
+/// mapped when forking and how many address spaces still map it. A frame is
+/// released once its last owner unmaps it or copies it on a write fault.
+pub struct CowFrames<H: PagingHandler> {
+    base: GuestPhysAddr,
+    slots: Vec<CowSlot>,
+    parent: Option<Arc<CowFrames<H>>>,
+    _phantom: core::marker::PhantomData<H>,
+}
+
+impl<H: PagingHandler> CowFrames<H> {
+    fn slot(&self, vaddr: GuestPhysAddr) -> &CowSlot {
+        &self.slots[(vaddr - self.base) / PAGE_SIZE_4K]
+    }
+
+    /// Returns the slot of `vaddr` if `paddr` is the frame shared in it.
+    fn shared_slot(&self, vaddr: GuestPhysAddr, paddr: PhysAddr) -> Option<&CowSlot> {
+        let slot = self.slot(vaddr);
+        (slot.paddr == paddr && slot.refs.load(Ordering::Acquire) > 0).then_some(slot)
+    }
+
+    /// Drops one reference to the frame shared at `vaddr`, deallocating it
+    /// when the last owner is gone.
+    fn release(&self, vaddr: GuestPhysAddr) {
+        let slot = self.slot(vaddr);
+        if slot.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            match &self.parent {
+                Some(parent) if slot.inherited => parent.release(vaddr),
+                _ => H::dealloc_frame(slot.paddr),
+            }
+        }
+    }
+}
+
+impl<H: PagingHandler, F: NestedFormat> Backend<H, F> {
+    /// Whether the pages of the area are shared by the parent and a child
+    /// address space after [`AddrSpace::fork`](crate::AddrSpace::fork).
+    ///
+    /// The frames of allocation and copy-on-write areas are owned by the
+    /// address space and shared. Linear areas are not owned by the address
+    /// space, so the child maps the same host memory, and MMIO regions are
+    /// dispatched to the same devices.
+    pub(crate) const fn is_shared_by_fork(&self) -> bool {
+        matches!(self, Self::Alloc { .. } | Self::Cow { .. })
+    }
+
+    /// Prepares the pages of an area to be shared with a child address space.
+    ///
+    /// Huge pages are split into 4K pages, which are shared one by one, and
+    /// the tables of the pages that are not mapped are created, so that the
+    /// area can be remapped by the copy-on-write backend without allocating
+    /// memory. The mappings themselves are unchanged.
+    pub(crate) fn prepare_fork(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H, F>,
+    ) -> bool {
+        if !self.is_shared_by_fork() {
+            return true;
+        }
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            match pt.query(addr) {
+                Ok((_, _, page_size)) if page_size.is_huge() => {
+                    if !split_huge_page_4k(pt, addr) {
+                        return false;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => match pt.map(
+                    addr,
+                    PhysAddr::from(0),
+                    PageSize::Size4K,
+                    MappingFlags::empty(),
+                ) {
+                    // Leave the entry unused like before.
+                    Ok(()) => {
+                        let _ = pt.unmap(addr);
+                    }
+                    // An empty entry is already there.
+                    Err(PagingError::AlreadyMapped) => {}
+                    Err(_) => return false,
+                },
+            }
+        }
+        true
+    }
+
+    /// Returns the backend of an area in a child address space, and in the
+    /// parent one if it is shared by the fork.
+    ///
+    /// Mapped frames of allocation and copy-on-write areas, prepared by
+    /// [`Backend::prepare_fork`], are recorded in a new [`CowFrames`] table
+    /// with two owners. The pages are not changed in `pt`.
+    pub(crate) fn fork(&self, start: GuestPhysAddr, size: usize, pt: &PageTable<H, F>) -> Self {
+        debug!("fork: [{:#x}, {:#x})", start, start + size);
+        let parent = match self {
+            Self::Linear { .. } | Self::Mmio { .. } => return self.clone(),
+            Self::Alloc { .. } => None,
+            Self::Cow { frames } => Some(frames.clone()),
+        };
+
+        let slots = PageIter4K::new(start, start + size)
+            .unwrap()
+            .map(|addr| match pt.query(addr) {
+                Ok((paddr, ..)) => {
+                    // A frame already shared by the parent keeps its
+                    // reference there, the new table borrows it on behalf of
+                    // both owners.
+                    let inherited = parent
+                        .as_ref()
+                        .is_some_and(|frames| frames.shared_slot(addr, paddr).is_some());
+                    CowSlot {
+                        paddr,
+                        refs: AtomicUsize::new(2),
+                        inherited,
+                    }
+                }
+                Err(_) => CowSlot {
+                    paddr: PhysAddr::from(0),
+                    refs: AtomicUsize::new(0),
+                    inherited: false,
+                },
+            })
+            .collect();
+        Self::Cow {
+            frames: Arc::new(CowFrames {
+                base: start,
+                slots,
+                parent,
+                _phantom: core::marker::PhantomData,
+            }),
+        }
+    }
+
+    /// Detaches the pages of an area from `pt` without releasing their
+    /// frames, which are owned by the backend returned by [`Backend::fork`].
+    pub(crate) fn detach_pages(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H, F>) {
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            let _ = pt.unmap(addr);
+        }
+    }
+
+    pub(crate) fn map_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
+        frames: &CowFrames<H>,
+    ) -> bool {
+        debug!("map_cow: [{:#x}, {:#x}) {:?}", start, start + size, flags);
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            let paddr = frames.slot(addr).paddr;
+            let res = if paddr.as_usize() != 0 {
+                // Shared frames are mapped read-only until the first write.
+                pt.map(addr, paddr, PageSize::Size4K, flags - MappingFlags::WRITE)
+            } else {
+                // Map to a empty entry for on-demand mapping.
+                pt.map(addr, paddr, PageSize::Size4K, MappingFlags::empty())
+            };
+            if res.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn unmap_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        pt: &mut PageTable<H, F>,
+        frames: &CowFrames<H>,
+    ) -> bool {
+        debug!("unmap_cow: [{:#x}, {:#x})", start, start + size);
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if let Ok((frame, _)) = pt.unmap(addr) {
+                if frames.shared_slot(addr, frame).is_some() {
+                    frames.release(addr);
+                } else {
+                    // The frame was privately copied on a write fault.
+                    H::dealloc_frame(frame);
+                }
+            }
+        }
+        true
+    }
+
+    pub(crate) fn protect_cow(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
+        frames: &CowFrames<H>,
+    ) -> bool {
+        debug!(
+            "protect_cow: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            new_flags
+        );
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            let Ok((paddr, _, _)) = pt.query(addr) else {
+                continue;
+            };
+            let flags = if frames
+                .shared_slot(addr, paddr)
+                .is_some_and(|slot| !slot.is_exclusive())
+            {
+                new_flags - MappingFlags::WRITE
+            } else {
+                new_flags
+            };
+            if pt.protect(addr, flags).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn handle_page_fault_cow(
+        &self,
+        vaddr: GuestPhysAddr,
+        area: FaultAreaInfo,
+        pt: &mut PageTable<H, F>,
+        frames: &CowFrames<H>,
+    ) -> PageFaultOutcome {
+        // The frames are shared per 4K page, and the fault address is usually
+        // not aligned.
+        let vaddr = vaddr.align_down_4k();
+        let Ok((paddr, _, _)) = pt.query(vaddr) else {
+            // Allocate a physical frame lazily like the allocation backend.
+            let Some(frame) = H::alloc_frame() else {
+                return PageFaultOutcome::NoMemory { area };
+            };
+            return match pt.remap(vaddr, frame, area.flags) {
+                Ok(_) => PageFaultOutcome::Handled,
+                Err(_) => {
+                    H::dealloc_frame(frame);
+                    PageFaultOutcome::Unexpected { area }
+                }
+            };
+        };
+        // Private frames should not trigger page faults, nor reads of shared
+        // frames.
+        let paddr = paddr.align_down_4k();
+        let Some(slot) = frames.shared_slot(vaddr, paddr) else {
+            return PageFaultOutcome::Unexpected { area };
+        };
+        if !area.flags.contains(MappingFlags::WRITE) {
+            return PageFaultOutcome::Unexpected { area };
+        }
+
+        if slot.is_exclusive() {
+            // The last owner takes the frame over without copying.
+            return match pt.protect(vaddr, area.flags) {
+                Ok(_) => PageFaultOutcome::Handled,
+                Err(_) => PageFaultOutcome::Unexpected { area },
+            };
+        }
+        let Some(frame) = H::alloc_frame() else {
+            return PageFaultOutcome::NoMemory { area };
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                H::phys_to_virt(paddr).as_ptr(),
+                H::phys_to_virt(frame).as_mut_ptr(),
+                PAGE_SIZE_4K,
+            );
+        }
+        match pt.remap(vaddr, frame, area.flags) {
+            Ok(_) => {
+                frames.release(vaddr);
+                PageFaultOutcome::Handled
+            }
+            Err(_) => {
+                H::dealloc_frame(frame);
+                PageFaultOutcome::Unexpected { area }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{HostHal, take_tlb_flushes};
+    use crate::{AddrSpace, NestedTlbFlush};
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr)
+    }
+
+    fn rw() -> MappingFlags {
+        MappingFlags::READ | MappingFlags::WRITE
+    }
+
+    /// Creates an address space with two populated pages filled with `0x11`
+    /// and `0x22` at `0x1000`, and forks it.
+    fn forked() -> (AddrSpace<HostHal>, AddrSpace<HostHal>) {
+        let mut parent = AddrSpace::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        parent.map_alloc(gpa(0x1000), 0x2000, rw(), true).unwrap();
+        parent.write_bytes(gpa(0x1000), &[0x11; 0x1000]).unwrap();
+        parent.write_bytes(gpa(0x2000), &[0x22; 0x1000]).unwrap();
+        let child = parent.fork().unwrap();
+        (parent, child)
+    }
+
+    /// Returns the number of owners of the frame shared at `vaddr`.
+    fn refs(aspace: &AddrSpace<HostHal>, vaddr: GuestPhysAddr) -> usize {
+        match aspace.areas.find(vaddr).unwrap().backend() {
+            Backend::Cow { frames } => frames.slot(vaddr).refs.load(Ordering::Acquire),
+            _ => panic!("not a copy-on-write area"),
+        }
+    }
+
+    fn read_byte(aspace: &mut AddrSpace<HostHal>, vaddr: GuestPhysAddr) -> u8 {
+        let mut byte = [0];
+        aspace.read_bytes(vaddr, &mut byte).unwrap();
+        byte[0]
+    }
+
+    #[test]
+    fn fork_shares_frames_read_only() {
+        let (parent, child) = forked();
+        for vaddr in [gpa(0x1000), gpa(0x2000)] {
+            let shared = parent.translate(vaddr).unwrap();
+            assert_eq!(child.translate(vaddr), Some(shared));
+            assert_eq!(refs(&parent, vaddr), 2);
+            for aspace in [&parent, &child] {
+                let flags = aspace.page_table().query(vaddr).unwrap().1;
+                assert_eq!(flags, MappingFlags::READ);
+            }
+        }
+    }
+
+    #[test]
+    fn unaligned_write_fault_copies_the_page() {
+        let (mut parent, child) = forked();
+        let shared = parent.translate(gpa(0x1000)).unwrap();
+
+        assert!(
+            parent
+                .handle_page_fault(gpa(0x1234), MappingFlags::WRITE)
+                .is_handled()
+        );
+        let copy = parent.translate(gpa(0x1000)).unwrap();
+        assert_ne!(copy, shared);
+        assert!(copy.is_aligned_4k());
+        assert_eq!(parent.page_table().query(gpa(0x1000)).unwrap().1, rw());
+        assert_eq!(refs(&parent, gpa(0x1000)), 1);
+        // The whole page is copied, not only from the fault address.
+        assert_eq!(read_byte(&mut parent, gpa(0x1000)), 0x11);
+        assert_eq!(read_byte(&mut parent, gpa(0x1fff)), 0x11);
+        assert_eq!(child.translate(gpa(0x1000)), Some(shared));
+    }
+
+    #[test]
+    fn write_bytes_across_shared_pages() {
+        let (mut parent, mut child) = forked();
+        parent.write_bytes(gpa(0x1ffe), &[0xaa; 4]).unwrap();
+
+        let mut buf = [0; 6];
+        parent.read_bytes(gpa(0x1ffd), &mut buf).unwrap();
+        assert_eq!(buf, [0x11, 0xaa, 0xaa, 0xaa, 0xaa, 0x22]);
+        child.read_bytes(gpa(0x1ffd), &mut buf).unwrap();
+        assert_eq!(buf, [0x11, 0x11, 0x11, 0x22, 0x22, 0x22]);
+        assert_eq!(refs(&parent, gpa(0x1000)), 1);
+        assert_eq!(refs(&parent, gpa(0x2000)), 1);
+    }
+
+    #[test]
+    fn exclusive_owner_takes_the_frame_over() {
+        let (mut parent, mut child) = forked();
+        let shared = parent.translate(gpa(0x1000)).unwrap();
+        assert!(
+            child
+                .handle_page_fault(gpa(0x1800), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert_ne!(child.translate(gpa(0x1000)), Some(shared));
+
+        // The parent is the last owner, the frame is not copied again.
+        assert!(
+            parent
+                .handle_page_fault(gpa(0x1008), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert_eq!(parent.translate(gpa(0x1000)), Some(shared));
+        assert_eq!(parent.page_table().query(gpa(0x1000)).unwrap().1, rw());
+        assert_eq!(refs(&parent, gpa(0x1000)), 1);
+        // Faults on private copies are not copy-on-write faults.
+        assert!(matches!(
+            child.handle_page_fault(gpa(0x1000), MappingFlags::WRITE),
+            PageFaultOutcome::Unexpected { .. }
+        ));
+    }
+
+    #[test]
+    fn frames_released_after_both_sides_wrote() {
+        let (mut parent, mut child) = forked();
+        let shared = parent.translate(gpa(0x1000)).unwrap();
+        parent.write_bytes(gpa(0x1010), &[1]).unwrap();
+        child.write_bytes(gpa(0x1020), &[2]).unwrap();
+        assert_eq!(child.translate(gpa(0x1000)), Some(shared));
+        assert_eq!(refs(&child, gpa(0x1000)), 1);
+
+        // The shared frame is released by its last owner, the private copy
+        // by the other one. Freeing a frame twice panics in `HostHal`.
+        child.unmap(gpa(0x1000), 0x1000).unwrap();
+        assert_eq!(refs(&parent, gpa(0x1000)), 0);
+        parent.unmap(gpa(0x1000), 0x1000).unwrap();
+        assert_eq!(refs(&parent, gpa(0x2000)), 2);
+        drop(parent);
+        assert_eq!(refs(&child, gpa(0x2000)), 1);
+    }
+
+    #[test]
+    fn fork_of_a_forked_space() {
+        let (mut parent, child) = forked();
+        let shared = parent.translate(gpa(0x1000)).unwrap();
+        let mut grandchild = parent.fork().unwrap();
+        assert_eq!(grandchild.translate(gpa(0x1000)), Some(shared));
+        // The frame is borrowed from the first fork on behalf of both owners.
+        assert_eq!(refs(&child, gpa(0x1000)), 2);
+        assert_eq!(refs(&parent, gpa(0x1000)), 2);
+
+        grandchild.write_bytes(gpa(0x1000), &[3]).unwrap();
+        assert_eq!(refs(&parent, gpa(0x1000)), 1);
+        assert_eq!(refs(&child, gpa(0x1000)), 2);
+        drop(parent);
+        assert_eq!(refs(&child, gpa(0x1000)), 1);
+    }
+
+    #[test]
+    fn fork_splits_huge_pages_and_flushes_once() {
+        let mut parent = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        parent
+            .map_alloc_huge(gpa(0x20_0000), 0x20_0000, rw(), true, PageSize::Size2M)
+            .unwrap();
+        parent
+            .map_alloc(gpa(0x40_0000), 0x4000, rw(), false)
+            .unwrap();
+        parent
+            .map_linear(
+                gpa(0x80_0000),
+                PhysAddr::from(0x8000_0000),
+                0x1000,
+                rw(),
+                None,
+            )
+            .unwrap();
+        let frame = parent.translate(gpa(0x20_1000)).unwrap();
+
+        take_tlb_flushes();
+        let mut child = parent.fork().unwrap();
+        let context = parent.tlb_context();
+        let flushes = take_tlb_flushes();
+        assert_eq!(flushes.len(), 1);
+        assert!(matches!(
+            flushes[0],
+            NestedTlbFlush::Context(_) | NestedTlbFlush::All
+        ));
+        assert_eq!(context.map(NestedTlbFlush::Context), Some(flushes[0]));
+
+        // The huge page is shared as 4K pages.
+        for aspace in [&parent, &child] {
+            assert_eq!(aspace.translate(gpa(0x20_1000)), Some(frame));
+            let (_, flags, page_size) = aspace.page_table().query(gpa(0x20_1000)).unwrap();
+            assert_eq!((flags, page_size), (MappingFlags::READ, PageSize::Size4K));
+        }
+        // Linear areas map the same host memory, lazy pages are allocated by
+        // each side.
+        assert_eq!(
+            child.translate(gpa(0x80_0000)),
+            Some(PhysAddr::from(0x8000_0000))
+        );
+        assert!(
+            child
+                .handle_page_fault(gpa(0x40_0010), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert!(
+            parent
+                .handle_page_fault(gpa(0x40_0010), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert_ne!(
+            child.translate(gpa(0x40_0000)),
+            parent.translate(gpa(0x40_0000))
+        );
+    }
+
+    #[test]
+    fn fork_fails_while_logging_dirty_pages() {
+        let mut parent = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        parent.map_alloc(gpa(0x1000), 0x1000, rw(), true).unwrap();
+        let frame = parent.translate(gpa(0x1000));
+        parent.start_dirty_log(gpa(0x1000), 0x1000).unwrap();
+        assert!(parent.fork().is_err());
+        parent.stop_dirty_log().unwrap();
+        assert_eq!(parent.translate(gpa(0x1000)), frame);
+        assert!(matches!(
+            parent.areas.find(gpa(0x1000)).unwrap().backend(),
+            Backend::Alloc { .. }
+        ));
+    }
+}
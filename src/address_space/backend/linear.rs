@@ -1,13 +1,19 @@
 use memory_addr::PhysAddr;
 use page_table_multiarch::{MappingFlags, PagingHandler};
 
-use super::Backend;
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use super::{Backend, split_huge_page};
+use crate::{GuestPhysAddr, NestedFormat, npt::NestedPageTable as PageTable};
 
-impl<H: PagingHandler> Backend<H> {
+impl<H: PagingHandler, F: NestedFormat> Backend<H, F> {
     /// Creates a new linear mapping backend.
-    pub const fn new_linear(pa_va_offset: usize) -> Self {
-        Self::Linear { pa_va_offset }
+    ///
+    /// The pages have the memory attribute `mem_attr` if it is given, or the
+    /// one derived from the mapping flags otherwise.
+    pub const fn new_linear(pa_va_offset: usize, mem_attr: Option<F::MemAttr>) -> Self {
+        Self::Linear {
+            pa_va_offset,
+            mem_attr,
+        }
     }
 
     pub(crate) fn map_linear(
@@ -15,25 +21,27 @@ impl<H: PagingHandler> Backend<H> {
         start: GuestPhysAddr,
         size: usize,
         flags: MappingFlags,
-        pt: &mut PageTable<H>,
+        pt: &mut PageTable<H, F>,
         pa_va_offset: usize,
+        mem_attr: Option<F::MemAttr>,
     ) -> bool {
-        let pa_start = PhysAddr::from(start.as_usize() - pa_va_offset);
+        let pa_start = PhysAddr::from(start.as_usize().wrapping_sub(pa_va_offset));
         debug!(
-            "map_linear: [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
+            "map_linear: [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?} {:?}",
             start,
             start + size,
             pa_start,
             pa_start + size,
-            flags
+            flags,
+            mem_attr
         );
         pt.map_region(
             start,
-            |va| PhysAddr::from(va.as_usize() - pa_va_offset),
+            |va| PhysAddr::from(va.as_usize().wrapping_sub(pa_va_offset)),
             size,
             flags,
-            false,
-            false,
+            true,
+            mem_attr,
         )
         .is_ok()
     }
@@ -42,10 +50,35 @@ impl<H: PagingHandler> Backend<H> {
         &self,
         start: GuestPhysAddr,
         size: usize,
-        pt: &mut PageTable<H>,
+        pt: &mut PageTable<H, F>,
         _pa_va_offset: usize,
     ) -> bool {
         debug!("unmap_linear: [{:#x}, {:#x})", start, start + size);
-        pt.unmap_region(start, size, true).is_ok()
+        // Huge pages crossing the boundaries are split to unmap only the range.
+        split_huge_page(pt, start)
+            && split_huge_page(pt, start + size)
+            && pt.unmap_region(start, size).is_ok()
+    }
+
+    pub(crate) fn protect_linear(
+        &self,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
+        _pa_va_offset: usize,
+        mem_attr: Option<F::MemAttr>,
+    ) -> bool {
+        debug!(
+            "protect_linear: [{:#x}, {:#x}) {:?}",
+            start,
+            start + size,
+            new_flags
+        );
+        // The attribute of the mapping is applied again, as the parts of it
+        // that a page without `EXECUTE` cannot encode are lost.
+        split_huge_page(pt, start)
+            && split_huge_page(pt, start + size)
+            && pt.protect_region(start, size, new_flags, mem_attr).is_ok()
     }
 }
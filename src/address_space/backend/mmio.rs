@@ -0,0 +1,225 @@
+use alloc::sync::Arc;
+use core::fmt;
+
+use page_table_multiarch::PagingHandler;
+
+use super::Backend;
+use crate::device::DeviceOps;
+use crate::{GuestPhysAddr, GuestPhysAddrRange, NestedFormat};
+
+/// A device emulated by trapping the guest accesses to its MMIO region.
+pub type MmioDevice = dyn DeviceOps<GuestPhysAddrRange>;
+
+/// A guest access to an MMIO region registered with
+/// [`AddrSpace::register_mmio`](crate::AddrSpace::register_mmio).
+#[derive(Clone)]
+pub struct MmioAccess {
+    /// The address range of the region.
+    pub region: GuestPhysAddrRange,
+    /// The offset of the accessed address in the region.
+    pub offset: usize,
+    /// The device emulating the region.
+    pub device: Arc<MmioDevice>,
+}
+
+impl fmt::Debug for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MmioAccess")
+            .field("region", &self.region)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl<H: PagingHandler, F: NestedFormat> Backend<H, F> {
+    /// Creates a new MMIO trap backend dispatching to `device`.
+    pub fn new_mmio(device: Arc<MmioDevice>) -> Self {
+        Self::Mmio { device }
+    }
+
+    /// Returns the access to `vaddr` if the backend is an MMIO region.
+    pub(crate) fn mmio_access(&self, vaddr: GuestPhysAddr) -> Option<MmioAccess> {
+        let Self::Mmio { device } = self else {
+            return None;
+        };
+        let region = device.address_range();
+        Some(MmioAccess {
+            region,
+            offset: vaddr - region.start,
+            device: device.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use axerrno::{AxError, AxResult};
+    use memory_addr::PhysAddr;
+    use page_table_multiarch::MappingFlags;
+
+    use super::*;
+    use crate::device::{AccessWidth, DeviceOps};
+    use crate::host::HostHal;
+    use crate::{AddrSpace, PageFaultOutcome};
+
+    /// A device whose reads return its tag and the offset of the access, and
+    /// which records the last value written.
+    struct TestDevice {
+        range: GuestPhysAddrRange,
+        tag: usize,
+        written: AtomicUsize,
+    }
+
+    impl DeviceOps<GuestPhysAddrRange> for TestDevice {
+        fn address_range(&self) -> GuestPhysAddrRange {
+            self.range
+        }
+
+        fn read(&self, addr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+            Ok(self.tag | (addr - self.range.start) | (width.size() << 8))
+        }
+
+        fn write(&self, _addr: GuestPhysAddr, _width: AccessWidth, value: usize) -> AxResult {
+            self.written.store(value, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from_usize(addr)
+    }
+
+    fn device(start: usize, size: usize, tag: usize) -> Arc<TestDevice> {
+        Arc::new(TestDevice {
+            range: GuestPhysAddrRange::from_start_size(gpa(start), size),
+            tag,
+            written: AtomicUsize::new(0),
+        })
+    }
+
+    fn err<T>(res: AxResult<T>) -> Option<AxError> {
+        res.err()
+    }
+
+    #[test]
+    fn dispatch_to_unaligned_regions() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        // Two regions sharing a page.
+        let uart = device(0x10_0100, 0x20, 0x1_0000);
+        let rtc = device(0x10_0120, 0x10, 0x2_0000);
+        aspace.register_mmio(uart.clone()).unwrap();
+        aspace.register_mmio(rtc.clone()).unwrap();
+        assert_eq!(aspace.translate(gpa(0x10_0100)), None);
+
+        let outcome = aspace.handle_page_fault(gpa(0x10_0108), MappingFlags::WRITE);
+        let PageFaultOutcome::Mmio(access) = outcome else {
+            panic!("unexpected outcome {outcome:?}");
+        };
+        assert_eq!(access.region, uart.range);
+        assert_eq!(access.offset, 8);
+        let access = aspace.mmio_access(gpa(0x10_012c)).unwrap();
+        assert_eq!((access.region, access.offset), (rtc.range, 0xc));
+
+        let read = aspace.handle_mmio_read(gpa(0x10_011f), AccessWidth::Byte);
+        assert_eq!(read, Ok(0x1_011f));
+        let read = aspace.handle_mmio_read(gpa(0x10_0124), AccessWidth::Dword);
+        assert_eq!(read, Ok(0x2_0404));
+        aspace
+            .handle_mmio_write(gpa(0x10_0120), AccessWidth::Word, 0xbeef)
+            .unwrap();
+        assert_eq!(rtc.written.load(Ordering::Relaxed), 0xbeef);
+        assert_eq!(uart.written.load(Ordering::Relaxed), 0);
+
+        // The regions are not accessible as guest memory.
+        let mut buf = [0; 4];
+        assert_eq!(
+            err(aspace.read_bytes(gpa(0x10_0100), &mut buf)),
+            Some(AxError::Unsupported)
+        );
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        let uart = device(0x10_0000, 0x1000, 0);
+        aspace.register_mmio(uart.clone()).unwrap();
+        aspace
+            .map_linear(
+                gpa(0x20_0000),
+                PhysAddr::from(0x8000_0000),
+                0x1000,
+                MappingFlags::READ,
+                None,
+            )
+            .unwrap();
+
+        // Overlapping, empty and out-of-range regions.
+        let overlap = aspace.register_mmio(device(0x10_0ff0, 0x20, 0));
+        assert_eq!(err(overlap), Some(AxError::AlreadyExists));
+        let overlap = aspace.register_mmio(device(0x20_0800, 0x10, 0));
+        assert_eq!(err(overlap), Some(AxError::AlreadyExists));
+        let empty = aspace.register_mmio(device(0x30_0000, 0, 0));
+        assert_eq!(err(empty), Some(AxError::InvalidInput));
+        let outside = aspace.register_mmio(device(0xff_f000, 0x2000, 0));
+        assert_eq!(err(outside), Some(AxError::InvalidInput));
+
+        // Only whole MMIO regions are unregistered.
+        let partial = GuestPhysAddrRange::from_start_size(gpa(0x10_0000), 0x800);
+        assert_eq!(
+            err(aspace.unregister_mmio(partial)),
+            Some(AxError::NotFound)
+        );
+        let linear = GuestPhysAddrRange::from_start_size(gpa(0x20_0000), 0x1000);
+        assert_eq!(err(aspace.unregister_mmio(linear)), Some(AxError::NotFound));
+        assert!(aspace.mmio_access(gpa(0x20_0000)).is_none());
+        assert_eq!(
+            err(aspace.handle_mmio_read(gpa(0x20_0000), AccessWidth::Byte)),
+            Some(AxError::NotFound)
+        );
+
+        aspace.unregister_mmio(uart.range).unwrap();
+        assert!(aspace.mmio_access(gpa(0x10_0000)).is_none());
+        assert_eq!(
+            err(aspace.handle_mmio_write(gpa(0x10_0000), AccessWidth::Byte, 1)),
+            Some(AxError::NotFound)
+        );
+        assert!(matches!(
+            aspace.handle_page_fault(gpa(0x10_0000), MappingFlags::READ),
+            PageFaultOutcome::Unmapped
+        ));
+        assert_eq!(
+            err(aspace.unregister_mmio(uart.range)),
+            Some(AxError::NotFound)
+        );
+        // The range can be registered again.
+        aspace.register_mmio(device(0x10_0800, 0x10, 0)).unwrap();
+    }
+
+    #[test]
+    fn unmap_and_protect_whole_regions() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        let uart = device(0x10_0800, 0x1000, 0);
+        aspace.register_mmio(uart.clone()).unwrap();
+
+        // Ranges covering a part of the region are rejected.
+        assert_eq!(
+            err(aspace.unmap(gpa(0x10_1000), 0x1000)),
+            Some(AxError::InvalidInput)
+        );
+        assert_eq!(
+            err(aspace.protect(gpa(0x10_0000), 0x1000, MappingFlags::READ)),
+            Some(AxError::InvalidInput)
+        );
+        let access = aspace.mmio_access(gpa(0x10_17ff)).unwrap();
+        assert_eq!((access.region, access.offset), (uart.range, 0xfff));
+
+        // Whole regions are still removed by the generic unmap.
+        aspace
+            .protect(gpa(0x10_0000), 0x2000, MappingFlags::READ)
+            .unwrap();
+        aspace.unmap(gpa(0x10_0000), 0x2000).unwrap();
+        assert!(aspace.mmio_access(gpa(0x10_0800)).is_none());
+    }
+}
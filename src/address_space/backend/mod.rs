@@ -1,30 +1,56 @@
 //! Memory mapping backends.
 
+use ::alloc::sync::Arc;
+
+use memory_addr::MemoryAddr;
 use memory_set::MappingBackend;
-use page_table_multiarch::{MappingFlags, PagingHandler};
+use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
 
-use crate::{GuestPhysAddr, npt::NestedPageTable as PageTable};
+use crate::{
+    FaultAreaInfo, GuestPhysAddr, GuestPhysAddrRange, NativeFormat, NestedFormat, PageFaultOutcome,
+    npt::{MemAttrState, NestedPageTable as PageTable, NestedPageTable64},
+};
 
 mod alloc;
+mod cow;
 mod linear;
+mod mmio;
+
+pub use alloc::HugeFrameAllocator;
+pub use cow::CowFrames;
+pub use mmio::{MmioAccess, MmioDevice};
 
 /// A unified enum type for different memory mapping backends.
 ///
-/// Currently, two backends are implemented:
+/// Currently, four backends are implemented:
 ///
 /// - **Linear**: used for linear mappings. The target physical frames are
 ///   contiguous and their addresses should be known when creating the mapping.
 /// - **Allocation**: used in general, or for lazy mappings. The target physical
 ///   frames are obtained from the global allocator.
-pub enum Backend<H: PagingHandler> {
+/// - **Copy-on-write**: used for address spaces created by
+///   [`AddrSpace::fork`](crate::AddrSpace::fork). The target physical frames
+///   are shared read-only until the first write.
+/// - **MMIO**: used for emulated devices. Nothing is mapped, so that every
+///   guest access traps and is dispatched to the device.
+///
+/// The backends update the page table without invalidating the TLB entries,
+/// the address space invalidates them once the whole operation is done.
+pub enum Backend<H: PagingHandler, F: NestedFormat = NativeFormat> {
     /// Linear mapping backend.
     ///
     /// The offset between the virtual address and the physical address is
     /// constant, which is specified by `pa_va_offset`. For example, the virtual
     /// address `vaddr` is mapped to the physical address `vaddr - pa_va_offset`.
+    ///
+    /// The region is mapped with 2M or 1G huge pages wherever both addresses
+    /// are aligned to the huge page size.
     Linear {
         /// `vaddr - paddr`.
         pa_va_offset: usize,
+        /// The memory attribute of the pages, or `None` to derive it from the
+        /// mapping flags.
+        mem_attr: Option<F::MemAttr>,
     },
     /// Allocation mapping backend.
     ///
@@ -32,75 +58,201 @@ pub enum Backend<H: PagingHandler> {
     /// mapping is created, and no page faults are triggered during the memory
     /// access. Otherwise, the physical frames are allocated on demand (by
     /// handling page faults).
+    ///
+    /// If `huge` is set, the mapping is backed by huge pages of contiguous
+    /// frames wherever the region is large enough, falling back to 4K pages
+    /// when contiguous frames are not available.
     Alloc {
         /// Whether to populate the physical frames when creating the mapping.
         populate: bool,
+        /// The allocator of the contiguous frames for huge pages.
+        huge: Option<HugeFrameAllocator>,
         /// A phantom data for the paging handler.
         _phantom: core::marker::PhantomData<H>,
     },
+    /// Copy-on-write mapping backend.
+    ///
+    /// The physical frames recorded in `frames` are shared between the parent
+    /// and the child address spaces and mapped read-only. A private copy is
+    /// allocated on the first write fault, and a shared frame is deallocated
+    /// when its last owner unmaps it.
+    Cow {
+        /// The shared physical frames and their reference counts.
+        frames: Arc<CowFrames<H>>,
+    },
+    /// MMIO trap backend.
+    ///
+    /// The region is left unmapped in the page table, the guest accesses to
+    /// it are reported by [`AddrSpace::mmio_access`](crate::AddrSpace::mmio_access)
+    /// and handled by `device`.
+    Mmio {
+        /// The device emulating the region.
+        device: Arc<MmioDevice>,
+    },
 }
 
-impl<H: PagingHandler> Clone for Backend<H> {
+impl<H: PagingHandler, F: NestedFormat> Clone for Backend<H, F> {
     fn clone(&self) -> Self {
         match *self {
-            Self::Linear { pa_va_offset } => Self::Linear { pa_va_offset },
-            Self::Alloc { populate, .. } => Self::Alloc {
+            Self::Linear {
+                pa_va_offset,
+                mem_attr,
+            } => Self::Linear {
+                pa_va_offset,
+                mem_attr,
+            },
+            Self::Alloc { populate, huge, .. } => Self::Alloc {
                 populate,
+                huge,
                 _phantom: core::marker::PhantomData,
             },
+            Self::Cow { ref frames } => Self::Cow {
+                frames: frames.clone(),
+            },
+            Self::Mmio { ref device } => Self::Mmio {
+                device: device.clone(),
+            },
         }
     }
 }
 
-impl<H: PagingHandler> MappingBackend for Backend<H> {
+impl<H: PagingHandler, F: NestedFormat> MappingBackend for Backend<H, F> {
     type Addr = GuestPhysAddr;
     type Flags = MappingFlags;
-    type PageTable = PageTable<H>;
+    type PageTable = PageTable<H, F>;
 
     fn map(
         &self,
         start: GuestPhysAddr,
         size: usize,
         flags: MappingFlags,
-        pt: &mut PageTable<H>,
+        pt: &mut PageTable<H, F>,
     ) -> bool {
         match *self {
-            Self::Linear { pa_va_offset } => self.map_linear(start, size, flags, pt, pa_va_offset),
-            Self::Alloc { populate, .. } => self.map_alloc(start, size, flags, pt, populate),
+            Self::Linear {
+                pa_va_offset,
+                mem_attr,
+            } => self.map_linear(start, size, flags, pt, pa_va_offset, mem_attr),
+            Self::Alloc { populate, huge, .. } => {
+                self.map_alloc(start, size, flags, pt, populate, huge)
+            }
+            Self::Cow { ref frames } => self.map_cow(start, size, flags, pt, frames),
+            Self::Mmio { .. } => true, // Accesses to MMIO regions always trap.
         }
     }
 
-    fn unmap(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H>) -> bool {
+    fn unmap(&self, start: GuestPhysAddr, size: usize, pt: &mut PageTable<H, F>) -> bool {
         match *self {
-            Self::Linear { pa_va_offset } => self.unmap_linear(start, size, pt, pa_va_offset),
-            Self::Alloc { populate, .. } => self.unmap_alloc(start, size, pt, populate),
+            Self::Linear { pa_va_offset, .. } => self.unmap_linear(start, size, pt, pa_va_offset),
+            Self::Alloc { populate, huge, .. } => self.unmap_alloc(start, size, pt, populate, huge),
+            Self::Cow { ref frames } => self.unmap_cow(start, size, pt, frames),
+            Self::Mmio { .. } => true,
         }
     }
 
     fn protect(
         &self,
-        _start: GuestPhysAddr,
-        _size: usize,
-        _new_flags: MappingFlags,
-        _page_table: &mut PageTable<H>,
+        start: GuestPhysAddr,
+        size: usize,
+        new_flags: MappingFlags,
+        pt: &mut PageTable<H, F>,
     ) -> bool {
-        // a stub here
-        true
+        match *self {
+            Self::Linear {
+                pa_va_offset,
+                mem_attr,
+            } => self.protect_linear(start, size, new_flags, pt, pa_va_offset, mem_attr),
+            Self::Alloc { populate, huge, .. } => {
+                self.protect_alloc(start, size, new_flags, pt, populate, huge)
+            }
+            Self::Cow { ref frames } => self.protect_cow(start, size, new_flags, pt, frames),
+            Self::Mmio { .. } => true,
+        }
     }
 }
 
-impl<H: PagingHandler> Backend<H> {
+impl<H: PagingHandler, F: NestedFormat> Backend<H, F> {
     pub(crate) fn handle_page_fault(
         &self,
         vaddr: GuestPhysAddr,
         orig_flags: MappingFlags,
-        page_table: &mut PageTable<H>,
-    ) -> bool {
+        page_table: &mut PageTable<H, F>,
+        area: GuestPhysAddrRange,
+    ) -> PageFaultOutcome {
+        let area = FaultAreaInfo {
+            range: area,
+            flags: orig_flags,
+        };
         match *self {
-            Self::Linear { .. } => false, // Linear mappings should not trigger page faults.
-            Self::Alloc { populate, .. } => {
-                self.handle_page_fault_alloc(vaddr, orig_flags, page_table, populate)
+            // Linear mappings should not trigger page faults.
+            Self::Linear { .. } => PageFaultOutcome::Unexpected { area },
+            Self::Alloc { populate, huge, .. } => {
+                self.handle_page_fault_alloc(vaddr, area, page_table, populate, huge)
+            }
+            Self::Cow { ref frames } => self.handle_page_fault_cow(vaddr, area, page_table, frames),
+            // MMIO accesses are handled by the device.
+            Self::Mmio { .. } => match self.mmio_access(vaddr) {
+                Some(access) => PageFaultOutcome::Mmio(access),
+                None => PageFaultOutcome::Unexpected { area },
+            },
+        }
+    }
+}
+
+/// Splits the huge page containing `vaddr` into 4K pages.
+pub(crate) fn split_huge_page_4k<PTE: MemAttrState, H: PagingHandler>(
+    pt: &mut NestedPageTable64<PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> bool {
+    // Once `vaddr` is at a page boundary, the page still containing it is
+    // split at the next 4K page.
+    split_huge_page(pt, vaddr) && split_huge_page(pt, vaddr + memory_addr::PAGE_SIZE_4K)
+}
+
+/// Splits the huge page containing `vaddr` into smaller pages, until `vaddr`
+/// is at a page boundary.
+///
+/// The split pages map the same physical memory with the same flags and
+/// memory attribute. Returns `false`, leaving the huge page mapped, if the
+/// smaller pages cannot be mapped.
+fn split_huge_page<PTE: MemAttrState, H: PagingHandler>(
+    pt: &mut NestedPageTable64<PTE, H>,
+    vaddr: GuestPhysAddr,
+) -> bool {
+    while let Ok((paddr, flags, page_size)) = pt.query(vaddr) {
+        let sub_size = match page_size {
+            PageSize::Size1G => PageSize::Size2M,
+            PageSize::Size2M => PageSize::Size4K,
+            PageSize::Size4K => break,
+        };
+        if page_size.is_aligned(vaddr.as_usize()) {
+            break;
+        }
+        debug!(
+            "split_huge_page: {:#x} {:?} -> {:?}",
+            vaddr, page_size, sub_size
+        );
+
+        let start = vaddr.align_down(page_size);
+        let paddr = paddr.align_down(page_size);
+        let mem_attr = pt.query_mem_attr(start).ok().flatten();
+        if pt.unmap(start).is_err() {
+            return false;
+        }
+        for offset in (0..page_size as usize).step_by(sub_size as usize) {
+            if pt
+                .map_with_attr(start + offset, paddr + offset, sub_size, flags, mem_attr)
+                .is_err()
+            {
+                // The huge page is mapped back, its page table entry being
+                // free again once the smaller pages are unmapped.
+                for mapped in (0..offset).step_by(sub_size as usize) {
+                    let _ = pt.unmap(start + mapped);
+                }
+                let _ = pt.map_with_attr(start, paddr, page_size, flags, mem_attr);
+                return false;
             }
         }
     }
+    true
 }
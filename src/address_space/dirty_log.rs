@@ -0,0 +1,409 @@
+use alloc::{vec, vec::Vec};
+
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+/// A bitmap with one bit per 4K page.
+struct PageBitmap(Vec<u64>);
+
+impl PageBitmap {
+    fn new(num_pages: usize) -> Self {
+        Self(vec![0; num_pages.div_ceil(64)])
+    }
+
+    fn get(&self, idx: usize) -> bool {
+        self.0[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn set(&mut self, idx: usize, value: bool) {
+        if value {
+            self.0[idx / 64] |= 1 << (idx % 64);
+        } else {
+            self.0[idx / 64] &= !(1 << (idx % 64));
+        }
+    }
+}
+
+/// The state of dirty page logging over a guest physical address range.
+pub(crate) struct DirtyLog {
+    range: GuestPhysAddrRange,
+    /// Whether the dirty state is recorded by the hardware in the entries.
+    hw: bool,
+    /// Pages known to be dirty, since the last fetch.
+    dirty: PageBitmap,
+    /// Pages that are write-protected to trap the first write.
+    protected: PageBitmap,
+}
+
+impl DirtyLog {
+    pub fn new(range: GuestPhysAddrRange, hw: bool) -> Self {
+        let num_pages = range.size() / PAGE_SIZE_4K;
+        Self {
+            range,
+            hw,
+            dirty: PageBitmap::new(num_pages),
+            protected: PageBitmap::new(num_pages),
+        }
+    }
+
+    pub const fn range(&self) -> GuestPhysAddrRange {
+        self.range
+    }
+
+    pub const fn is_hw(&self) -> bool {
+        self.hw
+    }
+
+    fn index(&self, vaddr: GuestPhysAddr) -> Option<usize> {
+        self.range
+            .contains(vaddr)
+            .then(|| (vaddr - self.range.start) / PAGE_SIZE_4K)
+    }
+
+    pub fn mark_dirty(&mut self, vaddr: GuestPhysAddr) {
+        if let Some(idx) = self.index(vaddr) {
+            self.dirty.set(idx, true);
+        }
+    }
+
+    /// Returns whether the page was dirty, and marks it clean.
+    pub fn take_dirty(&mut self, vaddr: GuestPhysAddr) -> bool {
+        let Some(idx) = self.index(vaddr) else {
+            return false;
+        };
+        let dirty = self.dirty.get(idx);
+        self.dirty.set(idx, false);
+        dirty
+    }
+
+    pub fn set_protected(&mut self, vaddr: GuestPhysAddr) {
+        if let Some(idx) = self.index(vaddr) {
+            self.protected.set(idx, true);
+        }
+    }
+
+    /// Returns whether the page was write-protected for logging, and clears
+    /// the protection state.
+    pub fn take_protected(&mut self, vaddr: GuestPhysAddr) -> bool {
+        let Some(idx) = self.index(vaddr) else {
+            return false;
+        };
+        let protected = self.protected.get(idx);
+        self.protected.set(idx, false);
+        protected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+    use memory_addr::PhysAddr;
+    use page_table_multiarch::{MappingFlags, PageSize, PagingHandler};
+
+    use super::*;
+    use crate::host::HostHal;
+    use crate::{AddrSpace, AxMmHal, HostPhysAddr, HostVirtAddr};
+
+    fn err<T>(res: axerrno::AxResult<T>) -> Option<AxError> {
+        res.err()
+    }
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr)
+    }
+
+    fn rw() -> MappingFlags {
+        MappingFlags::READ | MappingFlags::WRITE
+    }
+
+    fn writable(aspace: &AddrSpace<HostHal>, vaddr: GuestPhysAddr) -> bool {
+        let (_, flags, _) = aspace.page_table().query(vaddr).unwrap();
+        flags.contains(MappingFlags::WRITE)
+    }
+
+    #[test]
+    fn page_bitmap_words() {
+        let mut bitmap = PageBitmap::new(130);
+        assert_eq!(bitmap.0.len(), 3);
+        for idx in [0, 63, 64, 129] {
+            bitmap.set(idx, true);
+        }
+        assert_eq!(bitmap.0, [1 | 1 << 63, 1, 1 << 1]);
+        bitmap.set(63, false);
+        assert!(!bitmap.get(63));
+        assert!(bitmap.get(64));
+    }
+
+    #[test]
+    fn log_ignores_pages_out_of_range() {
+        let mut log = DirtyLog::new(
+            GuestPhysAddrRange::from_start_size(gpa(0x1000), 0x2000),
+            false,
+        );
+        log.mark_dirty(gpa(0x0fff));
+        log.mark_dirty(gpa(0x3000));
+        log.mark_dirty(gpa(0x2800));
+        assert!(!log.take_dirty(gpa(0x1000)));
+        assert!(log.take_dirty(gpa(0x2000)));
+        // The dirty state is cleared when taken.
+        assert!(!log.take_dirty(gpa(0x2000)));
+        assert!(!log.take_dirty(gpa(0x3000)));
+
+        log.set_protected(gpa(0x1fff));
+        log.set_protected(gpa(0x3000));
+        assert!(log.take_protected(gpa(0x1000)));
+        assert!(!log.take_protected(gpa(0x1000)));
+        assert!(!log.take_protected(gpa(0x3000)));
+    }
+
+    #[test]
+    fn write_protection_logging() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        // 70 pages, so that the bitmap spans two words.
+        aspace
+            .map_alloc(gpa(0x10_0000), 70 * 0x1000, rw(), true)
+            .unwrap();
+        aspace
+            .map_linear(
+                gpa(0x20_0000),
+                PhysAddr::from(0x4000_0000),
+                0x20_0000,
+                rw(),
+                None,
+            )
+            .unwrap();
+        aspace.start_dirty_log(gpa(0x10_0000), 0x30_0000).unwrap();
+        assert!(!writable(&aspace, gpa(0x10_0000)));
+        // Huge pages are split to log 4K pages.
+        let (_, _, page_size) = aspace.page_table().query(gpa(0x20_1000)).unwrap();
+        assert_eq!(page_size, PageSize::Size4K);
+        assert!(!writable(&aspace, gpa(0x20_1000)));
+
+        assert!(
+            aspace
+                .handle_page_fault(gpa(0x10_1008), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert!(writable(&aspace, gpa(0x10_1000)));
+        aspace.write_bytes(gpa(0x14_1000), &[1]).unwrap();
+        assert!(
+            aspace
+                .handle_page_fault(gpa(0x20_1000), MappingFlags::WRITE)
+                .is_handled()
+        );
+
+        let bitmap = aspace
+            .fetch_and_clear_dirty_log(gpa(0x10_0000), 70 * 0x1000)
+            .unwrap();
+        assert_eq!(bitmap, [1 << 1, 1 << (0x41 - 64)]);
+        let bitmap = aspace
+            .fetch_and_clear_dirty_log(gpa(0x20_0000), 0x2000)
+            .unwrap();
+        assert_eq!(bitmap, [1 << 1]);
+        // The dirty pages are write-protected again and their state cleared.
+        assert!(!writable(&aspace, gpa(0x10_1000)));
+        assert!(!writable(&aspace, gpa(0x20_1000)));
+        let bitmap = aspace
+            .fetch_and_clear_dirty_log(gpa(0x10_0000), 0x30_0000)
+            .unwrap();
+        assert!(bitmap.iter().all(|&word| word == 0));
+
+        aspace.stop_dirty_log().unwrap();
+        assert!(writable(&aspace, gpa(0x10_1000)));
+        assert!(writable(&aspace, gpa(0x20_1000)));
+        assert!(writable(&aspace, gpa(0x14_5000)));
+    }
+
+    #[test]
+    fn read_faults_while_logging_are_write_protected() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        aspace.map_alloc(gpa(0x1000), 0x2000, rw(), false).unwrap();
+        aspace.start_dirty_log(gpa(0x1000), 0x2000).unwrap();
+
+        assert!(
+            aspace
+                .handle_page_fault(gpa(0x1000), MappingFlags::READ)
+                .is_handled()
+        );
+        assert!(!writable(&aspace, gpa(0x1000)));
+        assert!(
+            aspace
+                .handle_page_fault(gpa(0x2000), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert!(writable(&aspace, gpa(0x2000)));
+        assert_eq!(
+            aspace
+                .fetch_and_clear_dirty_log(gpa(0x1000), 0x2000)
+                .unwrap(),
+            [0b10]
+        );
+
+        assert!(
+            aspace
+                .handle_page_fault(gpa(0x1000), MappingFlags::WRITE)
+                .is_handled()
+        );
+        assert_eq!(
+            aspace
+                .fetch_and_clear_dirty_log(gpa(0x1000), 0x2000)
+                .unwrap(),
+            [0b01]
+        );
+    }
+
+    #[test]
+    fn dirty_log_errors() {
+        let mut aspace = AddrSpace::<HostHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        assert_eq!(err(aspace.stop_dirty_log()), Some(AxError::BadState));
+        assert_eq!(
+            err(aspace.fetch_and_clear_dirty_log(gpa(0), 0x1000)),
+            Some(AxError::BadState)
+        );
+        assert_eq!(
+            err(aspace.start_dirty_log(gpa(0x800), 0x1000)),
+            Some(AxError::InvalidInput)
+        );
+        assert_eq!(
+            err(aspace.start_dirty_log(gpa(0xff_f000), 0x2000)),
+            Some(AxError::InvalidInput)
+        );
+
+        aspace.start_dirty_log(gpa(0x1000), 0x2000).unwrap();
+        assert_eq!(
+            err(aspace.start_dirty_log(gpa(0x4000), 0x1000)),
+            Some(AxError::AlreadyExists)
+        );
+        assert_eq!(
+            err(aspace.fetch_and_clear_dirty_log(gpa(0x1000), 0x3000)),
+            Some(AxError::InvalidInput)
+        );
+        assert_eq!(
+            err(aspace.fetch_and_clear_dirty_log(gpa(0x1800), 0x1000)),
+            Some(AxError::InvalidInput)
+        );
+        aspace.stop_dirty_log().unwrap();
+        assert_eq!(err(aspace.stop_dirty_log()), Some(AxError::BadState));
+    }
+
+    std::thread_local! {
+        static FAIL_ALLOC: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+    }
+
+    /// A HAL whose frame allocations fail on demand.
+    struct FailingHal;
+
+    impl AxMmHal for FailingHal {
+        fn alloc_frame() -> Option<HostPhysAddr> {
+            if FAIL_ALLOC.get() {
+                return None;
+            }
+            <HostHal as AxMmHal>::alloc_frame()
+        }
+
+        fn dealloc_frame(paddr: HostPhysAddr) {
+            <HostHal as AxMmHal>::dealloc_frame(paddr)
+        }
+
+        fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+            <HostHal as AxMmHal>::phys_to_virt(paddr)
+        }
+
+        fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
+            <HostHal as AxMmHal>::virt_to_phys(vaddr)
+        }
+    }
+
+    impl PagingHandler for FailingHal {
+        fn alloc_frame() -> Option<HostPhysAddr> {
+            <Self as AxMmHal>::alloc_frame()
+        }
+
+        fn dealloc_frame(paddr: HostPhysAddr) {
+            <Self as AxMmHal>::dealloc_frame(paddr)
+        }
+
+        fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+            <Self as AxMmHal>::phys_to_virt(paddr)
+        }
+    }
+
+    #[test]
+    fn split_failure_rolls_back() {
+        let mut aspace = AddrSpace::<FailingHal>::new_with_hal(gpa(0), 0x100_0000).unwrap();
+        aspace
+            .map_alloc(gpa(0x1f_e000), 0x2000, rw(), true)
+            .unwrap();
+        aspace
+            .map_linear(
+                gpa(0x20_0000),
+                PhysAddr::from(0x4000_0000),
+                0x20_0000,
+                rw(),
+                None,
+            )
+            .unwrap();
+
+        // Splitting the huge page needs a new page table.
+        FAIL_ALLOC.set(true);
+        let res = aspace.start_dirty_log(gpa(0x1f_e000), 0x4000);
+        FAIL_ALLOC.set(false);
+        assert_eq!(err(res), Some(AxError::NoMemory));
+        // The pages protected before the failure are writable again.
+        for addr in [0x1f_e000, 0x1f_f000] {
+            let (_, flags, _) = aspace.page_table().query(gpa(addr)).unwrap();
+            assert!(flags.contains(MappingFlags::WRITE));
+        }
+        // The huge page stays mapped as it was.
+        let (paddr, flags, page_size) = aspace.page_table().query(gpa(0x20_1000)).unwrap();
+        assert_eq!(paddr, PhysAddr::from(0x4000_1000));
+        assert_eq!(page_size, PageSize::Size2M);
+        assert!(flags.contains(MappingFlags::WRITE));
+        assert_eq!(err(aspace.stop_dirty_log()), Some(AxError::BadState));
+        aspace.start_dirty_log(gpa(0x1f_e000), 0x2000).unwrap();
+    }
+
+    #[cfg(any(target_arch = "x86_64", feature = "ept"))]
+    #[test]
+    fn hardware_dirty_logging() {
+        use crate::npt::DirtyState;
+        use crate::{EptConfig, EptFormat};
+
+        let config = EptConfig {
+            accessed_dirty: true,
+            ..Default::default()
+        };
+        let mut aspace =
+            AddrSpace::<HostHal, EptFormat>::new_with_config(gpa(0), 0x100_0000, config).unwrap();
+        aspace.map_alloc(gpa(0x1000), 0x3000, rw(), true).unwrap();
+        aspace.write_bytes(gpa(0x1000), &[1]).unwrap();
+        aspace.start_dirty_log(gpa(0x1000), 0x3000).unwrap();
+        // The pages stay writable, the processor sets the dirty flags.
+        let (_, flags, _) = aspace.page_table().query(gpa(0x1000)).unwrap();
+        assert!(flags.contains(MappingFlags::WRITE));
+        assert_eq!(
+            aspace
+                .fetch_and_clear_dirty_log(gpa(0x1000), 0x3000)
+                .unwrap(),
+            [0]
+        );
+
+        aspace.pt.leaf_entry_mut(gpa(0x3000)).unwrap().0.set_dirty();
+        aspace.write_bytes(gpa(0x1000), &[2]).unwrap();
+        assert_eq!(
+            aspace
+                .fetch_and_clear_dirty_log(gpa(0x1000), 0x3000)
+                .unwrap(),
+            [0b101]
+        );
+        assert!(!aspace.pt.leaf_entry_mut(gpa(0x3000)).unwrap().0.is_dirty());
+        assert_eq!(
+            aspace
+                .fetch_and_clear_dirty_log(gpa(0x1000), 0x3000)
+                .unwrap(),
+            [0]
+        );
+        aspace.stop_dirty_log().unwrap();
+    }
+}
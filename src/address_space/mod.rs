@@ -1,27 +1,54 @@
-use alloc::vec::Vec;
+use alloc::{sync::Arc, vec, vec::Vec};
 use core::fmt;
 
 use axerrno::{AxError, AxResult, ax_err};
-use memory_addr::{MemoryAddr, PhysAddr, is_aligned_4k};
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PageIter4K, PhysAddr, is_aligned_4k};
 use memory_set::{MemoryArea, MemorySet};
 use page_table_multiarch::PagingHandler;
 
-use crate::npt::NestedPageTable as PageTable;
-use crate::{GuestPhysAddr, GuestPhysAddrRange, mapping_err_to_ax_err};
+use crate::device::AccessWidth;
+use crate::guest_memory::{GuestMemory, copy_from_volatile, copy_to_volatile};
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+use crate::npt::EptFormat;
+use crate::npt::{
+    DirtyState, NestedPageTable as PageTable, RootAllocator, TableGeometry, VMID_ALLOCATOR,
+    vmid_bits,
+};
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+use crate::npt::{GStageFormat, GStageMode};
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+use crate::npt::{Stage2Config, Stage2Format};
+use crate::{
+    AxMmHal, FaultAreaInfo, GuestPhysAddr, GuestPhysAddrRange, NativeFormat, NestedFormat,
+    NestedPageFaultInfo, NestedTlbFlush, PageFaultOutcome, mapping_err_to_ax_err,
+};
 
 mod backend;
+mod dirty_log;
 
-pub use backend::Backend;
+use backend::split_huge_page_4k;
+use dirty_log::DirtyLog;
+
+pub use backend::{Backend, CowFrames, HugeFrameAllocator, MmioAccess, MmioDevice};
 pub use page_table_entry::MappingFlags;
+pub use page_table_multiarch::PageSize;
 
 /// The virtual memory address space.
-pub struct AddrSpace<H: PagingHandler> {
+///
+/// Its nested page table has the format `F`, which is the one of the current
+/// architecture by default.
+pub struct AddrSpace<H: PagingHandler, F: NestedFormat = NativeFormat> {
     va_range: GuestPhysAddrRange,
-    areas: MemorySet<Backend<H>>,
-    pt: PageTable<H>,
+    areas: MemorySet<Backend<H, F>>,
+    pt: PageTable<H, F>,
+    dirty_log: Option<DirtyLog>,
+    hw_dirty_bit: bool,
+    tlb_flush: fn(NestedTlbFlush),
+    vmid_bits: fn() -> usize,
+    vmid: Option<u16>,
 }
 
-impl<H: PagingHandler> AddrSpace<H> {
+impl<H: PagingHandler, F: NestedFormat> AddrSpace<H, F> {
     /// Returns the address space base.
     pub const fn base(&self) -> GuestPhysAddr {
         self.va_range.start
@@ -38,7 +65,7 @@ impl<H: PagingHandler> AddrSpace<H> {
     }
 
     /// Returns the reference to the inner page table.
-    pub const fn page_table(&self) -> &PageTable<H> {
+    pub const fn page_table(&self) -> &PageTable<H, F> {
         &self.pt
     }
 
@@ -48,31 +75,131 @@ impl<H: PagingHandler> AddrSpace<H> {
     }
 
     /// Checks if the address space contains the given address range.
+    ///
+    /// A range whose end overflows is never contained.
     pub fn contains_range(&self, start: GuestPhysAddr, size: usize) -> bool {
-        self.va_range
-            .contains_range(GuestPhysAddrRange::from_start_size(start, size))
+        GuestPhysAddrRange::try_from_start_size(start, size)
+            .is_some_and(|range| self.va_range.contains_range(range))
     }
 
     /// Creates a new empty address space.
+    ///
+    /// The TLB entries of the nested page table are invalidated by executing
+    /// the instruction on the current CPU directly, see
+    /// [`AddrSpace::new_with_hal`] to route them through the HAL instead.
+    ///
+    /// It fails with the RISC-V G-stage format, whose root table spans
+    /// several contiguous frames, which can only be allocated by
+    /// [`AddrSpace::new_with_hal`].
     pub fn new_empty(base: GuestPhysAddr, size: usize) -> AxResult<Self> {
+        let geometry = F::geometry(&F::Config::default());
+        if geometry.root_tables() > 1 {
+            return ax_err!(
+                Unsupported,
+                "the root table needs contiguous frames, use AddrSpace::new_with_hal"
+            );
+        }
+        Self::new_with_table(
+            base,
+            size,
+            geometry,
+            RootAllocator::single::<H>(),
+            F::flush_tlb,
+            vmid_bits,
+        )
+    }
+
+    /// Creates a new empty address space with a nested page table of
+    /// `geometry`.
+    ///
+    /// A VMID is allocated for the address space if the nested translations
+    /// are tagged by VMIDs, whose width is given by `vmid_bits`. It fails if
+    /// the width differs from the one of the VMIDs already allocated.
+    fn new_with_table(
+        base: GuestPhysAddr,
+        size: usize,
+        geometry: TableGeometry,
+        root_alloc: RootAllocator,
+        tlb_flush: fn(NestedTlbFlush),
+        vmid_bits: fn() -> usize,
+    ) -> AxResult<Self> {
+        let Some(va_range) = GuestPhysAddrRange::try_from_start_size(base, size) else {
+            return ax_err!(InvalidInput, "address range overflows");
+        };
+        if va_range.end.as_usize() > 1 << geometry.input_bits {
+            return ax_err!(InvalidInput, "address out of the range of the page table");
+        }
+        let pt = PageTable::<H, F>::try_new(geometry, root_alloc, F::huge_page_supported)
+            .map_err(|_| AxError::NoMemory)?;
         Ok(Self {
-            va_range: GuestPhysAddrRange::from_start_size(base, size),
+            va_range,
             areas: MemorySet::new(),
-            pt: PageTable::try_new().map_err(|_| AxError::NoMemory)?,
+            pt,
+            dirty_log: None,
+            hw_dirty_bit: false,
+            tlb_flush,
+            vmid_bits,
+            vmid: VMID_ALLOCATOR.alloc(vmid_bits, tlb_flush)?,
         })
     }
 
+    /// Returns the VMID tagging the nested translations of the address space
+    /// in the TLB, or `None` on the architectures without VMIDs, like x86_64
+    /// whose translations are tagged by the EPTP.
+    ///
+    /// It is allocated when the address space is created, and freed when it
+    /// is dropped.
+    pub const fn vmid(&self) -> Option<u16> {
+        self.vmid
+    }
+
+    /// Invalidates the TLB entries of the nested page table.
+    ///
+    /// The operations changing present mappings call it once when they are
+    /// done, rather than once per page.
+    fn flush_tlb(&self) {
+        (self.tlb_flush)(match self.tlb_context() {
+            Some(context) => NestedTlbFlush::Context(context),
+            None => NestedTlbFlush::All,
+        });
+    }
+
+    /// Invalidates the TLB entries of the page at `vaddr`, after a page fault
+    /// changed its mapping.
+    fn flush_tlb_page(&self, vaddr: GuestPhysAddr) {
+        (self.tlb_flush)(NestedTlbFlush::Page {
+            context: self.tlb_context(),
+            gpa: vaddr,
+        });
+    }
+
+    /// Returns the context identifying the nested page table in targeted
+    /// invalidations, see [`NestedFormat::tlb_context`].
+    fn tlb_context(&self) -> Option<u64> {
+        F::tlb_context(self.pt.root_paddr(), self.pt.geometry(), self.vmid)
+    }
+
     /// Add a new linear mapping.
     ///
     /// See [`Backend`] for more details about the mapping backends.
     ///
     /// The `flags` parameter indicates the mapping permissions and attributes.
+    /// Flags that the nested page table cannot encode, like executable device
+    /// memory on AArch64, are rejected.
+    ///
+    /// The memory attribute of the pages is `mem_attr` if it is given, like an
+    /// EPT memory type and PAT override on x86_64, or a device type or
+    /// cacheability and the exception levels executing the pages on AArch64.
+    /// Otherwise it is derived from `flags`. It is kept when the permissions
+    /// are changed by [`AddrSpace::protect`], and reported by
+    /// [`AddrSpace::translate_mem_attr`].
     pub fn map_linear(
         &mut self,
         start_vaddr: GuestPhysAddr,
         start_paddr: PhysAddr,
         size: usize,
         flags: MappingFlags,
+        mem_attr: Option<F::MemAttr>,
     ) -> AxResult {
         if !self.contains_range(start_vaddr, size) {
             return ax_err!(InvalidInput, "address out of range");
@@ -80,9 +207,22 @@ impl<H: PagingHandler> AddrSpace<H> {
         if !start_vaddr.is_aligned_4k() || !start_paddr.is_aligned_4k() || !is_aligned_4k(size) {
             return ax_err!(InvalidInput, "address not aligned");
         }
+        let supported = match mem_attr {
+            Some(mem_attr) => F::mem_attr_supported(mem_attr, flags),
+            None => F::flags_supported(flags),
+        };
+        if !supported {
+            return ax_err!(InvalidInput, "mapping flags not supported");
+        }
 
-        let offset = start_vaddr.as_usize() - start_paddr.as_usize();
-        let area = MemoryArea::new(start_vaddr, size, flags, Backend::new_linear(offset));
+        // The offset wraps around when the physical address is the higher one.
+        let offset = start_vaddr.as_usize().wrapping_sub(start_paddr.as_usize());
+        let area = MemoryArea::new(
+            start_vaddr,
+            size,
+            flags,
+            Backend::new_linear(offset, mem_attr),
+        );
         self.areas
             .map(area, &mut self.pt, false)
             .map_err(mapping_err_to_ax_err)?;
@@ -94,6 +234,8 @@ impl<H: PagingHandler> AddrSpace<H> {
     /// See [`Backend`] for more details about the mapping backends.
     ///
     /// The `flags` parameter indicates the mapping permissions and attributes.
+    /// Flags that the nested page table cannot encode, like executable device
+    /// memory on AArch64, are rejected.
     pub fn map_alloc(
         &mut self,
         start: GuestPhysAddr,
@@ -110,6 +252,9 @@ impl<H: PagingHandler> AddrSpace<H> {
         if !start.is_aligned_4k() || !is_aligned_4k(size) {
             return ax_err!(InvalidInput, "address not aligned");
         }
+        if !F::flags_supported(flags) {
+            return ax_err!(InvalidInput, "mapping flags not supported");
+        }
 
         let area = MemoryArea::new(start, size, flags, Backend::new_alloc(populate));
         self.areas
@@ -118,7 +263,87 @@ impl<H: PagingHandler> AddrSpace<H> {
         Ok(())
     }
 
+    /// Registers an MMIO region trapping the guest accesses to the address
+    /// range of `device`.
+    ///
+    /// Nothing is mapped in the region, so that the accesses cause nested page
+    /// faults, which are reported as [`PageFaultOutcome::Mmio`] by
+    /// [`AddrSpace::handle_page_fault`]. The region may be unaligned,
+    /// and is removed by [`AddrSpace::unregister_mmio`].
+    pub fn register_mmio(&mut self, device: Arc<MmioDevice>) -> AxResult {
+        let range = device.address_range();
+        if !self.contains_range(range.start, range.size()) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if range.is_empty() {
+            return ax_err!(InvalidInput, "empty MMIO region");
+        }
+
+        let flags = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE;
+        let area = MemoryArea::new(range.start, range.size(), flags, Backend::new_mmio(device));
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Removes the MMIO region registered over `region`.
+    pub fn unregister_mmio(&mut self, region: GuestPhysAddrRange) -> AxResult {
+        match self.areas.find(region.start) {
+            Some(area)
+                if matches!(area.backend(), Backend::Mmio { .. }) && area.va_range() == region => {}
+            _ => return ax_err!(NotFound, "no MMIO region registered over the range"),
+        }
+        self.areas
+            .unmap(region.start, region.size(), &mut self.pt)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+
+    /// Returns the MMIO region containing `vaddr`, with the offset of `vaddr`
+    /// in the region, if any.
+    pub fn mmio_access(&self, vaddr: GuestPhysAddr) -> Option<MmioAccess> {
+        self.areas.find(vaddr)?.backend().mmio_access(vaddr)
+    }
+
+    /// Dispatches a guest read of `width` at `vaddr` to the device of the MMIO
+    /// region containing it.
+    pub fn handle_mmio_read(&self, vaddr: GuestPhysAddr, width: AccessWidth) -> AxResult<usize> {
+        match self.mmio_access(vaddr) {
+            Some(access) => access.device.read(vaddr, width),
+            None => ax_err!(NotFound, "no MMIO region at the address"),
+        }
+    }
+
+    /// Dispatches a guest write of `value` of `width` at `vaddr` to the device
+    /// of the MMIO region containing it.
+    pub fn handle_mmio_write(
+        &self,
+        vaddr: GuestPhysAddr,
+        width: AccessWidth,
+        value: usize,
+    ) -> AxResult {
+        match self.mmio_access(vaddr) {
+            Some(access) => access.device.write(vaddr, width, value),
+            None => ax_err!(NotFound, "no MMIO region at the address"),
+        }
+    }
+
+    /// Checks if the given address range covers only a part of an MMIO
+    /// region, which would then no longer match the range of its device.
+    fn splits_mmio_region(&self, start: GuestPhysAddr, size: usize) -> bool {
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        self.areas.iter().any(|area| {
+            matches!(area.backend(), Backend::Mmio { .. })
+                && area.va_range().overlaps(range)
+                && !range.contains_range(area.va_range())
+        })
+    }
+
     /// Removes mappings within the specified virtual address range.
+    ///
+    /// MMIO regions must be removed as a whole, the range must not cover only
+    /// a part of one.
     pub fn unmap(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
         if !self.contains_range(start, size) {
             return ax_err!(InvalidInput, "address out of range");
@@ -126,37 +351,363 @@ impl<H: PagingHandler> AddrSpace<H> {
         if !start.is_aligned_4k() || !is_aligned_4k(size) {
             return ax_err!(InvalidInput, "address not aligned");
         }
+        if self.splits_mmio_region(start, size) {
+            return ax_err!(InvalidInput, "range splits an MMIO region");
+        }
+
+        let res = self.areas.unmap(start, size, &mut self.pt);
+        self.flush_tlb();
+        res.map_err(mapping_err_to_ax_err)
+    }
+
+    /// Changes the permissions of the mappings within the specified virtual
+    /// address range.
+    ///
+    /// Areas that partially overlap the range are split, and the leaf entries
+    /// of the affected mappings are rewritten with the new `flags`. Their
+    /// memory attributes are kept. MMIO regions are not split, the range must
+    /// cover them as a whole.
+    pub fn protect(&mut self, start: GuestPhysAddr, size: usize, flags: MappingFlags) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if self.splits_mmio_region(start, size) {
+            return ax_err!(InvalidInput, "range splits an MMIO region");
+        }
+        if !F::flags_supported(flags) {
+            return ax_err!(InvalidInput, "mapping flags not supported");
+        }
+
+        let res = self.areas.protect(
+            start,
+            size,
+            |old_flags| (old_flags != flags).then_some(flags),
+            &mut self.pt,
+        );
+        self.flush_tlb();
+        res.map_err(mapping_err_to_ax_err)
+    }
+
+    /// Creates a child address space that shares the memory of this one.
+    ///
+    /// The child's nested page table is built from the areas of this address
+    /// space. Frames of allocation areas are shared read-only by both address
+    /// spaces through the copy-on-write backend, and each side gets a private
+    /// copy on its first write fault (see [`Backend::Cow`]). Linear areas are
+    /// mapped to the same host memory in the child.
+    ///
+    /// It fails if dirty page logging is active. If it fails on an area, the
+    /// areas that have not been shared yet are left unchanged.
+    pub fn fork(&mut self) -> AxResult<Self> {
+        if self.dirty_log.is_some() {
+            return ax_err!(BadState, "dirty log is active");
+        }
+        let mut child = Self::new_with_table(
+            self.base(),
+            self.size(),
+            self.pt.geometry(),
+            self.pt.root_allocator(),
+            self.tlb_flush,
+            self.vmid_bits,
+        )?;
+        child.hw_dirty_bit = self.hw_dirty_bit;
+        let areas: Vec<_> = self
+            .areas
+            .iter()
+            .map(|area| {
+                let backend = area.backend().clone();
+                (area.start(), area.size(), area.flags(), backend)
+            })
+            .collect();
+        let res = areas
+            .into_iter()
+            .try_for_each(|(start, size, flags, backend)| {
+                self.fork_area(&mut child, start, size, flags, backend)
+            });
+        // The shared pages are remapped read-only in this address space.
+        self.flush_tlb();
+        res.map(|_| child)
+    }
 
+    /// Shares an area of this address space with `child`, see
+    /// [`AddrSpace::fork`].
+    ///
+    /// Everything that may fail is done before the area is replaced in this
+    /// address space, which is left unchanged on error.
+    fn fork_area(
+        &mut self,
+        child: &mut Self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        backend: Backend<H, F>,
+    ) -> AxResult {
+        if !backend.prepare_fork(start, size, &mut self.pt) {
+            return ax_err!(NoMemory, "fork area failed");
+        }
+        let shared = backend.fork(start, size, &self.pt);
+        child
+            .areas
+            .map(
+                MemoryArea::new(start, size, flags, shared.clone()),
+                &mut child.pt,
+                false,
+            )
+            .map_err(mapping_err_to_ax_err)?;
+        if !backend.is_shared_by_fork() {
+            // Linear and MMIO areas are kept as they are.
+            return Ok(());
+        }
+        // The prepared pages are detached from the old area, whose backend
+        // releases nothing, and remapped without allocating tables.
+        backend.detach_pages(start, size, &mut self.pt);
         self.areas
             .unmap(start, size, &mut self.pt)
             .map_err(mapping_err_to_ax_err)?;
-        Ok(())
+        self.areas
+            .map(
+                MemoryArea::new(start, size, flags, shared),
+                &mut self.pt,
+                false,
+            )
+            .map_err(mapping_err_to_ax_err)
     }
 
     /// Removes all mappings in the address space.
     pub fn clear(&mut self) {
         self.areas.clear(&mut self.pt).unwrap();
+        self.flush_tlb();
     }
 
     /// Handles a page fault at the given address.
     ///
     /// `access_flags` indicates the access type that caused the page fault.
     ///
-    /// Returns `true` if the page fault is handled successfully (not a real
-    /// fault).
-    pub fn handle_page_fault(&mut self, vaddr: GuestPhysAddr, access_flags: MappingFlags) -> bool {
+    /// Returns [`PageFaultOutcome::Handled`] if the page fault is handled
+    /// successfully (not a real fault), or the reason why it cannot be.
+    pub fn handle_page_fault(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+    ) -> PageFaultOutcome {
         if !self.va_range.contains(vaddr) {
-            return false;
+            return PageFaultOutcome::Unmapped;
         }
-        if let Some(area) = self.areas.find(vaddr) {
-            let orig_flags = area.flags();
-            if !orig_flags.contains(access_flags) {
-                return false;
-            }
+        let Some(area) = self.areas.find(vaddr) else {
+            return PageFaultOutcome::Unmapped;
+        };
+        let orig_flags = area.flags();
+        let area_info = FaultAreaInfo {
+            range: area.va_range(),
+            flags: orig_flags,
+        };
+        if !orig_flags.contains(access_flags) {
+            return PageFaultOutcome::PermissionDenied {
+                area: area_info,
+                access_flags,
+            };
+        }
+        if let Some(log) = self.dirty_log.as_mut()
+            && access_flags.contains(MappingFlags::WRITE)
+            && log.take_protected(vaddr)
+        {
+            // The first write to a page write-protected for logging.
+            log.mark_dirty(vaddr);
+            return match self.pt.protect(vaddr, orig_flags) {
+                Ok(_) => {
+                    self.flush_tlb_page(vaddr);
+                    PageFaultOutcome::Handled
+                }
+                Err(_) => PageFaultOutcome::Unexpected { area: area_info },
+            };
+        }
+        // Filling an entry that was not present needs no invalidation.
+        let mut changed = self.pt.query(vaddr).is_ok();
+        let outcome =
             area.backend()
-                .handle_page_fault(vaddr, orig_flags, &mut self.pt)
-        } else {
-            false
+                .handle_page_fault(vaddr, orig_flags, &mut self.pt, area.va_range());
+        if outcome.is_handled()
+            && let Some(log) = self.dirty_log.as_mut()
+            && log.range().contains(vaddr)
+        {
+            Self::track_dirty_page(log, &mut self.pt, vaddr, access_flags);
+            changed = true;
+        }
+        if changed && outcome.is_handled() {
+            self.flush_tlb_page(vaddr);
+        }
+        outcome
+    }
+
+    /// Handles the nested page fault described by `info`, see
+    /// [`AddrSpace::handle_page_fault`].
+    pub fn handle_nested_page_fault(&mut self, info: &NestedPageFaultInfo) -> PageFaultOutcome {
+        self.handle_page_fault(info.fault_guest_paddr, info.access_flags)
+    }
+
+    /// Enables or disables the hardware dirty state for dirty page logging.
+    ///
+    /// The hardware must be configured accordingly by the caller, i.e., the
+    /// accessed and dirty flags enabled in the EPTP on x86_64, or the hardware
+    /// management of dirty state (`VTCR_EL2.HD`) on AArch64, as set by
+    /// `AddrSpace::eptp` and `AddrSpace::vtcr`. It takes effect from the next
+    /// [`AddrSpace::start_dirty_log`], and is ignored on the architectures
+    /// without hardware dirty state, or if the CPU does not support it, see
+    /// [`NestedFormat::hw_dirty_supported`].
+    pub fn set_hw_dirty_bit(&mut self, enable: bool) {
+        self.hw_dirty_bit = enable && F::hw_dirty_supported();
+    }
+
+    /// Starts dirty page logging over the specified range.
+    ///
+    /// Huge pages in the range are split into 4K pages. Writes are recorded by
+    /// the hardware dirty state if enabled, otherwise the writable pages are
+    /// write-protected and the first write to each page is trapped by
+    /// [`AddrSpace::handle_page_fault`].
+    ///
+    /// Only one range can be logged at a time. Mappings created or changed in
+    /// the range while logging are not tracked.
+    pub fn start_dirty_log(&mut self, start: GuestPhysAddr, size: usize) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if self.dirty_log.is_some() {
+            return ax_err!(AlreadyExists, "dirty log already started");
+        }
+
+        let range = GuestPhysAddrRange::from_start_size(start, size);
+        let mut log = DirtyLog::new(range, self.hw_dirty_bit);
+        for addr in PageIter4K::new(start, start + size).unwrap() {
+            if !split_huge_page_4k(&mut self.pt, addr) {
+                // The pages already tracked are restored, as no log will
+                // handle their writes.
+                self.restore_dirty_log(&mut log);
+                return ax_err!(NoMemory, "split huge page failed");
+            }
+            if log.is_hw() {
+                if let Some((entry, _)) = self.pt.leaf_entry_mut(addr) {
+                    entry.start_dirty_tracking();
+                }
+            } else if let Ok((_, flags, _)) = self.pt.query(addr)
+                && flags.contains(MappingFlags::WRITE)
+            {
+                let _ = self.pt.protect(addr, flags - MappingFlags::WRITE);
+                log.set_protected(addr);
+            }
+        }
+        self.flush_tlb();
+        self.dirty_log = Some(log);
+        Ok(())
+    }
+
+    /// Stops dirty page logging, and restores the write permission of the
+    /// pages in the logged range.
+    ///
+    /// The huge pages split when starting the logging are not merged back.
+    pub fn stop_dirty_log(&mut self) -> AxResult {
+        let Some(mut log) = self.dirty_log.take() else {
+            return ax_err!(BadState, "dirty log not started");
+        };
+        self.restore_dirty_log(&mut log);
+        Ok(())
+    }
+
+    /// Stops tracking the writes to the pages of `log`, and restores their
+    /// write permission.
+    fn restore_dirty_log(&mut self, log: &mut DirtyLog) {
+        let range = log.range();
+        for addr in PageIter4K::new(range.start, range.end).unwrap() {
+            if log.is_hw() {
+                if let Some((entry, _)) = self.pt.leaf_entry_mut(addr) {
+                    entry.stop_dirty_tracking();
+                }
+            } else if log.take_protected(addr)
+                && let Ok((_, flags, _)) = self.pt.query(addr)
+            {
+                let _ = self.pt.protect(addr, flags | MappingFlags::WRITE);
+            }
+        }
+        self.flush_tlb();
+    }
+
+    /// Returns the bitmap of the pages written in the specified range since
+    /// the last fetch, and clears their dirty state.
+    ///
+    /// Bit `i` of the bitmap (bit `i % 64` of word `i / 64`) is set if the page
+    /// at `start + i * 4K` is dirty. The range must be within the logged range.
+    pub fn fetch_and_clear_dirty_log(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+    ) -> AxResult<Vec<u64>> {
+        let Some(log) = self.dirty_log.as_mut() else {
+            return ax_err!(BadState, "dirty log not started");
+        };
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if !GuestPhysAddrRange::try_from_start_size(start, size)
+            .is_some_and(|range| log.range().contains_range(range))
+        {
+            return ax_err!(InvalidInput, "address out of the logged range");
+        }
+
+        let mut bitmap = vec![0u64; (size / PAGE_SIZE_4K).div_ceil(64)];
+        for (i, addr) in PageIter4K::new(start, start + size).unwrap().enumerate() {
+            let mut dirty = log.take_dirty(addr);
+            if log.is_hw() {
+                if let Some((entry, _)) = self.pt.leaf_entry_mut(addr) {
+                    dirty |= entry.is_dirty();
+                    entry.clear_dirty();
+                }
+            } else if dirty {
+                // Write-protect the page again to trap the next write.
+                if let Ok((_, flags, _)) = self.pt.query(addr)
+                    && flags.contains(MappingFlags::WRITE)
+                {
+                    let _ = self.pt.protect(addr, flags - MappingFlags::WRITE);
+                    log.set_protected(addr);
+                }
+            }
+            if dirty {
+                bitmap[i / 64] |= 1 << (i % 64);
+            }
+        }
+        self.flush_tlb();
+        Ok(bitmap)
+    }
+
+    /// Tracks the page mapped by a page fault handled by the backend, while
+    /// dirty page logging is active.
+    fn track_dirty_page(
+        log: &mut DirtyLog,
+        pt: &mut PageTable<H, F>,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+    ) {
+        if !split_huge_page_4k(pt, vaddr) {
+            warn!("failed to split huge page at {:?} for dirty log", vaddr);
+        }
+        if access_flags.contains(MappingFlags::WRITE) {
+            log.mark_dirty(vaddr);
+        }
+        if log.is_hw() {
+            if let Some((entry, _)) = pt.leaf_entry_mut(vaddr) {
+                entry.start_dirty_tracking();
+            }
+        } else if !access_flags.contains(MappingFlags::WRITE)
+            && let Ok((_, flags, _)) = pt.query(vaddr)
+            && flags.contains(MappingFlags::WRITE)
+            && pt.protect(vaddr, flags - MappingFlags::WRITE).is_ok()
+        {
+            // Trap the first write to the page mapped by a read access.
+            log.set_protected(vaddr);
         }
     }
 
@@ -176,6 +727,18 @@ impl<H: PagingHandler> AddrSpace<H> {
             .ok()
     }
 
+    /// Returns the memory attribute of the page mapping the given guest
+    /// physical address, like the EPT memory type and PAT override on x86_64.
+    ///
+    /// Returns `None` if the address is out of range or not mapped, or if the
+    /// attribute of the page is not a valid encoding.
+    pub fn translate_mem_attr(&self, vaddr: GuestPhysAddr) -> Option<F::MemAttr> {
+        if !self.va_range.contains(vaddr) {
+            return None;
+        }
+        self.pt.query_mem_attr(vaddr).ok().flatten()
+    }
+
     /// Translate&Copy the given `VirtAddr` with LENGTH len to a mutable u8 Vec through page table.
     ///
     /// Returns `None` if the virtual address is out of range or not mapped.
@@ -188,11 +751,11 @@ impl<H: PagingHandler> AddrSpace<H> {
             return None;
         }
         if let Some(area) = self.areas.find(vaddr) {
-            if len > area.size() {
+            let remaining = area.end().sub_addr(vaddr);
+            if len > remaining {
                 warn!(
-                    "AddrSpace translated_byte_buffer len {:#x} exceeds area length {:#x}",
-                    len,
-                    area.size()
+                    "AddrSpace translated_byte_buffer len {:#x} exceeds area remaining length {:#x}",
+                    len, remaining
                 );
                 return None;
             }
@@ -209,7 +772,8 @@ impl<H: PagingHandler> AddrSpace<H> {
 
             let mut v = Vec::new();
             while start < end {
-                let (start_paddr, _, page_size) = self.page_table().query(start).unwrap();
+                // Pages of lazy mappings may not be mapped yet.
+                let (start_paddr, _, page_size) = self.page_table().query(start).ok()?;
                 let mut end_va = start.align_down(page_size) + page_size.into();
                 end_va = end_va.min(end);
 
@@ -227,6 +791,120 @@ impl<H: PagingHandler> AddrSpace<H> {
         }
     }
 
+    /// Reads guest memory starting at `vaddr` into `buf`.
+    ///
+    /// The access may span multiple pages and areas. Pages of lazy mappings
+    /// are faulted in on demand, and the access is checked against the
+    /// permissions of the areas like a guest read.
+    ///
+    /// Returns [`AxError::BadAddress`] if the range contains unmapped holes,
+    /// or [`AxError::Unsupported`] if it contains device memory.
+    pub fn read_bytes(&mut self, vaddr: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        self.for_each_guest_chunk(vaddr, buf.len(), MappingFlags::READ, |offset, ptr, len| {
+            let dst = &mut buf[offset..offset + len];
+            unsafe { core::ptr::copy_nonoverlapping(ptr, dst.as_mut_ptr(), len) };
+        })
+    }
+
+    /// Writes `buf` into guest memory starting at `vaddr`.
+    ///
+    /// The access may span multiple pages and areas. Pages of lazy mappings
+    /// are faulted in on demand, and the access is checked against the
+    /// permissions of the areas like a guest write, so copy-on-write pages
+    /// are copied and the pages are recorded by dirty page logging.
+    ///
+    /// Returns [`AxError::BadAddress`] if the range contains unmapped holes,
+    /// or [`AxError::Unsupported`] if it contains device memory. The bytes
+    /// before the failing page are written.
+    pub fn write_bytes(&mut self, vaddr: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        self.for_each_guest_chunk(vaddr, buf.len(), MappingFlags::WRITE, |offset, ptr, len| {
+            let src = &buf[offset..offset + len];
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), ptr, len) };
+        })
+    }
+
+    /// Calls `f` with the offset, the host pointer and the length of each
+    /// chunk of guest memory of `[vaddr, vaddr + len)` that is contiguous in
+    /// a host page.
+    fn for_each_guest_chunk(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        len: usize,
+        access_flags: MappingFlags,
+        mut f: impl FnMut(usize, *mut u8, usize),
+    ) -> AxResult {
+        if !self.contains_range(vaddr, len) {
+            return ax_err!(BadAddress, "address out of range");
+        }
+        let mut offset = 0;
+        while offset < len {
+            let (paddr, page_remaining) = self.access_guest_page(vaddr + offset, access_flags)?;
+            let chunk_len = page_remaining.min(len - offset);
+            f(offset, H::phys_to_virt(paddr).as_mut_ptr(), chunk_len);
+            offset += chunk_len;
+        }
+        Ok(())
+    }
+
+    /// Returns the host physical address of `vaddr`, and the number of bytes
+    /// remaining in its page, faulting the page in if needed.
+    fn access_guest_page(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        access_flags: MappingFlags,
+    ) -> AxResult<(PhysAddr, usize)> {
+        let Some(area) = self.areas.find(vaddr) else {
+            return ax_err!(BadAddress, "guest address not mapped");
+        };
+        if area.flags().contains(MappingFlags::DEVICE) {
+            return ax_err!(Unsupported, "guest address is device memory");
+        }
+        if !area.flags().contains(access_flags) {
+            return ax_err!(PermissionDenied, "guest access not permitted");
+        }
+
+        let write = access_flags.contains(MappingFlags::WRITE);
+        if write {
+            self.mark_host_write(vaddr);
+        }
+        let accessible = |pt: &PageTable<H, F>| {
+            pt.query(vaddr)
+                .is_ok_and(|(_, flags, _)| flags.contains(access_flags))
+        };
+        if !accessible(&self.pt) {
+            match self.handle_page_fault(vaddr, access_flags) {
+                PageFaultOutcome::Handled => {}
+                PageFaultOutcome::NoMemory { .. } => return ax_err!(NoMemory),
+                _ => return ax_err!(BadState, "fault in guest page failed"),
+            }
+            if write {
+                self.mark_host_write(vaddr);
+            }
+        }
+        match self.pt.query(vaddr) {
+            Ok((paddr, flags, page_size)) if flags.contains(access_flags) => Ok((
+                paddr,
+                page_size as usize - page_size.align_offset(vaddr.as_usize()),
+            )),
+            _ => ax_err!(BadState, "guest page not accessible"),
+        }
+    }
+
+    /// Records a write of the hypervisor to the page of `vaddr` in the dirty
+    /// page log.
+    fn mark_host_write(&mut self, vaddr: GuestPhysAddr) {
+        if let Some(log) = self.dirty_log.as_mut()
+            && log.range().contains(vaddr)
+        {
+            log.mark_dirty(vaddr);
+            if log.is_hw()
+                && let Some((entry, _)) = self.pt.leaf_entry_mut(vaddr)
+            {
+                entry.set_dirty();
+            }
+        }
+    }
+
     /// Translates the given `VirtAddr` into `PhysAddr`,
     /// and returns the size of the `MemoryArea` corresponding to the target vaddr.
     ///
@@ -246,7 +924,152 @@ impl<H: PagingHandler> AddrSpace<H> {
     }
 }
 
-impl<H: PagingHandler> fmt::Debug for AddrSpace<H> {
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+impl<H: PagingHandler> AddrSpace<H, GStageFormat> {
+    /// Returns the value of `hgatp` selecting the G-stage table and the VMID
+    /// of the address space.
+    pub fn hgatp(&self) -> u64 {
+        GStageMode::from_geometry(self.pt.geometry())
+            .hgatp(self.page_table_root(), self.vmid.unwrap_or(0))
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+impl<H: PagingHandler> AddrSpace<H, Stage2Format> {
+    /// Returns the value of `VTCR_EL2` matching the stage-2 table of the
+    /// address space, see [`Stage2Config::vtcr`].
+    ///
+    /// The hardware dirty state is enabled if it is used for dirty page
+    /// logging, see [`AddrSpace::set_hw_dirty_bit`].
+    pub fn vtcr(&self) -> u64 {
+        Stage2Config::from_geometry(self.pt.geometry()).vtcr(self.hw_dirty_bit)
+    }
+
+    /// Returns the value of `VTTBR_EL2` selecting the stage-2 table and the
+    /// VMID of the address space.
+    pub fn vttbr(&self) -> u64 {
+        Stage2Config::vttbr(self.page_table_root(), self.vmid.unwrap_or(0))
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+impl<H: PagingHandler> AddrSpace<H, EptFormat> {
+    /// Returns the EPTP of the extended page table of the address space.
+    ///
+    /// The paging structures are accessed with the write-back memory type if
+    /// supported, and the accessed and dirty flags are enabled if they are
+    /// used for dirty page logging, see [`AddrSpace::set_hw_dirty_bit`].
+    pub fn eptp(&self) -> u64 {
+        crate::npt::eptp(
+            self.page_table_root(),
+            self.pt.geometry().levels,
+            self.hw_dirty_bit,
+        )
+    }
+}
+
+impl<H: PagingHandler + AxMmHal, F: NestedFormat> AddrSpace<H, F> {
+    /// Creates a new empty address space, whose TLB invalidations are issued
+    /// by [`AxMmHal::flush_nested_tlb`].
+    ///
+    /// The root table of the nested page table is allocated with
+    /// [`AxMmHal::alloc_contiguous_frames`] if it spans several frames. The
+    /// address spaces forked from it use the same hooks.
+    pub fn new_with_hal(base: GuestPhysAddr, size: usize) -> AxResult<Self> {
+        Self::new_with_table(
+            base,
+            size,
+            F::geometry(&F::Config::default()),
+            RootAllocator::contiguous::<H>(),
+            H::flush_nested_tlb,
+            H::vmid_bits,
+        )
+    }
+
+    /// Creates a new empty address space like [`AddrSpace::new_with_hal`],
+    /// whose nested page table has the shape given by `config`, i.e., the
+    /// walk length and the accessed and dirty flags of the EPT on x86_64, the
+    /// G-stage translation mode on RISC-V, or the IPA size and the start level
+    /// on AArch64.
+    ///
+    /// The address space must fit in the guest physical addresses of `config`.
+    /// It fails if the hardware does not support `config`, like an EPT walk
+    /// length on x86_64. The hardware dirty state enabled by `config`, like
+    /// the accessed and dirty flags of the EPT, is used for dirty page logging.
+    pub fn new_with_config(base: GuestPhysAddr, size: usize, config: F::Config) -> AxResult<Self> {
+        if !F::config_supported(&config) {
+            return ax_err!(Unsupported, "nested page table configuration not supported");
+        }
+        let mut aspace = Self::new_with_table(
+            base,
+            size,
+            F::geometry(&config),
+            RootAllocator::contiguous::<H>(),
+            H::flush_nested_tlb,
+            H::vmid_bits,
+        )?;
+        aspace.set_hw_dirty_bit(F::config_hw_dirty(&config));
+        Ok(aspace)
+    }
+
+    /// Add a new allocation mapping backed by huge pages.
+    ///
+    /// The mapping is backed by huge pages up to `page_size`, allocated as
+    /// contiguous frames by [`AxMmHal::alloc_contiguous_frames`]. Parts of the
+    /// region that are not aligned to a huge page, or for which contiguous
+    /// frames are not available, are mapped with 4K pages.
+    ///
+    /// See [`AddrSpace::map_alloc`] for the other parameters.
+    pub fn map_alloc_huge(
+        &mut self,
+        start: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        populate: bool,
+        page_size: PageSize,
+    ) -> AxResult {
+        if !self.contains_range(start, size) {
+            return ax_err!(InvalidInput, "address out of range");
+        }
+        if !start.is_aligned_4k() || !is_aligned_4k(size) {
+            return ax_err!(InvalidInput, "address not aligned");
+        }
+        if !F::flags_supported(flags) {
+            return ax_err!(InvalidInput, "mapping flags not supported");
+        }
+
+        let huge = HugeFrameAllocator::new::<H>(page_size);
+        let area = MemoryArea::new(start, size, flags, Backend::new_alloc_huge(populate, huge));
+        self.areas
+            .map(area, &mut self.pt, false)
+            .map_err(mapping_err_to_ax_err)?;
+        Ok(())
+    }
+}
+
+impl<H: PagingHandler, F: NestedFormat> GuestMemory for AddrSpace<H, F> {
+    fn read_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        AddrSpace::read_bytes(self, gpa, buf)
+    }
+
+    fn write_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        AddrSpace::write_bytes(self, gpa, buf)
+    }
+
+    fn read_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult {
+        self.for_each_guest_chunk(gpa, buf.len(), MappingFlags::READ, |offset, ptr, len| {
+            unsafe { copy_from_volatile(ptr, &mut buf[offset..offset + len]) };
+        })
+    }
+
+    fn write_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult {
+        self.for_each_guest_chunk(gpa, buf.len(), MappingFlags::WRITE, |offset, ptr, len| {
+            unsafe { copy_to_volatile(&buf[offset..offset + len], ptr) };
+        })
+    }
+}
+
+impl<H: PagingHandler, F: NestedFormat> fmt::Debug for AddrSpace<H, F> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("AddrSpace")
             .field("va_range", &self.va_range)
@@ -256,8 +1079,170 @@ impl<H: PagingHandler> fmt::Debug for AddrSpace<H> {
     }
 }
 
-impl<H: PagingHandler> Drop for AddrSpace<H> {
+impl<H: PagingHandler, F: NestedFormat> Drop for AddrSpace<H, F> {
     fn drop(&mut self) {
         self.clear();
+        if let Some(vmid) = self.vmid {
+            VMID_ALLOCATOR.free(vmid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::HostHal;
+    use crate::{HostPhysAddr, HostVirtAddr};
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr)
+    }
+
+    fn rw() -> MappingFlags {
+        MappingFlags::READ | MappingFlags::WRITE
+    }
+
+    fn new_addr_space() -> AddrSpace<HostHal> {
+        AddrSpace::new_with_hal(gpa(0), 0x100_0000).unwrap()
+    }
+
+    #[test]
+    fn access_spans_pages_and_areas() {
+        let mut aspace = new_addr_space();
+        aspace.map_alloc(gpa(0x1000), 0x2000, rw(), true).unwrap();
+        aspace.map_alloc(gpa(0x3000), 0x1000, rw(), false).unwrap();
+        aspace
+            .map_alloc_huge(gpa(0x20_0000), 0x20_0000, rw(), true, PageSize::Size2M)
+            .unwrap();
+
+        let data: Vec<u8> = (0..0x1400).map(|i| i as u8).collect();
+        aspace.write_bytes(gpa(0x2c00), &data).unwrap();
+        // The lazy page is faulted in by the write.
+        assert!(aspace.translate(gpa(0x3000)).is_some());
+        let mut buf = vec![0; data.len()];
+        aspace.read_bytes(gpa(0x2c00), &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Accesses within a huge page are done in one chunk.
+        aspace.write_bytes(gpa(0x2f_fff0), &data[..0x20]).unwrap();
+        let mut buf = [0; 0x20];
+        aspace.read_bytes(gpa(0x2f_fff0), &mut buf).unwrap();
+        assert_eq!(buf, data[..0x20]);
+        let chunks = aspace.translated_byte_buffer(gpa(0x20_0ff0), 0x20).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len(), 0x20);
+    }
+
+    #[test]
+    fn read_faults_in_lazy_pages() {
+        let mut aspace = new_addr_space();
+        aspace.map_alloc(gpa(0x1000), 0x1000, rw(), false).unwrap();
+        let mut buf = [0xff; 8];
+        aspace.read_bytes(gpa(0x1ff8), &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+        assert!(aspace.translate(gpa(0x1000)).is_some());
+    }
+
+    #[test]
+    fn access_errors() {
+        let mut aspace = new_addr_space();
+        aspace.map_alloc(gpa(0x1000), 0x1000, rw(), true).unwrap();
+        aspace
+            .map_alloc(gpa(0x3000), 0x1000, MappingFlags::READ, true)
+            .unwrap();
+        aspace
+            .map_alloc(gpa(0x4000), 0x1000, rw() | MappingFlags::DEVICE, true)
+            .unwrap();
+
+        // The bytes before the hole are written.
+        let res = aspace.write_bytes(gpa(0x1ffe), &[0xaa; 4]);
+        assert_eq!(res, Err(AxError::BadAddress));
+        let mut buf = [0; 2];
+        aspace.read_bytes(gpa(0x1ffe), &mut buf).unwrap();
+        assert_eq!(buf, [0xaa; 2]);
+
+        assert_eq!(
+            aspace.write_bytes(gpa(0x3000), &[1]),
+            Err(AxError::PermissionDenied)
+        );
+        assert_eq!(aspace.read_bytes(gpa(0x3000), &mut buf), Ok(()));
+        assert_eq!(
+            aspace.read_bytes(gpa(0x4000), &mut buf),
+            Err(AxError::Unsupported)
+        );
+        assert_eq!(
+            aspace.read_bytes(gpa(0xff_ffff), &mut buf),
+            Err(AxError::BadAddress)
+        );
+        assert_eq!(aspace.read_bytes(gpa(0x1000), &mut []), Ok(()));
+        assert!(aspace.translated_byte_buffer(gpa(0x1800), 0x1000).is_none());
+        assert!(aspace.translated_byte_buffer(gpa(0x2000), 1).is_none());
+    }
+
+    /// A HAL whose nested translations are tagged by 16-bit VMIDs, like the
+    /// AArch64 ones in the host-side software mode.
+    struct VmidHal;
+
+    impl AxMmHal for VmidHal {
+        fn alloc_frame() -> Option<HostPhysAddr> {
+            <HostHal as AxMmHal>::alloc_frame()
+        }
+
+        fn dealloc_frame(paddr: HostPhysAddr) {
+            <HostHal as AxMmHal>::dealloc_frame(paddr)
+        }
+
+        fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+            <HostHal as AxMmHal>::phys_to_virt(paddr)
+        }
+
+        fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
+            <HostHal as AxMmHal>::virt_to_phys(vaddr)
+        }
+
+        fn vmid_bits() -> usize {
+            16
+        }
+    }
+
+    impl PagingHandler for VmidHal {
+        fn alloc_frame() -> Option<HostPhysAddr> {
+            <Self as AxMmHal>::alloc_frame()
+        }
+
+        fn dealloc_frame(paddr: HostPhysAddr) {
+            <Self as AxMmHal>::dealloc_frame(paddr)
+        }
+
+        fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+            <Self as AxMmHal>::phys_to_virt(paddr)
+        }
+    }
+
+    #[test]
+    fn vmid_after_address_space_without_hal() {
+        // The width probed without the HAL is 0 on x86_64 and RISC-V, which
+        // must not disable the VMIDs reported by the HAL.
+        let _untagged = AddrSpace::<VmidHal>::new_empty(gpa(0), 0x10_0000).unwrap();
+        let aspace = AddrSpace::<VmidHal>::new_with_hal(gpa(0), 0x10_0000).unwrap();
+        assert!(aspace.vmid().is_some());
+    }
+
+    #[test]
+    fn access_overflowing_range() {
+        let mut aspace = new_addr_space();
+        let end = gpa(usize::MAX - 3);
+        assert!(!aspace.contains_range(end, 8));
+        let mut buf = [0; 8];
+        assert_eq!(aspace.read_bytes(end, &mut buf), Err(AxError::BadAddress));
+        assert_eq!(aspace.write_bytes(end, &buf), Err(AxError::BadAddress));
+        assert_eq!(
+            aspace.map_alloc(gpa(usize::MAX - 0xfff), 0x2000, rw(), true),
+            Err(AxError::InvalidInput)
+        );
+        assert_eq!(
+            AddrSpace::<HostHal>::new_with_hal(end, 8).err(),
+            Some(AxError::InvalidInput)
+        );
     }
 }
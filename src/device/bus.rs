@@ -0,0 +1,226 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use axerrno::{AxResult, ax_err};
+
+use super::{AccessWidth, DeviceAddr, DeviceAddrRange, DeviceOps};
+
+/// A bus dispatching device accesses by address, for MMIO, port I/O or
+/// system register accesses.
+///
+/// Devices are indexed by the lowest address of their ranges, so that the
+/// device of an address is found in `O(log n)`. The ranges of the devices
+/// must not overlap, non-contiguous ranges are considered to span from their
+/// lowest to their highest address.
+pub struct DeviceBus<R: DeviceAddrRange> {
+    devices: BTreeMap<R::Addr, BusEntry<R>>,
+}
+
+/// A device on a bus, with the range it was inserted at.
+struct BusEntry<R: DeviceAddrRange> {
+    range: R,
+    device: Arc<dyn DeviceOps<R>>,
+}
+
+impl<R: DeviceAddrRange> DeviceBus<R> {
+    /// Creates a new empty bus.
+    pub const fn new() -> Self {
+        Self {
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of devices on the bus.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Returns whether there is no device on the bus.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Adds a device to the bus, at its address range.
+    ///
+    /// Returns [`AxError::AlreadyExists`](axerrno::AxError::AlreadyExists) if
+    /// the range overlaps the one of another device on the bus.
+    pub fn insert(&mut self, device: Arc<dyn DeviceOps<R>>) -> AxResult {
+        let range = device.address_range();
+        let start = range.start();
+        let Some(last) = range.last() else {
+            return ax_err!(InvalidInput, "empty device address range");
+        };
+        // The ranges on the bus are disjoint, so only the one starting last
+        // before the end of the new range may overlap it.
+        if let Some((_, prev)) = self.devices.range(..=last).next_back()
+            && prev
+                .range
+                .last()
+                .is_some_and(|prev_last| prev_last >= start)
+        {
+            return ax_err!(AlreadyExists, "device address range overlaps");
+        }
+        self.devices.insert(start, BusEntry { range, device });
+        Ok(())
+    }
+
+    /// Removes the device whose range starts at `start` from the bus, and
+    /// returns it.
+    pub fn remove(&mut self, start: R::Addr) -> Option<Arc<dyn DeviceOps<R>>> {
+        self.devices.remove(&start).map(|entry| entry.device)
+    }
+
+    /// Returns the device at `addr` and the offset of `addr` in its range.
+    pub fn find(&self, addr: R::Addr) -> Option<(&Arc<dyn DeviceOps<R>>, usize)> {
+        let (start, entry) = self.devices.range(..=addr).next_back()?;
+        entry
+            .range
+            .contains(addr)
+            .then(|| (&entry.device, addr.offset_from(*start)))
+    }
+
+    /// Returns an iterator over the devices on the bus, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn DeviceOps<R>>> {
+        self.devices.values().map(|entry| &entry.device)
+    }
+
+    /// Dispatches a read of `width` at `addr` to the device at the address.
+    pub fn handle_read(&self, addr: R::Addr, width: AccessWidth) -> AxResult<usize> {
+        match self.find(addr) {
+            Some((device, _)) => device.read(addr, width),
+            None => ax_err!(NotFound, "no device at the address"),
+        }
+    }
+
+    /// Dispatches a write of `value` of `width` at `addr` to the device at the
+    /// address.
+    pub fn handle_write(&self, addr: R::Addr, width: AccessWidth, value: usize) -> AxResult {
+        match self.find(addr) {
+            Some((device, _)) => device.write(addr, width, value),
+            None => ax_err!(NotFound, "no device at the address"),
+        }
+    }
+}
+
+impl<R: DeviceAddrRange> Default for DeviceBus<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::device::{Port, PortRange};
+    use crate::{GuestPhysAddr, GuestPhysAddrRange};
+
+    /// A device whose reads return its tag, and which records the last value
+    /// written.
+    struct TestDevice<R> {
+        range: R,
+        tag: usize,
+        written: AtomicUsize,
+    }
+
+    impl<R: DeviceAddrRange + Copy + Send + Sync> DeviceOps<R> for TestDevice<R> {
+        fn address_range(&self) -> R {
+            self.range
+        }
+
+        fn read(&self, _addr: R::Addr, _width: AccessWidth) -> AxResult<usize> {
+            Ok(self.tag)
+        }
+
+        fn write(&self, _addr: R::Addr, _width: AccessWidth, value: usize) -> AxResult {
+            self.written.store(value, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn device<R>(range: R, tag: usize) -> Arc<TestDevice<R>> {
+        Arc::new(TestDevice {
+            range,
+            tag,
+            written: AtomicUsize::new(0),
+        })
+    }
+
+    fn ports(start: u16, end: u16) -> PortRange {
+        PortRange::new(Port(start), Port(end))
+    }
+
+    #[test]
+    fn insert_overlapping_and_adjacent_ranges() {
+        let mut bus = DeviceBus::new();
+        bus.insert(device(ports(0x3f8, 0x3ff), 1)).unwrap();
+        // Adjacent ranges on both sides.
+        bus.insert(device(ports(0x3f0, 0x3f7), 2)).unwrap();
+        bus.insert(device(ports(0x400, 0x400), 3)).unwrap();
+        assert_eq!(bus.len(), 3);
+
+        for (start, end) in [
+            (0x3ff, 0x3ff),
+            (0x3e0, 0x3f0),
+            (0x3f9, 0x3fa),
+            (0x300, 0x500),
+        ] {
+            let res = bus.insert(device(ports(start, end), 0));
+            assert_eq!(res, Err(AxError::AlreadyExists));
+        }
+        // Empty ranges.
+        let res = bus.insert(device(ports(0x500, 0x4ff), 0));
+        assert_eq!(res, Err(AxError::InvalidInput));
+        assert_eq!(bus.len(), 3);
+
+        let removed = bus.remove(Port(0x3f8)).unwrap();
+        assert_eq!(removed.address_range(), ports(0x3f8, 0x3ff));
+        assert!(bus.remove(Port(0x3f8)).is_none());
+        bus.insert(device(ports(0x3f9, 0x3fa), 4)).unwrap();
+        let starts: alloc::vec::Vec<_> = bus.iter().map(|dev| dev.address_range().start).collect();
+        assert_eq!(starts, [Port(0x3f0), Port(0x3f9), Port(0x400)]);
+    }
+
+    #[test]
+    fn empty_range_at_zero() {
+        let mut bus = DeviceBus::new();
+        let range = GuestPhysAddrRange::from_start_size(GuestPhysAddr::from_usize(0), 0);
+        assert_eq!(range.last(), None);
+        assert_eq!(bus.insert(device(range, 0)), Err(AxError::InvalidInput));
+        assert!(bus.is_empty());
+    }
+
+    #[test]
+    fn dispatch_to_the_device() {
+        let mut bus = DeviceBus::new();
+        let gpa = GuestPhysAddr::from_usize;
+        let uart = device(GuestPhysAddrRange::from_start_size(gpa(0x1000), 0x100), 1);
+        let rtc = device(GuestPhysAddrRange::from_start_size(gpa(0x1100), 0x10), 2);
+        bus.insert(uart.clone()).unwrap();
+        bus.insert(rtc.clone()).unwrap();
+
+        let (found, offset) = bus.find(gpa(0x10ff)).unwrap();
+        assert_eq!((found.address_range(), offset), (uart.range, 0xff));
+        assert_eq!(bus.find(gpa(0x1104)).unwrap().1, 4);
+        assert!(bus.find(gpa(0xfff)).is_none());
+        assert!(bus.find(gpa(0x1110)).is_none());
+
+        assert_eq!(bus.handle_read(gpa(0x1000), AccessWidth::Dword), Ok(1));
+        assert_eq!(bus.handle_read(gpa(0x110f), AccessWidth::Byte), Ok(2));
+        bus.handle_write(gpa(0x1108), AccessWidth::Word, 0x55)
+            .unwrap();
+        assert_eq!(rtc.written.load(Ordering::Relaxed), 0x55);
+        assert_eq!(uart.written.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            bus.handle_read(gpa(0x2000), AccessWidth::Byte),
+            Err(AxError::NotFound)
+        );
+        assert_eq!(
+            bus.handle_write(gpa(0x1110), AccessWidth::Byte, 0),
+            Err(AxError::NotFound)
+        );
+    }
+}
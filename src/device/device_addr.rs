@@ -7,18 +7,39 @@ use crate::GuestPhysAddr;
 use super::{Port, SysRegAddr};
 
 /// An address-like type that can be used to access devices.
-pub trait DeviceAddr: Copy + Eq + Ord + core::fmt::Debug {}
+///
+/// [`DeviceAddr::offset_from`] is required since 0.2.0, to find the offset of
+/// an access in a [`DeviceBus`](super::DeviceBus).
+pub trait DeviceAddr: Copy + Eq + Ord + core::fmt::Debug {
+    /// Returns the offset of the address from `base`, which is not greater
+    /// than the address.
+    fn offset_from(self, base: Self) -> usize;
+}
 
 /// A range of device addresses. It may be contiguous or not.
+///
+/// [`DeviceAddrRange::start`] and [`DeviceAddrRange::last`] are required since
+/// 0.2.0, to order the ranges in a [`DeviceBus`](super::DeviceBus).
 pub trait DeviceAddrRange {
     /// The address type of the range.
     type Addr: DeviceAddr;
 
     /// Returns whether the address range contains the given address.
     fn contains(&self, addr: Self::Addr) -> bool;
+
+    /// Returns the lowest address of the range.
+    fn start(&self) -> Self::Addr;
+
+    /// Returns the highest address of the range, which is inclusive, or
+    /// `None` if the range is empty.
+    fn last(&self) -> Option<Self::Addr>;
 }
 
-impl DeviceAddr for GuestPhysAddr {}
+impl DeviceAddr for GuestPhysAddr {
+    fn offset_from(self, base: Self) -> usize {
+        self - base
+    }
+}
 
 impl DeviceAddrRange for AddrRange<GuestPhysAddr> {
     type Addr = GuestPhysAddr;
@@ -26,9 +47,21 @@ impl DeviceAddrRange for AddrRange<GuestPhysAddr> {
     fn contains(&self, addr: Self::Addr) -> bool {
         Self::contains(*self, addr)
     }
+
+    fn start(&self) -> Self::Addr {
+        self.start
+    }
+
+    fn last(&self) -> Option<Self::Addr> {
+        (!self.is_empty()).then(|| self.end - 1)
+    }
 }
 
-impl DeviceAddr for SysRegAddr {}
+impl DeviceAddr for SysRegAddr {
+    fn offset_from(self, base: Self) -> usize {
+        self.0 - base.0
+    }
+}
 
 /// A inclusive range of system register addresses.
 ///
@@ -54,6 +87,14 @@ impl DeviceAddrRange for SysRegAddrRange {
     fn contains(&self, addr: Self::Addr) -> bool {
         addr.0 >= self.start.0 && addr.0 <= self.end.0
     }
+
+    fn start(&self) -> Self::Addr {
+        self.start
+    }
+
+    fn last(&self) -> Option<Self::Addr> {
+        (self.start.0 <= self.end.0).then_some(self.end)
+    }
 }
 
 impl LowerHex for SysRegAddrRange {
@@ -62,7 +103,11 @@ impl LowerHex for SysRegAddrRange {
     }
 }
 
-impl DeviceAddr for Port {}
+impl DeviceAddr for Port {
+    fn offset_from(self, base: Self) -> usize {
+        (self.0 - base.0) as usize
+    }
+}
 
 /// A inclusive range of port numbers.
 ///
@@ -88,6 +133,14 @@ impl DeviceAddrRange for PortRange {
     fn contains(&self, addr: Self::Addr) -> bool {
         addr.0 >= self.start.0 && addr.0 <= self.end.0
     }
+
+    fn start(&self) -> Self::Addr {
+        self.start
+    }
+
+    fn last(&self) -> Option<Self::Addr> {
+        (self.start.0 <= self.end.0).then_some(self.end)
+    }
 }
 
 impl LowerHex for PortRange {
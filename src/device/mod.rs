@@ -2,10 +2,27 @@
 
 use core::fmt::{Debug, LowerHex, UpperHex};
 
+use axerrno::AxResult;
+
+mod bus;
 mod device_addr;
 
+pub use bus::DeviceBus;
 pub use device_addr::*;
 
+/// Operations of an emulated device, accessed through the addresses in the
+/// range `R`.
+pub trait DeviceOps<R: DeviceAddrRange>: Send + Sync {
+    /// Returns the address range of the device.
+    fn address_range(&self) -> R;
+
+    /// Handles a read of `width` at `addr`, returning the value read.
+    fn read(&self, addr: R::Addr, width: AccessWidth) -> AxResult<usize>;
+
+    /// Handles a write of `value` of `width` at `addr`.
+    fn write(&self, addr: R::Addr, width: AccessWidth, value: usize) -> AxResult;
+}
+
 /// The width of an access.
 ///
 /// Note that the term "word" here refers to 16-bit data, as in the x86 architecture.
@@ -0,0 +1,380 @@
+//! Decoding of the hardware state of nested page faults.
+
+use bit_field::BitField;
+use page_table_entry::MappingFlags;
+
+use crate::device::AccessWidth;
+use crate::{GuestPhysAddr, GuestVirtAddr, NestedPageFaultInfo};
+
+/// The register transferring the data of a faulting access, used to emulate
+/// the access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultAccessReg {
+    /// The index of the general-purpose register, the destination of loads or
+    /// the source of stores.
+    pub reg: usize,
+    /// Whether a load sign-extends the data into the register.
+    pub sign_extend: bool,
+    /// Whether the register is accessed as 64 bits, rather than 32 bits.
+    pub reg_64bit: bool,
+}
+
+impl NestedPageFaultInfo {
+    /// Creates the information of a fault on an access of `access_flags` at
+    /// `fault_guest_paddr`, without any further detail.
+    pub const fn new(access_flags: MappingFlags, fault_guest_paddr: GuestPhysAddr) -> Self {
+        Self {
+            access_flags,
+            fault_guest_paddr,
+            fault_guest_vaddr: None,
+            access_width: None,
+            access_reg: None,
+            stage1_walk: false,
+        }
+    }
+
+    /// Decodes an x86 EPT violation from its exit qualification, the
+    /// guest-physical address and the guest linear address fields of the VMCS.
+    /// (SDM Vol. 3C, Section 28.3.3.2)
+    ///
+    /// The width and the register of the access are not reported by EPT
+    /// violations, they must be decoded from the instruction.
+    pub fn from_ept_violation(
+        exit_qualification: u64,
+        guest_paddr: GuestPhysAddr,
+        guest_linear_addr: GuestVirtAddr,
+    ) -> Self {
+        let mut access_flags = MappingFlags::empty();
+        if exit_qualification.get_bit(0) {
+            access_flags |= MappingFlags::READ;
+        }
+        if exit_qualification.get_bit(1) {
+            access_flags |= MappingFlags::WRITE;
+        }
+        if exit_qualification.get_bit(2) {
+            access_flags |= MappingFlags::EXECUTE;
+        }
+        // Bit 8 is only defined if the guest linear address is valid, and is
+        // clear for accesses to the guest paging structures.
+        let linear_valid = exit_qualification.get_bit(7);
+        Self {
+            access_flags,
+            fault_guest_paddr: guest_paddr,
+            fault_guest_vaddr: linear_valid.then_some(guest_linear_addr),
+            access_width: None,
+            access_reg: None,
+            stage1_walk: linear_valid && !exit_qualification.get_bit(8),
+        }
+    }
+
+    /// Decodes an AArch64 stage-2 data or instruction abort taken to EL2 from
+    /// its ESR_EL2, HPFAR_EL2 and FAR_EL2 values.
+    ///
+    /// Returns `None` if the exception is not an abort from a lower exception
+    /// level, the aborts taken from EL2 itself are not stage-2 faults.
+    pub fn from_aarch64_abort(esr: u64, hpfar: u64, far: u64) -> Option<Self> {
+        const EC_IABT_LOWER: u64 = 0x20;
+        const EC_DABT_LOWER: u64 = 0x24;
+
+        let iss = esr.get_bits(0..25);
+        let data = match esr.get_bits(26..32) {
+            EC_DABT_LOWER => true,
+            EC_IABT_LOWER => false,
+            _ => return None,
+        };
+        let access_flags = if !data {
+            MappingFlags::EXECUTE
+        } else if iss.get_bit(6) && !iss.get_bit(8) {
+            // WnR, which is also set for cache maintenance operations (CM).
+            MappingFlags::WRITE
+        } else {
+            MappingFlags::READ
+        };
+
+        // HPFAR_EL2.FIPA holds bits [51:12] of the faulting IPA, and FAR_EL2
+        // the offset in the page.
+        let ipa = (hpfar.get_bits(4..44) << 12) | far.get_bits(0..12);
+        // The instruction syndrome is only valid for data aborts with ISV.
+        let isv = data && iss.get_bit(24);
+        let access_width = isv.then(|| match iss.get_bits(22..24) {
+            0 => AccessWidth::Byte,
+            1 => AccessWidth::Word,
+            2 => AccessWidth::Dword,
+            _ => AccessWidth::Qword,
+        });
+        let access_reg = isv.then(|| FaultAccessReg {
+            reg: iss.get_bits(16..21) as usize,
+            sign_extend: iss.get_bit(21),
+            reg_64bit: iss.get_bit(15),
+        });
+        Some(Self {
+            access_flags,
+            fault_guest_paddr: GuestPhysAddr::from_usize(ipa as usize),
+            // FnV, FAR_EL2 is not valid.
+            fault_guest_vaddr: (!iss.get_bit(10)).then(|| GuestVirtAddr::from_usize(far as usize)),
+            access_width,
+            access_reg,
+            stage1_walk: iss.get_bit(7),
+        })
+    }
+
+    /// Decodes a RISC-V guest-page fault taken to HS-mode from its `scause`,
+    /// `stval`, `htval`, `htinst` and `hstatus` values.
+    ///
+    /// The width and the register of the access are decoded from `htinst` if
+    /// it holds a transformed load or store instruction. Returns `None` if the
+    /// exception is not a guest-page fault.
+    pub fn from_riscv_guest_page_fault(
+        scause: usize,
+        stval: usize,
+        htval: usize,
+        htinst: usize,
+        hstatus: usize,
+    ) -> Option<Self> {
+        const INSTRUCTION_GUEST_PAGE_FAULT: usize = 20;
+        const LOAD_GUEST_PAGE_FAULT: usize = 21;
+        const STORE_GUEST_PAGE_FAULT: usize = 23;
+        const HSTATUS_GVA: usize = 1 << 6;
+        // Pseudoinstructions reported for the implicit accesses of VS-stage
+        // address translation.
+        const HTINST_VS_WALK: [usize; 4] = [0x2000, 0x2020, 0x3000, 0x3020];
+        const OPCODE_LOAD: usize = 0b000_0011;
+        const OPCODE_STORE: usize = 0b010_0011;
+
+        let access_flags = match scause {
+            INSTRUCTION_GUEST_PAGE_FAULT => MappingFlags::EXECUTE,
+            LOAD_GUEST_PAGE_FAULT => MappingFlags::READ,
+            STORE_GUEST_PAGE_FAULT => MappingFlags::WRITE,
+            _ => return None,
+        };
+        // `htval` holds the guest physical address shifted right by 2 bits,
+        // the low bits are the ones of the guest virtual address.
+        let gpa = (htval << 2) | (stval & 0b11);
+
+        let stage1_walk = HTINST_VS_WALK.contains(&htinst);
+        // Transformed instructions have bit 0 set, and bit 1 cleared for
+        // compressed ones.
+        let transformed = !stage1_walk && htinst.get_bit(0);
+        let (access_width, access_reg) = match htinst.get_bits(0..7) | 0b10 {
+            OPCODE_LOAD | OPCODE_STORE if transformed => {
+                let funct3 = htinst.get_bits(12..15);
+                let width = match funct3 & 0b11 {
+                    0 => AccessWidth::Byte,
+                    1 => AccessWidth::Word,
+                    2 => AccessWidth::Dword,
+                    _ => AccessWidth::Qword,
+                };
+                let is_load = htinst.get_bits(0..7) | 0b10 == OPCODE_LOAD;
+                let reg = if is_load {
+                    htinst.get_bits(7..12)
+                } else {
+                    htinst.get_bits(20..25)
+                };
+                let reg = FaultAccessReg {
+                    reg,
+                    // LB, LH and LW sign-extend, their unsigned variants
+                    // have bit 2 of funct3 set.
+                    sign_extend: is_load && funct3 & 0b100 == 0,
+                    reg_64bit: true,
+                };
+                (Some(width), Some(reg))
+            }
+            _ => (None, None),
+        };
+
+        Some(Self {
+            access_flags,
+            fault_guest_paddr: GuestPhysAddr::from_usize(gpa),
+            fault_guest_vaddr: (hstatus & HSTATUS_GVA != 0)
+                .then(|| GuestVirtAddr::from_usize(stval)),
+            access_width,
+            access_reg,
+            stage1_walk,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R: MappingFlags = MappingFlags::READ;
+    const W: MappingFlags = MappingFlags::WRITE;
+    const X: MappingFlags = MappingFlags::EXECUTE;
+
+    const HSTATUS_GVA: usize = 1 << 6;
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from_usize(addr)
+    }
+
+    fn gva(addr: usize) -> GuestVirtAddr {
+        GuestVirtAddr::from_usize(addr)
+    }
+
+    #[test]
+    fn ept_violation() {
+        let info = NestedPageFaultInfo::from_ept_violation(
+            0b11 | (1 << 7) | (1 << 8),
+            gpa(0x1234),
+            gva(0x5678),
+        );
+        assert_eq!(info.access_flags, R | W);
+        assert_eq!(info.fault_guest_paddr, gpa(0x1234));
+        assert_eq!(info.fault_guest_vaddr, Some(gva(0x5678)));
+        assert!(!info.stage1_walk);
+        assert_eq!((info.access_width, info.access_reg), (None, None));
+
+        // An access to the guest paging structures.
+        let info =
+            NestedPageFaultInfo::from_ept_violation(0b1 | (1 << 7), gpa(0x1000), gva(0x5678));
+        assert!(info.stage1_walk);
+        // Bit 8 is undefined without a valid linear address.
+        let info =
+            NestedPageFaultInfo::from_ept_violation(0b100 | (1 << 8), gpa(0x1000), gva(0x5678));
+        assert_eq!(info.access_flags, X);
+        assert_eq!(info.fault_guest_vaddr, None);
+        assert!(!info.stage1_walk);
+    }
+
+    /// Builds an ESR_EL2 value of the exception class `ec` and the syndrome
+    /// `iss`.
+    fn esr(ec: u64, iss: u64) -> u64 {
+        (ec << 26) | (1 << 25) | iss
+    }
+
+    #[test]
+    fn aarch64_data_abort() {
+        // A 32-bit sign-extending store of W5, with ISV and WnR.
+        let iss = (1 << 24) | (2 << 22) | (1 << 21) | (5 << 16) | (1 << 6);
+        let info = NestedPageFaultInfo::from_aarch64_abort(
+            esr(0x24, iss),
+            0x8_1230,
+            0xffff_0000_0000_0abc,
+        )
+        .unwrap();
+        assert_eq!(info.access_flags, W);
+        assert_eq!(info.fault_guest_paddr, gpa(0x812_3abc));
+        assert_eq!(info.fault_guest_vaddr, Some(gva(0xffff_0000_0000_0abc)));
+        assert_eq!(info.access_width, Some(AccessWidth::Dword));
+        assert_eq!(
+            info.access_reg,
+            Some(FaultAccessReg {
+                reg: 5,
+                sign_extend: true,
+                reg_64bit: false,
+            })
+        );
+        assert!(!info.stage1_walk);
+
+        // A 64-bit load of X30.
+        let iss = (1 << 24) | (3 << 22) | (30 << 16) | (1 << 15);
+        let info = NestedPageFaultInfo::from_aarch64_abort(esr(0x24, iss), 0, 0).unwrap();
+        assert_eq!(info.access_flags, R);
+        assert_eq!(info.access_width, Some(AccessWidth::Qword));
+        assert_eq!(info.access_reg.unwrap().reg, 30);
+        assert!(info.access_reg.unwrap().reg_64bit);
+
+        // Cache maintenance operations report WnR, a stage-1 walk and a
+        // FAR_EL2 that is not valid, without ISV.
+        let iss = (1 << 10) | (1 << 8) | (1 << 7) | (1 << 6);
+        let info = NestedPageFaultInfo::from_aarch64_abort(esr(0x24, iss), 0, 0).unwrap();
+        assert_eq!(info.access_flags, R);
+        assert_eq!(info.fault_guest_vaddr, None);
+        assert!(info.stage1_walk);
+        assert_eq!((info.access_width, info.access_reg), (None, None));
+    }
+
+    #[test]
+    fn aarch64_instruction_abort() {
+        // Bit 24 is reserved in the syndrome of instruction aborts.
+        let info = NestedPageFaultInfo::from_aarch64_abort(esr(0x20, 1 << 24), 0x10, 0x4).unwrap();
+        assert_eq!(info.access_flags, X);
+        assert_eq!(info.fault_guest_paddr, gpa(0x1004));
+        assert_eq!(info.fault_guest_vaddr, Some(gva(0x4)));
+        assert_eq!((info.access_width, info.access_reg), (None, None));
+        let info = NestedPageFaultInfo::from_aarch64_abort(esr(0x20, 1 << 10), 0x10, 0x4).unwrap();
+        assert_eq!(info.fault_guest_vaddr, None);
+
+        // Aborts taken from EL2 and other exceptions.
+        for ec in [0x21, 0x25, 0x16, 0x00] {
+            assert!(NestedPageFaultInfo::from_aarch64_abort(esr(ec, 0), 0x10, 0).is_none());
+        }
+    }
+
+    #[test]
+    fn riscv_guest_page_fault() {
+        // A transformed `lw x5`, with the guest virtual address.
+        let htinst = (2 << 12) | (5 << 7) | 0b000_0011;
+        let info = NestedPageFaultInfo::from_riscv_guest_page_fault(
+            21,
+            0x4006,
+            0x2001,
+            htinst,
+            HSTATUS_GVA,
+        )
+        .unwrap();
+        assert_eq!(info.access_flags, R);
+        assert_eq!(info.fault_guest_paddr, gpa(0x8006));
+        assert_eq!(info.fault_guest_vaddr, Some(gva(0x4006)));
+        assert_eq!(info.access_width, Some(AccessWidth::Dword));
+        assert_eq!(
+            info.access_reg,
+            Some(FaultAccessReg {
+                reg: 5,
+                sign_extend: true,
+                reg_64bit: true,
+            })
+        );
+
+        // A transformed compressed `c.lbu`, whose bit 1 is cleared.
+        let htinst = (4 << 12) | (9 << 7) | 0b000_0001;
+        let info =
+            NestedPageFaultInfo::from_riscv_guest_page_fault(21, 0, 0x400, htinst, 0).unwrap();
+        assert_eq!(info.fault_guest_vaddr, None);
+        assert_eq!(info.access_width, Some(AccessWidth::Byte));
+        let reg = info.access_reg.unwrap();
+        assert_eq!((reg.reg, reg.sign_extend), (9, false));
+
+        // A transformed `sd x7`.
+        let htinst = (7 << 20) | (3 << 12) | 0b010_0011;
+        let info =
+            NestedPageFaultInfo::from_riscv_guest_page_fault(23, 0, 0x400, htinst, 0).unwrap();
+        assert_eq!(info.access_flags, W);
+        assert_eq!(info.access_width, Some(AccessWidth::Qword));
+        let reg = info.access_reg.unwrap();
+        assert_eq!((reg.reg, reg.sign_extend), (7, false));
+    }
+
+    #[test]
+    fn riscv_implicit_and_untransformed_accesses() {
+        // The implicit read of a VS-stage walk.
+        let info = NestedPageFaultInfo::from_riscv_guest_page_fault(
+            21,
+            0x1000,
+            0x400,
+            0x3000,
+            HSTATUS_GVA,
+        )
+        .unwrap();
+        assert!(info.stage1_walk);
+        assert_eq!((info.access_width, info.access_reg), (None, None));
+
+        // No transformed instruction, or a standard one with bit 0 clear.
+        for htinst in [0, (2 << 12) | (5 << 7) | 0b000_0010] {
+            let info =
+                NestedPageFaultInfo::from_riscv_guest_page_fault(21, 0, 0x400, htinst, 0).unwrap();
+            assert!(!info.stage1_walk);
+            assert_eq!((info.access_width, info.access_reg), (None, None));
+        }
+
+        let info =
+            NestedPageFaultInfo::from_riscv_guest_page_fault(20, 0x1002, 0x400, 0, 0).unwrap();
+        assert_eq!(info.access_flags, X);
+        assert_eq!(info.fault_guest_paddr, gpa(0x1002));
+        // Other exceptions, such as VS-stage page faults.
+        for scause in [12, 13, 15, 22] {
+            assert!(NestedPageFaultInfo::from_riscv_guest_page_fault(scause, 0, 0, 0, 0).is_none());
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use core::mem::{MaybeUninit, size_of};
+
+use axerrno::AxResult;
+
+use crate::GuestPhysAddr;
+
+/// Types that can be safely copied from and to guest memory as raw bytes.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value of the type, and the type must not
+/// contain padding bytes.
+pub unsafe trait ByteValued: Copy {}
+
+macro_rules! impl_byte_valued {
+    ($($t:ty),*) => {
+        $(unsafe impl ByteValued for $t {})*
+    };
+}
+
+impl_byte_valued!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+unsafe impl<T: ByteValued, const N: usize> ByteValued for [T; N] {}
+
+/// Integers that can be accessed in guest memory with an explicit byte order.
+///
+/// Converting to a byte order is its own inverse, so the same conversions are
+/// used for reads and writes.
+pub trait GuestInt: ByteValued {
+    /// Converts between the host byte order and little endian.
+    fn to_le(self) -> Self;
+    /// Converts between the host byte order and big endian.
+    fn to_be(self) -> Self;
+}
+
+macro_rules! impl_guest_int {
+    ($($t:ty),*) => {
+        $(impl GuestInt for $t {
+            fn to_le(self) -> Self {
+                <$t>::to_le(self)
+            }
+            fn to_be(self) -> Self {
+                <$t>::to_be(self)
+            }
+        })*
+    };
+}
+
+impl_guest_int!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+/// Typed accesses to guest memory keyed by guest physical addresses.
+///
+/// Objects may straddle the boundaries of host pages, which are not
+/// necessarily contiguous in host memory, so they are copied chunk by chunk
+/// instead of being accessed through a pointer into guest memory.
+pub trait GuestMemory {
+    /// Reads guest memory starting at `gpa` into `buf`.
+    fn read_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult;
+
+    /// Writes `buf` into guest memory starting at `gpa`.
+    fn write_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult;
+
+    /// Reads guest memory starting at `gpa` into `buf` with volatile accesses.
+    ///
+    /// A naturally aligned access of 1, 2, 4 or 8 bytes is done with a single
+    /// volatile access, other accesses are done byte by byte.
+    fn read_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> AxResult;
+
+    /// Writes `buf` into guest memory starting at `gpa` with volatile accesses.
+    ///
+    /// A naturally aligned access of 1, 2, 4 or 8 bytes is done with a single
+    /// volatile access, other accesses are done byte by byte.
+    fn write_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> AxResult;
+
+    /// Reads an object of type `T` at `gpa`.
+    fn read_obj<T: ByteValued>(&mut self, gpa: GuestPhysAddr) -> AxResult<T> {
+        let mut val = zeroed::<T>();
+        self.read_bytes(gpa, obj_bytes_mut(&mut val))?;
+        Ok(val)
+    }
+
+    /// Writes an object of type `T` at `gpa`.
+    fn write_obj<T: ByteValued>(&mut self, gpa: GuestPhysAddr, val: T) -> AxResult {
+        self.write_bytes(gpa, obj_bytes(&val))
+    }
+
+    /// Reads an object of type `T` at `gpa` with volatile accesses.
+    fn read_volatile_obj<T: ByteValued>(&mut self, gpa: GuestPhysAddr) -> AxResult<T> {
+        let mut val = zeroed::<T>();
+        self.read_volatile_bytes(gpa, obj_bytes_mut(&mut val))?;
+        Ok(val)
+    }
+
+    /// Writes an object of type `T` at `gpa` with volatile accesses.
+    fn write_volatile_obj<T: ByteValued>(&mut self, gpa: GuestPhysAddr, val: T) -> AxResult {
+        self.write_volatile_bytes(gpa, obj_bytes(&val))
+    }
+
+    /// Reads a little-endian integer at `gpa`.
+    fn read_le<T: GuestInt>(&mut self, gpa: GuestPhysAddr) -> AxResult<T> {
+        self.read_obj::<T>(gpa).map(T::to_le)
+    }
+
+    /// Reads a big-endian integer at `gpa`.
+    fn read_be<T: GuestInt>(&mut self, gpa: GuestPhysAddr) -> AxResult<T> {
+        self.read_obj::<T>(gpa).map(T::to_be)
+    }
+
+    /// Writes a little-endian integer at `gpa`.
+    fn write_le<T: GuestInt>(&mut self, gpa: GuestPhysAddr, val: T) -> AxResult {
+        self.write_obj(gpa, val.to_le())
+    }
+
+    /// Writes a big-endian integer at `gpa`.
+    fn write_be<T: GuestInt>(&mut self, gpa: GuestPhysAddr, val: T) -> AxResult {
+        self.write_obj(gpa, val.to_be())
+    }
+}
+
+fn obj_bytes<T: ByteValued>(val: &T) -> &[u8] {
+    // SAFETY: `T` has no padding bytes.
+    unsafe { core::slice::from_raw_parts((val as *const T).cast(), size_of::<T>()) }
+}
+
+/// Returns the bytes of `val`, which can be overwritten with any value.
+///
+/// The buffers are initialized, so that an implementation of
+/// [`GuestMemory::read_bytes`] which does not fill them cannot expose
+/// uninitialized memory.
+fn obj_bytes_mut<T: ByteValued>(val: &mut T) -> &mut [u8] {
+    // SAFETY: `T` has no padding bytes, and every bit pattern is valid.
+    unsafe { core::slice::from_raw_parts_mut((val as *mut T).cast(), size_of::<T>()) }
+}
+
+/// Returns the value of `T` whose bytes are all zero.
+fn zeroed<T: ByteValued>() -> T {
+    // SAFETY: every bit pattern is a valid value of `T`.
+    unsafe { MaybeUninit::zeroed().assume_init() }
+}
+
+/// Copies `dst.len()` bytes from `src` with volatile accesses.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `dst.len()` bytes.
+pub(crate) unsafe fn copy_from_volatile(src: *const u8, dst: &mut [u8]) {
+    unsafe {
+        match dst.len() {
+            2 if src.cast::<u16>().is_aligned() => {
+                dst.copy_from_slice(&src.cast::<u16>().read_volatile().to_ne_bytes())
+            }
+            4 if src.cast::<u32>().is_aligned() => {
+                dst.copy_from_slice(&src.cast::<u32>().read_volatile().to_ne_bytes())
+            }
+            8 if src.cast::<u64>().is_aligned() => {
+                dst.copy_from_slice(&src.cast::<u64>().read_volatile().to_ne_bytes())
+            }
+            _ => {
+                for (i, byte) in dst.iter_mut().enumerate() {
+                    *byte = src.add(i).read_volatile();
+                }
+            }
+        }
+    }
+}
+
+/// Copies `src` to `dst` with volatile accesses.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `src.len()` bytes.
+pub(crate) unsafe fn copy_to_volatile(src: &[u8], dst: *mut u8) {
+    unsafe {
+        match src.len() {
+            2 if dst.cast::<u16>().is_aligned() => dst
+                .cast::<u16>()
+                .write_volatile(u16::from_ne_bytes(src.try_into().unwrap())),
+            4 if dst.cast::<u32>().is_aligned() => dst
+                .cast::<u32>()
+                .write_volatile(u32::from_ne_bytes(src.try_into().unwrap())),
+            8 if dst.cast::<u64>().is_aligned() => dst
+                .cast::<u64>()
+                .write_volatile(u64::from_ne_bytes(src.try_into().unwrap())),
+            _ => {
+                for (i, byte) in src.iter().enumerate() {
+                    dst.add(i).write_volatile(*byte);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+    use page_table_multiarch::MappingFlags;
+
+    use super::*;
+    use crate::AddrSpace;
+    use crate::host::HostHal;
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from(addr)
+    }
+
+    /// An address space with two populated 4K pages at 0x1000.
+    fn new_addr_space() -> AddrSpace<HostHal> {
+        let mut aspace = AddrSpace::new_with_hal(gpa(0), 0x10_0000).unwrap();
+        aspace
+            .map_alloc(
+                gpa(0x1000),
+                0x2000,
+                MappingFlags::READ | MappingFlags::WRITE,
+                true,
+            )
+            .unwrap();
+        aspace
+    }
+
+    #[test]
+    fn obj_round_trips() {
+        let mut aspace = new_addr_space();
+        aspace.write_obj(gpa(0x1010), [1u16, 2, 3]).unwrap();
+        assert_eq!(aspace.read_obj::<[u16; 3]>(gpa(0x1010)), Ok([1, 2, 3]));
+        aspace
+            .write_obj(gpa(0x1ffc), 0x0102_0304_0506_0708u64)
+            .unwrap();
+        assert_eq!(
+            aspace.read_obj::<u64>(gpa(0x1ffc)),
+            Ok(0x0102_0304_0506_0708)
+        );
+        assert_eq!(
+            aspace.read_obj::<u32>(gpa(0x2ffe)),
+            Err(AxError::BadAddress)
+        );
+    }
+
+    #[test]
+    fn byte_order() {
+        let mut aspace = new_addr_space();
+        aspace.write_le(gpa(0x1000), 0x1122_3344u32).unwrap();
+        assert_eq!(
+            aspace.read_obj::<[u8; 4]>(gpa(0x1000)),
+            Ok([0x44, 0x33, 0x22, 0x11])
+        );
+        assert_eq!(aspace.read_le::<u32>(gpa(0x1000)), Ok(0x1122_3344));
+        assert_eq!(aspace.read_be::<u32>(gpa(0x1000)), Ok(0x4433_2211));
+
+        aspace.write_be(gpa(0x1ffe), 0x5566_7788i32).unwrap();
+        assert_eq!(
+            aspace.read_obj::<[u8; 4]>(gpa(0x1ffe)),
+            Ok([0x55, 0x66, 0x77, 0x88])
+        );
+        assert_eq!(aspace.read_be::<i32>(gpa(0x1ffe)), Ok(0x5566_7788));
+    }
+
+    #[test]
+    fn volatile_access_straddling_pages() {
+        let mut aspace = new_addr_space();
+        let val = 0x8877_6655_4433_2211u64;
+        aspace.write_volatile_obj(gpa(0x1ffc), val).unwrap();
+        let mut bytes = [0; 8];
+        aspace.read_bytes(gpa(0x1ffc), &mut bytes).unwrap();
+        assert_eq!(bytes, val.to_ne_bytes());
+        assert_eq!(aspace.read_volatile_obj::<u64>(gpa(0x1ffc)), Ok(val));
+        assert_eq!(
+            aspace.read_volatile_obj::<u64>(gpa(0x2ffc)),
+            Err(AxError::BadAddress)
+        );
+    }
+
+    /// A guest memory whose reads succeed without filling the buffers.
+    struct LazyMemory;
+
+    impl GuestMemory for LazyMemory {
+        fn read_bytes(&mut self, _gpa: GuestPhysAddr, _buf: &mut [u8]) -> AxResult {
+            Ok(())
+        }
+
+        fn write_bytes(&mut self, _gpa: GuestPhysAddr, _buf: &[u8]) -> AxResult {
+            Ok(())
+        }
+
+        fn read_volatile_bytes(&mut self, _gpa: GuestPhysAddr, _buf: &mut [u8]) -> AxResult {
+            Ok(())
+        }
+
+        fn write_volatile_bytes(&mut self, _gpa: GuestPhysAddr, _buf: &[u8]) -> AxResult {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unfilled_reads_are_zero() {
+        assert_eq!(LazyMemory.read_obj::<u64>(gpa(0)), Ok(0));
+        assert_eq!(LazyMemory.read_volatile_obj::<[u32; 2]>(gpa(0)), Ok([0, 0]));
+    }
+}
@@ -0,0 +1,652 @@
+use page_table_entry::MappingFlags;
+
+use super::{GuestTranslation, GuestWalkError};
+use crate::{GuestMemory, GuestPhysAddr, GuestVirtAddr};
+
+const SCTLR_M: u64 = 1 << 0;
+const SCTLR_WXN: u64 = 1 << 19;
+const SCTLR_EE: u64 = 1 << 25;
+
+const DESC_VALID: u64 = 1 << 0;
+/// Set for table and page descriptors, clear for block descriptors.
+const DESC_TABLE_OR_PAGE: u64 = 1 << 1;
+/// AP[1], EL0 access.
+const DESC_AP_EL0: u64 = 1 << 6;
+/// AP[2], read-only access.
+const DESC_AP_RO: u64 = 1 << 7;
+const DESC_AF: u64 = 1 << 10;
+const DESC_PXN: u64 = 1 << 53;
+const DESC_UXN: u64 = 1 << 54;
+const DESC_PXN_TABLE: u64 = 1 << 59;
+const DESC_UXN_TABLE: u64 = 1 << 60;
+/// APTable[0], no EL0 access in the subsequent levels.
+const DESC_AP_TABLE_NO_EL0: u64 = 1 << 61;
+/// APTable[1], no write access in the subsequent levels.
+const DESC_AP_TABLE_RO: u64 = 1 << 62;
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+const TTBR_BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+
+/// The kind of a stage-1 translation fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aarch64FaultKind {
+    /// An output or table address exceeds the physical address size.
+    AddressSize,
+    /// The address is not mapped by a valid descriptor.
+    Translation,
+    /// The access flag of the descriptor is not set.
+    AccessFlag,
+    /// The access is not permitted by the descriptors.
+    Permission,
+}
+
+/// A fault of an AArch64 stage-1 guest page table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aarch64TranslationFault {
+    /// The kind of the fault.
+    pub kind: Aarch64FaultKind,
+    /// The lookup level at which the fault occurred, from `0` to `3`.
+    pub level: usize,
+}
+
+impl Aarch64TranslationFault {
+    /// Returns the fault status code to report in the DFSC or IFSC field of
+    /// the syndrome of the abort injected into the guest.
+    pub const fn status_code(&self) -> u8 {
+        // The kinds are encoded in bits [5:2], the levels in bits [1:0].
+        let kind = match self.kind {
+            Aarch64FaultKind::AddressSize => 0b0000,
+            Aarch64FaultKind::Translation => 0b0001,
+            Aarch64FaultKind::AccessFlag => 0b0010,
+            Aarch64FaultKind::Permission => 0b0011,
+        };
+        (kind << 2) | self.level as u8
+    }
+}
+
+/// The stage-1 translation state of an AArch64 guest at EL1&0, given by its
+/// system registers.
+///
+/// The 4K, 16K and 64K granules are supported for both VA ranges, with any
+/// starting level given by the TxSZ fields, up to 48-bit input and output
+/// addresses. The access flags of the descriptors are not updated by the
+/// walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aarch64GuestPaging {
+    /// The guest SCTLR_EL1.
+    pub sctlr: u64,
+    /// The guest TCR_EL1.
+    pub tcr: u64,
+    /// The guest TTBR0_EL1.
+    pub ttbr0: u64,
+    /// The guest TTBR1_EL1.
+    pub ttbr1: u64,
+}
+
+/// The TCR_EL1 fields of one of the VA ranges.
+struct RangeConfig {
+    ttbr: u64,
+    /// The number of bits of the input addresses.
+    va_bits: u32,
+    /// The shift of the translation granule.
+    granule_shift: u32,
+    /// Whether table walks are disabled (EPDn).
+    walk_disabled: bool,
+    /// Whether the top byte is ignored (TBIn).
+    top_byte_ignored: bool,
+    /// Whether the hierarchical permissions are disabled (HPDn).
+    hierarchical_disabled: bool,
+}
+
+/// The accumulated permissions of the entries of a walk.
+struct Permissions {
+    writable: bool,
+    el0: bool,
+    el0_executable: bool,
+    el1_executable: bool,
+}
+
+impl Aarch64GuestPaging {
+    /// Translates `gva` for an access of `access` through the guest page
+    /// table, whose descriptors are read from `mem`.
+    ///
+    /// `access` may contain [`MappingFlags::WRITE`] and
+    /// [`MappingFlags::EXECUTE`], and [`MappingFlags::USER`] for EL0 accesses.
+    /// The returned permissions are the ones of the exception level of the
+    /// access. Returns an [`Aarch64TranslationFault`] if the access faults.
+    pub fn translate<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<Aarch64TranslationFault>> {
+        let va = gva.as_usize() as u64;
+        if self.sctlr & SCTLR_M == 0 {
+            // Input addresses are output addresses with the stage-1 MMU off.
+            return Ok(GuestTranslation {
+                gpa: GuestPhysAddr::from_usize(va as usize),
+                flags: MappingFlags::READ
+                    | MappingFlags::WRITE
+                    | MappingFlags::EXECUTE
+                    | MappingFlags::USER,
+                page_size: 1 << 12,
+            });
+        }
+
+        let upper = va & (1 << 55) != 0;
+        let config = self.range_config(upper);
+        let top_bits = if config.top_byte_ignored { 56 } else { 64 };
+        let mut range_mask = !((1u64 << config.va_bits) - 1);
+        if top_bits < 64 {
+            range_mask &= (1u64 << top_bits) - 1;
+        }
+        let expected = if upper { range_mask } else { 0 };
+        if va & range_mask != expected || config.walk_disabled {
+            return Err(fault(Aarch64FaultKind::Translation, 0));
+        }
+
+        let pa_bits = self.pa_bits();
+        let granule_shift = config.granule_shift;
+        let stride = granule_shift - 3;
+        let levels = (config.va_bits - granule_shift).div_ceil(stride);
+        let start_level = 4 - levels as usize;
+        let level_shift = |level: usize| granule_shift + stride * (3 - level as u32);
+
+        // The table at the start level may be smaller than a granule.
+        let start_index_bits = config.va_bits - level_shift(start_level);
+        let start_table_size = (8u64 << start_index_bits).max(64);
+        let mut table = config.ttbr & TTBR_BADDR_MASK & !(start_table_size - 1);
+        if table >> pa_bits != 0 {
+            return Err(fault(Aarch64FaultKind::AddressSize, 0));
+        }
+
+        let mut perms = Permissions {
+            writable: true,
+            el0: true,
+            el0_executable: true,
+            el1_executable: true,
+        };
+        for level in start_level..=3 {
+            let shift = level_shift(level);
+            let index_bits = stride.min(config.va_bits - shift);
+            let index = (va >> shift) & ((1 << index_bits) - 1);
+            let entry_gpa = GuestPhysAddr::from_usize((table + index * 8) as usize);
+            let read = if self.sctlr & SCTLR_EE != 0 {
+                mem.read_be::<u64>(entry_gpa)
+            } else {
+                mem.read_le::<u64>(entry_gpa)
+            };
+            let desc = read.map_err(|err| GuestWalkError::TableAccess { entry_gpa, err })?;
+
+            if desc & DESC_VALID == 0 {
+                return Err(fault(Aarch64FaultKind::Translation, level));
+            }
+            let is_table = level < 3 && desc & DESC_TABLE_OR_PAGE != 0;
+            // Blocks are only allowed at level 2, or at level 1 with the 4K
+            // granule, and level 3 only holds page descriptors.
+            let is_valid_leaf = if level == 3 {
+                desc & DESC_TABLE_OR_PAGE != 0
+            } else {
+                level == 2 || (level == 1 && granule_shift == 12)
+            };
+            if !is_table && !is_valid_leaf {
+                return Err(fault(Aarch64FaultKind::Translation, level));
+            }
+
+            let out_shift = if is_table { granule_shift } else { shift };
+            let addr = desc & DESC_ADDR_MASK & !((1 << out_shift) - 1);
+            if addr >> pa_bits != 0 {
+                return Err(fault(Aarch64FaultKind::AddressSize, level));
+            }
+
+            if is_table {
+                if !config.hierarchical_disabled {
+                    perms.writable &= desc & DESC_AP_TABLE_RO == 0;
+                    perms.el0 &= desc & DESC_AP_TABLE_NO_EL0 == 0;
+                    perms.el0_executable &= desc & DESC_UXN_TABLE == 0;
+                    perms.el1_executable &= desc & DESC_PXN_TABLE == 0;
+                }
+                table = addr;
+                continue;
+            }
+
+            if desc & DESC_AF == 0 {
+                return Err(fault(Aarch64FaultKind::AccessFlag, level));
+            }
+            perms.writable &= desc & DESC_AP_RO == 0;
+            perms.el0 &= desc & DESC_AP_EL0 != 0;
+            perms.el0_executable &= desc & DESC_UXN == 0;
+            // EL1 never executes from memory writable at EL0.
+            perms.el1_executable &= desc & DESC_PXN == 0 && !(perms.el0 && desc & DESC_AP_RO == 0);
+            let flags = self.effective_flags(&perms, access.contains(MappingFlags::USER));
+            if !flags.contains(access) {
+                return Err(fault(Aarch64FaultKind::Permission, level));
+            }
+            let page_size = 1u64 << shift;
+            return Ok(GuestTranslation {
+                gpa: GuestPhysAddr::from_usize((addr | (va & (page_size - 1))) as usize),
+                flags,
+                page_size: page_size as usize,
+            });
+        }
+        unreachable!()
+    }
+
+    /// Returns the permissions of the accesses from EL0 if `el0`, or from
+    /// EL1 otherwise.
+    fn effective_flags(&self, perms: &Permissions, el0: bool) -> MappingFlags {
+        if el0 && !perms.el0 {
+            return MappingFlags::empty();
+        }
+        let mut flags = MappingFlags::READ;
+        if perms.writable {
+            flags |= MappingFlags::WRITE;
+        }
+        let executable = if el0 {
+            perms.el0_executable
+        } else {
+            perms.el1_executable
+        };
+        // Writable memory is never executable with SCTLR_EL1.WXN.
+        if executable && !(perms.writable && self.sctlr & SCTLR_WXN != 0) {
+            flags |= MappingFlags::EXECUTE;
+        }
+        if el0 {
+            flags |= MappingFlags::USER;
+        }
+        flags
+    }
+
+    fn range_config(&self, upper: bool) -> RangeConfig {
+        let tcr = self.tcr;
+        let (ttbr, tsz, granule_shift, epd, tbi, hpd) = if upper {
+            let granule_shift = match (tcr >> 30) & 0b11 {
+                0b01 => 14,
+                0b11 => 16,
+                _ => 12,
+            };
+            (
+                self.ttbr1,
+                tcr >> 16,
+                granule_shift,
+                tcr >> 23,
+                tcr >> 38,
+                tcr >> 42,
+            )
+        } else {
+            let granule_shift = match (tcr >> 14) & 0b11 {
+                0b01 => 16,
+                0b10 => 14,
+                _ => 12,
+            };
+            (
+                self.ttbr0,
+                tcr,
+                granule_shift,
+                tcr >> 7,
+                tcr >> 37,
+                tcr >> 41,
+            )
+        };
+        RangeConfig {
+            ttbr,
+            // Out-of-range TxSZ values are treated as the closest valid ones.
+            va_bits: 64 - (tsz & 0x3f).clamp(16, 39) as u32,
+            granule_shift,
+            walk_disabled: epd & 1 != 0,
+            top_byte_ignored: tbi & 1 != 0,
+            hierarchical_disabled: hpd & 1 != 0,
+        }
+    }
+
+    /// Returns the physical address size given by TCR_EL1.IPS.
+    fn pa_bits(&self) -> u32 {
+        match (self.tcr >> 32) & 0b111 {
+            0b000 => 32,
+            0b001 => 36,
+            0b010 => 40,
+            0b011 => 42,
+            0b100 => 44,
+            _ => 48,
+        }
+    }
+}
+
+const fn fault(kind: Aarch64FaultKind, level: usize) -> GuestWalkError<Aarch64TranslationFault> {
+    GuestWalkError::Fault(Aarch64TranslationFault { kind, level })
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::guest_paging::TestMemory;
+
+    const R: MappingFlags = MappingFlags::READ;
+    const W: MappingFlags = MappingFlags::WRITE;
+    const X: MappingFlags = MappingFlags::EXECUTE;
+    const U: MappingFlags = MappingFlags::USER;
+
+    const TABLE: u64 = DESC_VALID | DESC_TABLE_OR_PAGE;
+    const BLOCK: u64 = DESC_VALID | DESC_AF;
+    const PAGE: u64 = DESC_VALID | DESC_TABLE_OR_PAGE | DESC_AF;
+
+    /// A 39-bit VA range with the 4K granule and a 48-bit PA size.
+    const TCR_4K_39: u64 = 25 | (0b101 << 32);
+
+    fn gva(addr: usize) -> GuestVirtAddr {
+        GuestVirtAddr::from_usize(addr)
+    }
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from_usize(addr)
+    }
+
+    fn set_entry(mem: &mut TestMemory, table: usize, index: usize, desc: u64) {
+        mem.write_le(gpa(table + index * 8), desc).unwrap();
+    }
+
+    fn fault_of(
+        res: Result<GuestTranslation, GuestWalkError<Aarch64TranslationFault>>,
+    ) -> (Aarch64FaultKind, usize) {
+        match res {
+            Err(GuestWalkError::Fault(fault)) => (fault.kind, fault.level),
+            res => panic!("unexpected walk result {res:?}"),
+        }
+    }
+
+    /// A 3-level walk with tables at 0x1000 (level 1), 0x2000 (level 2) and
+    /// 0x3000 (level 3).
+    fn three_levels(mem: &mut TestMemory) -> Aarch64GuestPaging {
+        set_entry(mem, 0x1000, 0, 0x2000 | TABLE);
+        set_entry(mem, 0x2000, 0, 0x3000 | TABLE);
+        Aarch64GuestPaging {
+            sctlr: SCTLR_M,
+            tcr: TCR_4K_39,
+            ttbr0: 0x1000,
+            ttbr1: 0,
+        }
+    }
+
+    #[test]
+    fn mmu_disabled() {
+        let paging = Aarch64GuestPaging {
+            sctlr: 0,
+            tcr: 0,
+            ttbr0: 0,
+            ttbr1: 0,
+        };
+        let res = paging.translate(&mut TestMemory::new(0), gva(0x1234_5678), W | X | U);
+        assert_eq!(res.unwrap().gpa, gpa(0x1234_5678));
+    }
+
+    #[test]
+    fn pages_and_permissions() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = three_levels(&mut mem);
+        set_entry(&mut mem, 0x3000, 5, 0x8_0000 | PAGE | DESC_AP_EL0);
+        set_entry(
+            &mut mem,
+            0x3000,
+            6,
+            0x9_0000 | DESC_VALID | DESC_TABLE_OR_PAGE,
+        );
+        set_entry(&mut mem, 0x3000, 8, 0xa_0000 | PAGE | DESC_AP_RO | DESC_UXN);
+
+        let res = paging.translate(&mut mem, gva(0x5123), W | U).unwrap();
+        assert_eq!(res.gpa, gpa(0x8_0123));
+        assert_eq!(res.page_size, 0x1000);
+        assert_eq!(res.flags, R | W | X | U);
+        // EL1 never executes from memory writable at EL0.
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x5000), R).unwrap().flags,
+            R | W
+        );
+        let res = paging.translate(&mut mem, gva(0x5000), X);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Permission, 3));
+
+        // A read-only page of EL1.
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x8000), R).unwrap().flags,
+            R | X
+        );
+        let res = paging.translate(&mut mem, gva(0x8000), W);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Permission, 3));
+        let res = paging.translate(&mut mem, gva(0x8000), U);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Permission, 3));
+
+        let res = paging.translate(&mut mem, gva(0x6000), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::AccessFlag, 3));
+        let res = paging.translate(&mut mem, gva(0x7000), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 3));
+        let res = paging.translate(&mut mem, gva(0x4000_0000), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 1));
+    }
+
+    #[test]
+    fn status_codes() {
+        let code = |kind, level| Aarch64TranslationFault { kind, level }.status_code();
+        assert_eq!(code(Aarch64FaultKind::AddressSize, 0), 0b00_0000);
+        assert_eq!(code(Aarch64FaultKind::Translation, 1), 0b00_0101);
+        assert_eq!(code(Aarch64FaultKind::AccessFlag, 2), 0b00_1010);
+        assert_eq!(code(Aarch64FaultKind::Permission, 3), 0b00_1111);
+    }
+
+    #[test]
+    fn blocks() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = three_levels(&mut mem);
+        set_entry(&mut mem, 0x1000, 1, 0x8000_0000 | BLOCK);
+        set_entry(&mut mem, 0x2000, 1, 0x4000_0000 | BLOCK);
+        // Level 3 has no block descriptors.
+        set_entry(&mut mem, 0x3000, 0, 0x5000_0000 | BLOCK);
+
+        let res = paging.translate(&mut mem, gva(0x4123_4567), W).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x8123_4567), 0x4000_0000));
+        let res = paging.translate(&mut mem, gva(0x3f_ffff), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x401f_ffff), 0x20_0000));
+        assert_eq!(res.flags, R | W | X);
+        let res = paging.translate(&mut mem, gva(0), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 3));
+    }
+
+    #[test]
+    fn granules() {
+        // The 16K granule with a 36-bit VA range starts at level 2, with a
+        // 32M block size.
+        let mut mem = TestMemory::new(0x2_0000);
+        set_entry(&mut mem, 0x4000, 0, 0x8000 | TABLE);
+        set_entry(&mut mem, 0x4000, 1, 0x200_0000 | BLOCK);
+        set_entry(&mut mem, 0x8000, 1, 0x10_0000 | PAGE);
+        let paging = Aarch64GuestPaging {
+            sctlr: SCTLR_M,
+            tcr: 28 | (0b10 << 14) | (0b101 << 32),
+            ttbr0: 0x4000,
+            ttbr1: 0,
+        };
+        let res = paging.translate(&mut mem, gva(0x4012), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x10_0012), 0x4000));
+        let res = paging.translate(&mut mem, gva(0x200_0005), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x200_0005), 0x200_0000));
+        // There are no level 1 blocks with the 16K granule.
+        let paging = Aarch64GuestPaging {
+            tcr: 17 | (0b10 << 14) | (0b101 << 32),
+            ..paging
+        };
+        let res = paging.translate(&mut mem, gva(1 << 36), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 1));
+
+        // The 64K granule with a 42-bit VA range starts at level 2.
+        let mut mem = TestMemory::new(0x3_0000);
+        set_entry(&mut mem, 0x1_0000, 0, 0x2_0000 | TABLE);
+        set_entry(&mut mem, 0x2_0000, 1, 0x50_0000 | PAGE);
+        let paging = Aarch64GuestPaging {
+            sctlr: SCTLR_M,
+            tcr: 22 | (0b01 << 14) | (0b101 << 32),
+            ttbr0: 0x1_0000,
+            ttbr1: 0,
+        };
+        let res = paging.translate(&mut mem, gva(0x1_2345), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x50_2345), 0x1_0000));
+    }
+
+    #[test]
+    fn va_ranges() {
+        let mut mem = TestMemory::new(0x2_0000);
+        let mut paging = three_levels(&mut mem);
+        set_entry(&mut mem, 0x3000, 0, 0x8_0000 | PAGE);
+        set_entry(&mut mem, 0x1_0000, 0, 0x8000_0000 | BLOCK);
+        // TTBR1 with a 39-bit VA range and the 4K granule.
+        paging.tcr |= (25 << 16) | (0b10 << 30);
+        paging.ttbr1 = 0x1_0000;
+
+        let res = paging
+            .translate(&mut mem, gva(0xffff_ff80_0000_1234), R)
+            .unwrap();
+        assert_eq!(res.gpa, gpa(0x8000_1234));
+        let res = paging.translate(&mut mem, gva(0xffff_ff00_0000_0000), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 0));
+        let res = paging.translate(&mut mem, gva(0x80_0000_0000), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 0));
+
+        // The top byte is only ignored with TBI0.
+        let tagged = gva(0x5a00_0000_0000_0010);
+        let res = paging.translate(&mut mem, tagged, R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 0));
+        let tbi = Aarch64GuestPaging {
+            tcr: paging.tcr | (1 << 37),
+            ..paging
+        };
+        assert_eq!(
+            tbi.translate(&mut mem, tagged, R).unwrap().gpa,
+            gpa(0x8_0010)
+        );
+
+        // Walks of TTBR0 are disabled with EPD0.
+        let epd = Aarch64GuestPaging {
+            tcr: paging.tcr | (1 << 7),
+            ..paging
+        };
+        let res = epd.translate(&mut mem, gva(0), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Translation, 0));
+        assert!(
+            epd.translate(&mut mem, gva(0xffff_ff80_0000_0000), R)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn address_size_and_table_access() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = three_levels(&mut mem);
+        set_entry(&mut mem, 0x3000, 0, 0x1_0000_0000 | PAGE);
+        set_entry(&mut mem, 0x2000, 1, 0x10_0000 | TABLE);
+
+        assert!(paging.translate(&mut mem, gva(0), R).is_ok());
+        // A 32-bit PA size.
+        let small = Aarch64GuestPaging {
+            tcr: TCR_4K_39 & !(0b111 << 32),
+            ..paging
+        };
+        let res = small.translate(&mut mem, gva(0), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::AddressSize, 3));
+        let res = Aarch64GuestPaging {
+            ttbr0: 0x1_0000_0000,
+            ..small
+        }
+        .translate(&mut mem, gva(0), R);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::AddressSize, 0));
+
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x20_0000), R),
+            Err(GuestWalkError::TableAccess {
+                entry_gpa: gpa(0x10_0000),
+                err: AxError::BadAddress,
+            })
+        );
+    }
+
+    #[test]
+    fn big_endian_descriptors() {
+        let mut mem = TestMemory::new(0x4000);
+        mem.write_be(gpa(0x1000), 0x2000 | TABLE).unwrap();
+        mem.write_be(gpa(0x2000), 0x3000 | TABLE).unwrap();
+        mem.write_be(gpa(0x3008), 0x8_0000 | PAGE).unwrap();
+        let paging = Aarch64GuestPaging {
+            sctlr: SCTLR_M | SCTLR_EE,
+            tcr: TCR_4K_39,
+            ttbr0: 0x1000,
+            ttbr1: 0,
+        };
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x1010), R).unwrap().gpa,
+            gpa(0x8_0010)
+        );
+        let le = Aarch64GuestPaging {
+            sctlr: SCTLR_M,
+            ..paging
+        };
+        let res = le.translate(&mut mem, gva(0x1010), R);
+        assert!(matches!(res, Err(GuestWalkError::Fault(_))));
+    }
+
+    #[test]
+    fn write_implies_execute_never() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = Aarch64GuestPaging {
+            sctlr: SCTLR_M | SCTLR_WXN,
+            ..three_levels(&mut mem)
+        };
+        set_entry(&mut mem, 0x3000, 0, 0x8_0000 | PAGE | DESC_AP_EL0);
+        set_entry(
+            &mut mem,
+            0x3000,
+            1,
+            0x9_0000 | PAGE | DESC_AP_EL0 | DESC_AP_RO,
+        );
+        assert_eq!(
+            paging.translate(&mut mem, gva(0), U).unwrap().flags,
+            R | W | U
+        );
+        let res = paging.translate(&mut mem, gva(0x1000), X | U).unwrap();
+        assert_eq!(res.flags, R | X | U);
+    }
+
+    #[test]
+    fn hierarchical_permissions() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = three_levels(&mut mem);
+        set_entry(&mut mem, 0x3000, 0, 0x8_0000 | PAGE | DESC_AP_EL0);
+        set_entry(&mut mem, 0x3000, 1, 0x9_0000 | PAGE);
+
+        set_entry(
+            &mut mem,
+            0x1000,
+            0,
+            0x2000 | TABLE | DESC_AP_TABLE_RO | DESC_UXN_TABLE,
+        );
+        assert_eq!(paging.translate(&mut mem, gva(0), U).unwrap().flags, R | U);
+        let res = paging.translate(&mut mem, gva(0x1000), W);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Permission, 3));
+        // The table permissions are ignored with HPD0.
+        let hpd = Aarch64GuestPaging {
+            tcr: paging.tcr | (1 << 41),
+            ..paging
+        };
+        assert_eq!(
+            hpd.translate(&mut mem, gva(0), U).unwrap().flags,
+            R | W | X | U
+        );
+
+        set_entry(
+            &mut mem,
+            0x2000,
+            0,
+            0x3000 | TABLE | DESC_AP_TABLE_NO_EL0 | DESC_PXN_TABLE,
+        );
+        let res = paging.translate(&mut mem, gva(0), R | U);
+        assert_eq!(fault_of(res), (Aarch64FaultKind::Permission, 3));
+        // The EL0 page is only writable at EL1, and EL1 may not execute.
+        assert_eq!(paging.translate(&mut mem, gva(0x1000), R).unwrap().flags, R);
+    }
+}
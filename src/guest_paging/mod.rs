@@ -0,0 +1,88 @@
+//! Translation of guest virtual addresses through the page tables of the
+//! guest, which are read from guest physical memory.
+
+use axerrno::AxError;
+use page_table_entry::MappingFlags;
+
+use crate::GuestPhysAddr;
+
+mod aarch64;
+mod riscv;
+mod x86_64;
+
+pub use aarch64::{Aarch64FaultKind, Aarch64GuestPaging, Aarch64TranslationFault};
+pub use riscv::{RiscvGuestPaging, RiscvPageFault, RiscvPageFaultCause};
+pub use x86_64::{X86GuestPaging, X86PageFault, X86PageFaultErrorCode};
+
+/// A guest virtual address translated by the guest page table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GuestTranslation {
+    /// The translated guest physical address.
+    pub gpa: GuestPhysAddr,
+    /// The effective permissions of all the levels of the walk.
+    pub flags: MappingFlags,
+    /// The size of the page mapping the address, in bytes.
+    pub page_size: usize,
+}
+
+/// Errors of a guest page table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestWalkError<F> {
+    /// The walk faulted, the architecture-specific fault `F` should be
+    /// injected into the guest.
+    Fault(F),
+    /// The address is not in the range translated by the current paging mode
+    /// of the guest, such as a non-canonical address on x86_64.
+    OutOfRange,
+    /// An entry of the guest page table could not be read from guest
+    /// physical memory.
+    TableAccess {
+        /// The guest physical address of the entry.
+        entry_gpa: GuestPhysAddr,
+        /// The error of the access.
+        err: AxError,
+    },
+}
+
+/// Guest physical memory backed by a buffer, to test the walkers.
+#[cfg(test)]
+struct TestMemory(alloc::vec::Vec<u8>);
+
+#[cfg(test)]
+impl TestMemory {
+    /// Creates a zeroed guest physical memory of `size` bytes at address 0.
+    fn new(size: usize) -> Self {
+        Self(alloc::vec![0; size])
+    }
+
+    fn range(&self, gpa: GuestPhysAddr, len: usize) -> axerrno::AxResult<core::ops::Range<usize>> {
+        let start = gpa.as_usize();
+        match start.checked_add(len) {
+            Some(end) if end <= self.0.len() => Ok(start..end),
+            _ => Err(AxError::BadAddress),
+        }
+    }
+}
+
+#[cfg(test)]
+impl crate::GuestMemory for TestMemory {
+    fn read_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> axerrno::AxResult {
+        let range = self.range(gpa, buf.len())?;
+        buf.copy_from_slice(&self.0[range]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> axerrno::AxResult {
+        let range = self.range(gpa, buf.len())?;
+        self.0[range].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &mut [u8]) -> axerrno::AxResult {
+        self.read_bytes(gpa, buf)
+    }
+
+    fn write_volatile_bytes(&mut self, gpa: GuestPhysAddr, buf: &[u8]) -> axerrno::AxResult {
+        self.write_bytes(gpa, buf)
+    }
+}
@@ -0,0 +1,417 @@
+use page_table_entry::MappingFlags;
+
+use super::{GuestTranslation, GuestWalkError};
+use crate::{GuestMemory, GuestPhysAddr, GuestVirtAddr};
+
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const SATP_MODE_SV48: u64 = 9;
+const SATP_MODE_SV57: u64 = 10;
+const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+const SSTATUS_SUM: u64 = 1 << 18;
+const SSTATUS_MXR: u64 = 1 << 19;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+/// The PPN field of a PTE, in bits 10 to 53.
+const PTE_PPN_MASK: u64 = ((1 << 44) - 1) << 10;
+/// Bits of a PTE that are reserved for future standard use.
+const PTE_RESERVED: u64 = 0x7f << 54;
+
+numeric_enum_macro::numeric_enum! {
+    #[repr(usize)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    /// The exception causes of the page faults of a VS-stage translation.
+    pub enum RiscvPageFaultCause {
+        /// Instruction page fault.
+        InstructionPageFault = 12,
+        /// Load page fault.
+        LoadPageFault = 13,
+        /// Store/AMO page fault.
+        StorePageFault = 15,
+    }
+}
+
+/// A page fault of a RISC-V VS-stage guest page table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RiscvPageFault {
+    /// The exception cause to inject into the guest, with the faulting guest
+    /// virtual address in `stval`.
+    pub cause: RiscvPageFaultCause,
+    /// The level of the PTE that caused the fault, from `0` for the leaf
+    /// level of 4K pages.
+    pub level: usize,
+}
+
+/// The VS-stage translation state of a RISC-V guest, given by its CSRs.
+///
+/// The Sv39, Sv48 and Sv57 modes are supported. The A and D bits of the guest
+/// PTEs are not updated by the walk, accesses to pages without them fault as
+/// without the Svadu extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RiscvGuestPaging {
+    /// The guest `vsatp`.
+    pub vsatp: u64,
+    /// The guest `vsstatus`, for its SUM and MXR bits.
+    pub vsstatus: u64,
+}
+
+impl RiscvGuestPaging {
+    /// Translates `gva` for an access of `access` through the guest page
+    /// table, whose PTEs are read from `mem`.
+    ///
+    /// `access` may contain [`MappingFlags::WRITE`] and
+    /// [`MappingFlags::EXECUTE`], and [`MappingFlags::USER`] for VU-mode
+    /// accesses. Returns a [`RiscvPageFault`] if the access is not permitted.
+    pub fn translate<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<RiscvPageFault>> {
+        let va = gva.as_usize() as u64;
+        let levels = match self.vsatp >> 60 {
+            SATP_MODE_SV39 => 3,
+            SATP_MODE_SV48 => 4,
+            SATP_MODE_SV57 => 5,
+            mode => {
+                if mode != SATP_MODE_BARE {
+                    warn!("Unsupported vsatp mode {:#x}, treated as Bare", mode);
+                }
+                return Ok(GuestTranslation {
+                    gpa: GuestPhysAddr::from_usize(va as usize),
+                    flags: MappingFlags::READ
+                        | MappingFlags::WRITE
+                        | MappingFlags::EXECUTE
+                        | MappingFlags::USER,
+                    page_size: 1 << 12,
+                });
+            }
+        };
+
+        // The address must be sign-extended from its highest bit.
+        let shift = 64 - (12 + 9 * levels);
+        if ((va << shift) as i64 >> shift) as u64 != va {
+            return Err(fault(access, levels - 1));
+        }
+
+        let mut table = (self.vsatp & SATP_PPN_MASK) << 12;
+        for level in (0..levels).rev() {
+            let shift = 12 + 9 * level;
+            let entry_gpa =
+                GuestPhysAddr::from_usize((table + ((va >> shift) & 0x1ff) * 8) as usize);
+            // RISC-V page tables are little-endian.
+            let pte = mem
+                .read_le::<u64>(entry_gpa)
+                .map_err(|err| GuestWalkError::TableAccess { entry_gpa, err })?;
+            if pte & PTE_V == 0 || (pte & PTE_W != 0 && pte & PTE_R == 0) || pte & PTE_RESERVED != 0
+            {
+                return Err(fault(access, level));
+            }
+
+            let base = (pte & PTE_PPN_MASK) << 2;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Non-leaf PTEs must not have the bits of leaf PTEs.
+                if level == 0 || pte & (PTE_U | PTE_A | PTE_D) != 0 {
+                    return Err(fault(access, level));
+                }
+                table = base;
+                continue;
+            }
+
+            let page_size = 1u64 << shift;
+            // Superpages must be aligned.
+            if base & (page_size - 1) != 0 || !self.permitted(pte, access) {
+                return Err(fault(access, level));
+            }
+            return Ok(GuestTranslation {
+                gpa: GuestPhysAddr::from_usize((base | (va & (page_size - 1))) as usize),
+                flags: self.effective_flags(pte),
+                page_size: page_size as usize,
+            });
+        }
+        unreachable!()
+    }
+
+    /// Checks an access against the flags of a leaf PTE.
+    fn permitted(&self, pte: u64, access: MappingFlags) -> bool {
+        let user_page = pte & PTE_U != 0;
+        let privilege_ok = if access.contains(MappingFlags::USER) {
+            user_page
+        } else if access.contains(MappingFlags::EXECUTE) {
+            // VS-mode never executes from user pages.
+            !user_page
+        } else {
+            !user_page || self.vsstatus & SSTATUS_SUM != 0
+        };
+        let access_ok = if access.contains(MappingFlags::EXECUTE) {
+            pte & PTE_X != 0
+        } else if access.contains(MappingFlags::WRITE) {
+            pte & PTE_W != 0 && pte & PTE_D != 0
+        } else {
+            self.effective_flags(pte).contains(MappingFlags::READ)
+        };
+        privilege_ok && access_ok && pte & PTE_A != 0
+    }
+
+    /// Returns the permissions of a leaf PTE, with the loads from executable
+    /// pages made readable by MXR.
+    fn effective_flags(&self, pte: u64) -> MappingFlags {
+        let mut mflags = MappingFlags::empty();
+        if pte & PTE_R != 0 || (pte & PTE_X != 0 && self.vsstatus & SSTATUS_MXR != 0) {
+            mflags |= MappingFlags::READ;
+        }
+        if pte & PTE_W != 0 {
+            mflags |= MappingFlags::WRITE;
+        }
+        if pte & PTE_X != 0 {
+            mflags |= MappingFlags::EXECUTE;
+        }
+        if pte & PTE_U != 0 {
+            mflags |= MappingFlags::USER;
+        }
+        mflags
+    }
+}
+
+/// Builds the page fault at `level` for an access of `access`.
+fn fault(access: MappingFlags, level: usize) -> GuestWalkError<RiscvPageFault> {
+    let cause = if access.contains(MappingFlags::EXECUTE) {
+        RiscvPageFaultCause::InstructionPageFault
+    } else if access.contains(MappingFlags::WRITE) {
+        RiscvPageFaultCause::StorePageFault
+    } else {
+        RiscvPageFaultCause::LoadPageFault
+    };
+    GuestWalkError::Fault(RiscvPageFault { cause, level })
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::guest_paging::TestMemory;
+
+    const R: MappingFlags = MappingFlags::READ;
+    const W: MappingFlags = MappingFlags::WRITE;
+    const X: MappingFlags = MappingFlags::EXECUTE;
+    const U: MappingFlags = MappingFlags::USER;
+
+    const LOAD: RiscvPageFaultCause = RiscvPageFaultCause::LoadPageFault;
+    const STORE: RiscvPageFaultCause = RiscvPageFaultCause::StorePageFault;
+    const FETCH: RiscvPageFaultCause = RiscvPageFaultCause::InstructionPageFault;
+
+    const RWX_AD: u64 = PTE_V | PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+
+    fn gva(addr: usize) -> GuestVirtAddr {
+        GuestVirtAddr::from_usize(addr)
+    }
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from_usize(addr)
+    }
+
+    /// Builds a PTE pointing to `paddr`.
+    const fn pte(paddr: u64, flags: u64) -> u64 {
+        ((paddr >> 12) << 10) | flags
+    }
+
+    fn set_entry(mem: &mut TestMemory, table: usize, index: usize, entry: u64) {
+        mem.write_le(gpa(table + index * 8), entry).unwrap();
+    }
+
+    fn fault_of(
+        res: Result<GuestTranslation, GuestWalkError<RiscvPageFault>>,
+    ) -> (RiscvPageFaultCause, usize) {
+        match res {
+            Err(GuestWalkError::Fault(fault)) => (fault.cause, fault.level),
+            res => panic!("unexpected walk result {res:?}"),
+        }
+    }
+
+    /// Sv39 with tables at 0x1000 (level 2), 0x2000 (level 1) and 0x3000
+    /// (level 0).
+    fn sv39(mem: &mut TestMemory) -> RiscvGuestPaging {
+        set_entry(mem, 0x1000, 0, pte(0x2000, PTE_V));
+        set_entry(mem, 0x2000, 0, pte(0x3000, PTE_V));
+        RiscvGuestPaging {
+            vsatp: (SATP_MODE_SV39 << 60) | 1,
+            vsstatus: 0,
+        }
+    }
+
+    #[test]
+    fn bare_mode() {
+        let paging = RiscvGuestPaging {
+            vsatp: 0,
+            vsstatus: 0,
+        };
+        let res = paging.translate(&mut TestMemory::new(0), gva(0x1234_5678), W | X | U);
+        assert_eq!(res.unwrap().gpa, gpa(0x1234_5678));
+    }
+
+    #[test]
+    fn sv39_pages() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = sv39(&mut mem);
+        set_entry(&mut mem, 0x3000, 5, pte(0x8_0000, RWX_AD));
+        set_entry(&mut mem, 0x3000, 6, pte(0x9_0000, RWX_AD & !PTE_D));
+        set_entry(&mut mem, 0x3000, 7, pte(0xa_0000, RWX_AD & !PTE_A));
+        set_entry(
+            &mut mem,
+            0x3000,
+            8,
+            pte(0xb_0000, PTE_V | PTE_W | PTE_A | PTE_D),
+        );
+        set_entry(&mut mem, 0x3000, 9, pte(0xc_0000, RWX_AD | (1 << 54)));
+
+        let res = paging.translate(&mut mem, gva(0x5123), W).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x8_0123), 0x1000));
+        assert_eq!(res.flags, R | W | X);
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x5000), U)),
+            (LOAD, 0)
+        );
+
+        // Accesses fault without the A bit, and writes without the D bit.
+        assert!(paging.translate(&mut mem, gva(0x6000), R).is_ok());
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x6000), W)),
+            (STORE, 0)
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x7000), X)),
+            (FETCH, 0)
+        );
+        // Write-only PTEs and reserved bits.
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x8000), R)),
+            (LOAD, 0)
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x9000), R)),
+            (LOAD, 0)
+        );
+        // Not present.
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0xa000), W)),
+            (STORE, 0)
+        );
+        let res = paging.translate(&mut mem, gva(0x4000_0000), R);
+        assert_eq!(fault_of(res), (LOAD, 2));
+    }
+
+    #[test]
+    fn superpages() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = sv39(&mut mem);
+        set_entry(&mut mem, 0x2000, 1, pte(0x4000_0000, RWX_AD));
+        set_entry(&mut mem, 0x2000, 2, pte(0x4010_0000, RWX_AD));
+        set_entry(&mut mem, 0x1000, 1, pte(0x8000_0000, RWX_AD));
+        // Non-leaf PTEs with the bits of leaf PTEs.
+        set_entry(&mut mem, 0x2000, 3, pte(0x3000, PTE_V | PTE_A));
+
+        let res = paging.translate(&mut mem, gva(0x3f_ffff), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x401f_ffff), 0x20_0000));
+        let res = paging.translate(&mut mem, gva(0x4123_4567), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x8123_4567), 0x4000_0000));
+        // Misaligned superpages.
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x40_0000), R)),
+            (LOAD, 1)
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x60_0000), R)),
+            (LOAD, 1)
+        );
+    }
+
+    #[test]
+    fn sum_and_mxr() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = sv39(&mut mem);
+        set_entry(&mut mem, 0x3000, 0, pte(0x8_0000, RWX_AD | PTE_U));
+        set_entry(&mut mem, 0x3000, 1, pte(0x9_0000, PTE_V | PTE_X | PTE_A));
+
+        let res = paging.translate(&mut mem, gva(0), W | X | U).unwrap();
+        assert_eq!(res.flags, R | W | X | U);
+        assert_eq!(fault_of(paging.translate(&mut mem, gva(0), R)), (LOAD, 0));
+        let sum = RiscvGuestPaging {
+            vsstatus: SSTATUS_SUM,
+            ..paging
+        };
+        assert!(sum.translate(&mut mem, gva(0), W).is_ok());
+        // VS-mode never executes from user pages.
+        assert_eq!(fault_of(sum.translate(&mut mem, gva(0), X)), (FETCH, 0));
+
+        // Execute-only pages are readable with MXR.
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x1000), R)),
+            (LOAD, 0)
+        );
+        let mxr = RiscvGuestPaging {
+            vsstatus: SSTATUS_MXR,
+            ..paging
+        };
+        assert_eq!(
+            mxr.translate(&mut mem, gva(0x1000), R).unwrap().flags,
+            R | X
+        );
+    }
+
+    #[test]
+    fn sv48_and_non_canonical_addresses() {
+        let mut mem = TestMemory::new(0x5000);
+        let paging = sv39(&mut mem);
+        set_entry(&mut mem, 0x3000, 0, pte(0x8_0000, RWX_AD));
+
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(1 << 38), R)),
+            (LOAD, 2)
+        );
+        // Canonical upper addresses are walked.
+        let res = paging.translate(&mut mem, gva(0xffff_ffc0_0000_0000), W);
+        assert_eq!(fault_of(res), (STORE, 2));
+
+        // Sv48 with a level 3 table at 0x4000.
+        set_entry(&mut mem, 0x4000, 0, pte(0x1000, PTE_V));
+        let sv48 = RiscvGuestPaging {
+            vsatp: (SATP_MODE_SV48 << 60) | 4,
+            vsstatus: 0,
+        };
+        assert_eq!(
+            sv48.translate(&mut mem, gva(0x10), R).unwrap().gpa,
+            gpa(0x8_0010)
+        );
+        // Addresses beyond Sv39 are walked in Sv48.
+        assert_eq!(
+            fault_of(sv48.translate(&mut mem, gva(1 << 38), R)),
+            (LOAD, 2)
+        );
+        assert_eq!(
+            fault_of(sv48.translate(&mut mem, gva(1 << 47), X)),
+            (FETCH, 3)
+        );
+    }
+
+    #[test]
+    fn table_access_error() {
+        let mut mem = TestMemory::new(0x4000);
+        let paging = sv39(&mut mem);
+        set_entry(&mut mem, 0x2000, 1, pte(0x10_0000, PTE_V));
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x20_0000), R),
+            Err(GuestWalkError::TableAccess {
+                entry_gpa: gpa(0x10_0000),
+                err: AxError::BadAddress,
+            })
+        );
+    }
+}
@@ -0,0 +1,598 @@
+use page_table_entry::MappingFlags;
+
+use super::{GuestTranslation, GuestWalkError};
+use crate::{GuestInt, GuestMemory, GuestPhysAddr, GuestVirtAddr};
+
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PSE: u64 = 1 << 4;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_LA57: u64 = 1 << 12;
+const CR4_SMEP: u64 = 1 << 20;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_NXE: u64 = 1 << 11;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_HUGE: u64 = 1 << 7;
+/// The PAT bit of entries mapping huge pages.
+const PTE_HUGE_PAT: u64 = 1 << 12;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+/// Bits of a PAE page-directory-pointer-table entry that must be zero.
+const PDPTE_RESERVED: u64 = 0b1_1110_0110 | PTE_NO_EXECUTE;
+
+bitflags::bitflags! {
+    /// The error code of a page fault exception (#PF). (SDM Vol. 3A, Section 4.7)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct X86PageFaultErrorCode: u32 {
+        /// The fault was caused by a page-level protection violation, rather
+        /// than a non-present page.
+        const PRESENT =     1 << 0;
+        /// The access causing the fault was a write.
+        const WRITE =       1 << 1;
+        /// The access causing the fault was a user-mode access.
+        const USER =        1 << 2;
+        /// The fault was caused by a reserved bit set in a paging-structure
+        /// entry.
+        const RESERVED =    1 << 3;
+        /// The access causing the fault was an instruction fetch.
+        const INSTRUCTION = 1 << 4;
+    }
+}
+
+/// A page fault of an x86 guest page table walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X86PageFault {
+    /// The level of the paging-structure entry that caused the fault, from
+    /// `1` for page tables to `5` for PML5 tables.
+    pub level: usize,
+    /// The error code to push with the #PF exception.
+    pub error_code: X86PageFaultErrorCode,
+}
+
+/// The paging state of an x86 guest, given by its control registers.
+///
+/// All the paging modes are supported: 32-bit paging (with PSE), PAE paging,
+/// and 4-level and 5-level paging. The accessed and dirty bits of the guest
+/// entries are not updated by the walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X86GuestPaging {
+    /// The guest CR0.
+    pub cr0: u64,
+    /// The guest CR3.
+    pub cr3: u64,
+    /// The guest CR4.
+    pub cr4: u64,
+    /// The guest IA32_EFER.
+    pub efer: u64,
+}
+
+/// The accumulated permissions of the entries of a walk.
+struct Permissions {
+    writable: bool,
+    user: bool,
+    executable: bool,
+}
+
+impl X86GuestPaging {
+    /// Translates `gva` for an access of `access` through the guest page
+    /// table, whose entries are read from `mem`.
+    ///
+    /// `access` may contain [`MappingFlags::WRITE`] and
+    /// [`MappingFlags::EXECUTE`], and [`MappingFlags::USER`] for user-mode
+    /// accesses. Returns a [`X86PageFault`] if the access is not permitted.
+    pub fn translate<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        gva: GuestVirtAddr,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<X86PageFault>> {
+        let va = gva.as_usize() as u64;
+        if self.cr0 & CR0_PG == 0 {
+            // Linear addresses are physical addresses without paging.
+            return Ok(GuestTranslation {
+                gpa: GuestPhysAddr::from_usize(va as u32 as usize),
+                flags: MappingFlags::READ
+                    | MappingFlags::WRITE
+                    | MappingFlags::EXECUTE
+                    | MappingFlags::USER,
+                page_size: 1 << 12,
+            });
+        }
+
+        if self.cr4 & CR4_PAE == 0 {
+            self.walk_32bit(mem, va as u32, access)
+        } else if self.efer & EFER_LMA == 0 {
+            self.walk_pae(mem, va as u32, access)
+        } else {
+            let levels = if self.cr4 & CR4_LA57 != 0 { 5 } else { 4 };
+            // The address must be sign-extended from its highest bit.
+            let shift = 64 - (12 + 9 * levels);
+            if ((va << shift) as i64 >> shift) as u64 != va {
+                return Err(GuestWalkError::OutOfRange);
+            }
+            let table = self.cr3 & PTE_ADDR_MASK;
+            self.walk_64bit(mem, va, access, table, levels, Self::all_permissions())
+        }
+    }
+
+    /// Walks the 2-level tables of 32-bit paging, with 4-byte entries.
+    fn walk_32bit<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        va: u32,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<X86PageFault>> {
+        let mut perms = Self::all_permissions();
+        let mut table = self.cr3 as u32 & 0xffff_f000;
+        for level in [2, 1] {
+            let shift = 12 + 10 * (level - 1);
+            let entry_gpa = table as usize + ((va as usize >> shift) & 0x3ff) * 4;
+            let entry = Self::read_entry::<M, u32>(mem, entry_gpa)? as u64;
+            if entry & PTE_PRESENT == 0 {
+                return Err(self.fault(level, access, X86PageFaultErrorCode::empty()));
+            }
+            perms.writable &= entry & PTE_WRITABLE != 0;
+            perms.user &= entry & PTE_USER != 0;
+
+            if level == 1 || (entry & PTE_HUGE != 0 && self.cr4 & CR4_PSE != 0) {
+                let (base, page_size) = if level == 2 {
+                    // 4M pages, with PSE-36 bits 39:32 of the address in bits
+                    // 20:13 of the entry.
+                    let base = (entry & 0xffc0_0000) | ((entry >> 13) & 0xff) << 32;
+                    if entry & (1 << 21) != 0 {
+                        return Err(self.fault(level, access, X86PageFaultErrorCode::RESERVED));
+                    }
+                    (base, 1 << 22)
+                } else {
+                    (entry & 0xffff_f000, 1 << 12)
+                };
+                return self.leaf(level, va as u64, base, page_size, perms, access);
+            }
+            table = entry as u32 & 0xffff_f000;
+        }
+        unreachable!()
+    }
+
+    /// Walks the tables of PAE paging, starting with the four
+    /// page-directory-pointer-table entries referenced by CR3.
+    ///
+    /// The entries are read at each walk instead of being loaded with CR3.
+    fn walk_pae<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        va: u32,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<X86PageFault>> {
+        let entry_gpa = (self.cr3 as usize & 0xffff_ffe0) + (va as usize >> 30) * 8;
+        let entry = Self::read_entry::<M, u64>(mem, entry_gpa)?;
+        if entry & PTE_PRESENT == 0 {
+            return Err(self.fault(3, access, X86PageFaultErrorCode::empty()));
+        }
+        if entry & PDPTE_RESERVED != 0 {
+            return Err(self.fault(3, access, X86PageFaultErrorCode::RESERVED));
+        }
+        // The PDPTEs do not control the access rights.
+        let table = entry & PTE_ADDR_MASK;
+        self.walk_64bit(mem, va as u64, access, table, 2, Self::all_permissions())
+    }
+
+    /// Walks the levels `levels..=1` of the tables with 8-byte entries,
+    /// starting with the table at `table`.
+    fn walk_64bit<M: GuestMemory>(
+        &self,
+        mem: &mut M,
+        va: u64,
+        access: MappingFlags,
+        mut table: u64,
+        levels: usize,
+        mut perms: Permissions,
+    ) -> Result<GuestTranslation, GuestWalkError<X86PageFault>> {
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let entry_gpa = table as usize + ((va >> shift) & 0x1ff) as usize * 8;
+            let entry = Self::read_entry::<M, u64>(mem, entry_gpa)?;
+            if entry & PTE_PRESENT == 0 {
+                return Err(self.fault(level, access, X86PageFaultErrorCode::empty()));
+            }
+            let huge = level > 1 && entry & PTE_HUGE != 0;
+            if (entry & PTE_NO_EXECUTE != 0 && self.efer & EFER_NXE == 0) || (huge && level > 3) {
+                return Err(self.fault(level, access, X86PageFaultErrorCode::RESERVED));
+            }
+            perms.writable &= entry & PTE_WRITABLE != 0;
+            perms.user &= entry & PTE_USER != 0;
+            perms.executable &= entry & PTE_NO_EXECUTE == 0;
+
+            if level == 1 || huge {
+                let page_size = 1u64 << shift;
+                let mut base = entry & PTE_ADDR_MASK;
+                if huge {
+                    base &= !PTE_HUGE_PAT;
+                    if base & (page_size - 1) != 0 {
+                        return Err(self.fault(level, access, X86PageFaultErrorCode::RESERVED));
+                    }
+                }
+                return self.leaf(level, va, base, page_size as usize, perms, access);
+            }
+            table = entry & PTE_ADDR_MASK;
+        }
+        unreachable!()
+    }
+
+    /// Checks the access against the permissions of a leaf entry mapping the
+    /// page at `base`.
+    fn leaf(
+        &self,
+        level: usize,
+        va: u64,
+        base: u64,
+        page_size: usize,
+        perms: Permissions,
+        access: MappingFlags,
+    ) -> Result<GuestTranslation, GuestWalkError<X86PageFault>> {
+        let user_access = access.contains(MappingFlags::USER);
+        let denied = if user_access && !perms.user {
+            true
+        } else if access.contains(MappingFlags::WRITE) && !perms.writable {
+            // Supervisor writes ignore read-only pages unless CR0.WP is set.
+            user_access || self.cr0 & CR0_WP != 0
+        } else if access.contains(MappingFlags::EXECUTE) {
+            !perms.executable || (!user_access && perms.user && self.cr4 & CR4_SMEP != 0)
+        } else {
+            false
+        };
+        if denied {
+            return Err(self.fault(level, access, X86PageFaultErrorCode::PRESENT));
+        }
+
+        let mut flags = MappingFlags::READ;
+        if perms.writable {
+            flags |= MappingFlags::WRITE;
+        }
+        if perms.executable {
+            flags |= MappingFlags::EXECUTE;
+        }
+        if perms.user {
+            flags |= MappingFlags::USER;
+        }
+        Ok(GuestTranslation {
+            gpa: GuestPhysAddr::from_usize((base + (va & (page_size as u64 - 1))) as usize),
+            flags,
+            page_size,
+        })
+    }
+
+    /// Builds the page fault at `level` for an access of `access`.
+    fn fault(
+        &self,
+        level: usize,
+        access: MappingFlags,
+        cause: X86PageFaultErrorCode,
+    ) -> GuestWalkError<X86PageFault> {
+        let mut error_code = cause;
+        if cause.contains(X86PageFaultErrorCode::RESERVED) {
+            // Reserved bits are only checked in present entries.
+            error_code |= X86PageFaultErrorCode::PRESENT;
+        }
+        if access.contains(MappingFlags::WRITE) {
+            error_code |= X86PageFaultErrorCode::WRITE;
+        }
+        if access.contains(MappingFlags::USER) {
+            error_code |= X86PageFaultErrorCode::USER;
+        }
+        // Instruction fetches are only reported if they can be denied.
+        if access.contains(MappingFlags::EXECUTE)
+            && (self.efer & EFER_NXE != 0 || self.cr4 & CR4_SMEP != 0)
+        {
+            error_code |= X86PageFaultErrorCode::INSTRUCTION;
+        }
+        GuestWalkError::Fault(X86PageFault { level, error_code })
+    }
+
+    const fn all_permissions() -> Permissions {
+        Permissions {
+            writable: true,
+            user: true,
+            executable: true,
+        }
+    }
+
+    fn read_entry<M: GuestMemory, T: GuestInt>(
+        mem: &mut M,
+        entry_gpa: usize,
+    ) -> Result<T, GuestWalkError<X86PageFault>> {
+        let entry_gpa = GuestPhysAddr::from_usize(entry_gpa);
+        mem.read_le::<T>(entry_gpa)
+            .map_err(|err| GuestWalkError::TableAccess { entry_gpa, err })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::guest_paging::TestMemory;
+
+    const R: MappingFlags = MappingFlags::READ;
+    const W: MappingFlags = MappingFlags::WRITE;
+    const X: MappingFlags = MappingFlags::EXECUTE;
+    const U: MappingFlags = MappingFlags::USER;
+
+    fn gva(addr: usize) -> GuestVirtAddr {
+        GuestVirtAddr::from_usize(addr)
+    }
+
+    fn gpa(addr: usize) -> GuestPhysAddr {
+        GuestPhysAddr::from_usize(addr)
+    }
+
+    /// Writes the 8-byte entry `index` of the table at `table`.
+    fn set_entry(mem: &mut TestMemory, table: usize, index: usize, entry: u64) {
+        mem.write_le(gpa(table + index * 8), entry).unwrap();
+    }
+
+    fn fault_of<T: core::fmt::Debug>(
+        res: Result<T, GuestWalkError<X86PageFault>>,
+    ) -> (usize, X86PageFaultErrorCode) {
+        match res {
+            Err(GuestWalkError::Fault(fault)) => (fault.level, fault.error_code),
+            res => panic!("unexpected walk result {res:?}"),
+        }
+    }
+
+    const P: u64 = PTE_PRESENT;
+    const RW: u64 = PTE_PRESENT | PTE_WRITABLE;
+    const URW: u64 = PTE_PRESENT | PTE_WRITABLE | PTE_USER;
+
+    /// 4-level paging with tables at 0x1000 (PML4), 0x2000 (PDPT), 0x3000
+    /// (PD) and 0x4000 (PT).
+    fn long_mode(mem: &mut TestMemory) -> X86GuestPaging {
+        set_entry(mem, 0x1000, 0, 0x2000 | URW);
+        set_entry(mem, 0x2000, 0, 0x3000 | URW);
+        set_entry(mem, 0x3000, 0, 0x4000 | URW);
+        X86GuestPaging {
+            cr0: CR0_PG | CR0_WP,
+            cr3: 0x1000,
+            cr4: CR4_PAE,
+            efer: EFER_LMA | EFER_NXE,
+        }
+    }
+
+    #[test]
+    fn paging_disabled() {
+        let paging = X86GuestPaging {
+            cr0: 0,
+            cr3: 0,
+            cr4: 0,
+            efer: 0,
+        };
+        let res = paging.translate(&mut TestMemory::new(0), gva(0x1234_5678), W | X | U);
+        assert_eq!(res.unwrap().gpa, gpa(0x1234_5678));
+    }
+
+    #[test]
+    fn four_level_pages() {
+        let mut mem = TestMemory::new(0x10_0000);
+        let paging = long_mode(&mut mem);
+        set_entry(&mut mem, 0x4000, 5, 0x8_0000 | URW);
+        set_entry(&mut mem, 0x4000, 6, 0x9_0000 | P | PTE_NO_EXECUTE);
+
+        let res = paging.translate(&mut mem, gva(0x5123), W | U).unwrap();
+        assert_eq!(res.gpa, gpa(0x8_0123));
+        assert_eq!(res.flags, R | W | X | U);
+        assert_eq!(res.page_size, 0x1000);
+        let res = paging.translate(&mut mem, gva(0x6000), R).unwrap();
+        assert_eq!(res.flags, R);
+
+        // Supervisor writes to read-only pages fault with CR0.WP.
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::WRITE;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x6000), W)),
+            (1, code)
+        );
+        let no_wp = X86GuestPaging {
+            cr0: CR0_PG,
+            ..paging
+        };
+        assert!(no_wp.translate(&mut mem, gva(0x6000), W).is_ok());
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::INSTRUCTION;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x6000), X)),
+            (1, code)
+        );
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::USER;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x6000), U)),
+            (1, code)
+        );
+        // Not present.
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x7000), R)),
+            (1, X86PageFaultErrorCode::empty())
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x8000_0000), W)),
+            (3, X86PageFaultErrorCode::WRITE)
+        );
+    }
+
+    #[test]
+    fn huge_pages() {
+        let mut mem = TestMemory::new(0x10_0000);
+        let paging = long_mode(&mut mem);
+        // A 2M page, whose PAT bit is not part of the address.
+        set_entry(
+            &mut mem,
+            0x3000,
+            1,
+            0x4000_0000 | PTE_HUGE_PAT | RW | PTE_HUGE,
+        );
+        // A 1G page.
+        set_entry(&mut mem, 0x2000, 1, 0x8000_0000 | RW | PTE_HUGE);
+        // A misaligned 2M page.
+        set_entry(&mut mem, 0x3000, 2, 0x4010_0000 | RW | PTE_HUGE);
+        // Huge pages are not allowed in the PML4.
+        set_entry(&mut mem, 0x1000, 1, RW | PTE_HUGE);
+
+        let res = paging.translate(&mut mem, gva(0x3f_ffff), W).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x401f_ffff), 0x20_0000));
+        let res = paging.translate(&mut mem, gva(0x4123_4567), R).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x8123_4567), 0x4000_0000));
+        assert_eq!(res.flags, R | W | X);
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::RESERVED;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x40_0000), R)),
+            (2, code)
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x80_0000_0000), R)),
+            (4, code)
+        );
+    }
+
+    #[test]
+    fn reserved_and_non_canonical() {
+        let mut mem = TestMemory::new(0x10_0000);
+        let paging = long_mode(&mut mem);
+        set_entry(&mut mem, 0x4000, 0, 0x8_0000 | RW | PTE_NO_EXECUTE);
+        // The XD bit is reserved without EFER.NXE.
+        let no_nx = X86GuestPaging {
+            efer: EFER_LMA,
+            ..paging
+        };
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::RESERVED;
+        assert_eq!(fault_of(no_nx.translate(&mut mem, gva(0), R)), (1, code));
+
+        assert_eq!(
+            paging.translate(&mut mem, gva(0x0000_8000_0000_0000), R),
+            Err(GuestWalkError::OutOfRange)
+        );
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0xffff_8000_0000_0000), R)),
+            (4, X86PageFaultErrorCode::empty())
+        );
+        // 5-level paging translates 57-bit addresses.
+        let la57 = X86GuestPaging {
+            cr4: CR4_PAE | CR4_LA57,
+            ..paging
+        };
+        assert_eq!(
+            fault_of(la57.translate(&mut mem, gva(0x0000_8000_0000_0000), R)),
+            (4, X86PageFaultErrorCode::empty())
+        );
+        assert_eq!(
+            la57.translate(&mut mem, gva(0x0100_0000_0000_0000), R),
+            Err(GuestWalkError::OutOfRange)
+        );
+
+        // The tables must be in guest memory.
+        let paging = X86GuestPaging {
+            cr3: 0x20_0000,
+            ..paging
+        };
+        assert_eq!(
+            paging.translate(&mut mem, gva(0), R),
+            Err(GuestWalkError::TableAccess {
+                entry_gpa: gpa(0x20_0000),
+                err: AxError::BadAddress,
+            })
+        );
+    }
+
+    #[test]
+    fn smep_and_user_pages() {
+        let mut mem = TestMemory::new(0x10_0000);
+        let paging = X86GuestPaging {
+            cr4: CR4_PAE | CR4_SMEP,
+            ..long_mode(&mut mem)
+        };
+        set_entry(&mut mem, 0x4000, 0, 0x8_0000 | URW);
+        assert!(paging.translate(&mut mem, gva(0), X | U).is_ok());
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::INSTRUCTION;
+        assert_eq!(fault_of(paging.translate(&mut mem, gva(0), X)), (1, code));
+
+        // The user bit of every level is needed.
+        set_entry(&mut mem, 0x3000, 0, 0x4000 | RW);
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::USER;
+        assert_eq!(fault_of(paging.translate(&mut mem, gva(0), U)), (1, code));
+        assert_eq!(
+            paging.translate(&mut mem, gva(0), R).unwrap().flags,
+            R | W | X
+        );
+    }
+
+    #[test]
+    fn pae_paging() {
+        let mut mem = TestMemory::new(0x10_0000);
+        // The PDPT is 32-byte aligned.
+        set_entry(&mut mem, 0x1020, 1, 0x3000 | P);
+        set_entry(&mut mem, 0x1020, 2, 0x3000 | RW);
+        set_entry(&mut mem, 0x3000, 0, 0x4000 | URW);
+        set_entry(&mut mem, 0x3000, 1, 0x60_0000 | URW | PTE_HUGE);
+        set_entry(&mut mem, 0x4000, 1, 0x8_0000 | URW);
+        let paging = X86GuestPaging {
+            cr0: CR0_PG,
+            cr3: 0x1020,
+            cr4: CR4_PAE,
+            efer: EFER_NXE,
+        };
+        let res = paging.translate(&mut mem, gva(0x4000_1234), W | U).unwrap();
+        assert_eq!(res.gpa, gpa(0x8_0234));
+        let res = paging.translate(&mut mem, gva(0x4020_0010), W).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x60_0010), 0x20_0000));
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0), R)),
+            (3, X86PageFaultErrorCode::empty())
+        );
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::RESERVED;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x8000_0000), R)),
+            (3, code)
+        );
+    }
+
+    #[test]
+    fn legacy_paging() {
+        let mut mem = TestMemory::new(0x10_0000);
+        let set = |mem: &mut TestMemory, table: usize, index: usize, entry: u32| {
+            mem.write_le(gpa(table + index * 4), entry).unwrap();
+        };
+        set(&mut mem, 0x1000, 0, 0x2000 | URW as u32);
+        set(&mut mem, 0x2000, 3, 0x8_0000 | URW as u32);
+        // A 4M page with PSE-36 address bits, and one with a reserved bit.
+        set(
+            &mut mem,
+            0x1000,
+            1,
+            0x80_0000 | (0x5 << 13) | (RW | PTE_HUGE) as u32,
+        );
+        set(
+            &mut mem,
+            0x1000,
+            2,
+            0xc0_0000 | (1 << 21) | (RW | PTE_HUGE) as u32,
+        );
+        let paging = X86GuestPaging {
+            cr0: CR0_PG,
+            cr3: 0x1000,
+            cr4: CR4_PSE,
+            efer: 0,
+        };
+        let res = paging.translate(&mut mem, gva(0x3456), W | U).unwrap();
+        assert_eq!(res.gpa, gpa(0x8_0456));
+        let res = paging.translate(&mut mem, gva(0x40_1234), W).unwrap();
+        assert_eq!((res.gpa, res.page_size), (gpa(0x5_0080_1234), 0x40_0000));
+        let code = X86PageFaultErrorCode::PRESENT | X86PageFaultErrorCode::RESERVED;
+        assert_eq!(
+            fault_of(paging.translate(&mut mem, gva(0x80_0000), R)),
+            (2, code)
+        );
+        // Without PSE, the PS bit is ignored and the entry points to a table.
+        let no_pse = X86GuestPaging { cr4: 0, ..paging };
+        let res = no_pse.translate(&mut mem, gva(0x40_0000), R);
+        assert!(matches!(res, Err(GuestWalkError::TableAccess { .. })));
+    }
+}
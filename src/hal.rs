@@ -1,4 +1,4 @@
-use crate::{HostPhysAddr, HostVirtAddr};
+use crate::{HostPhysAddr, HostVirtAddr, frame::PAGE_SIZE, npt::NestedTlbFlush};
 
 /// Hardware abstraction layer for memory management.
 pub trait AxMmHal {
@@ -16,6 +16,54 @@ pub trait AxMmHal {
     /// * `paddr` - The physical address of the frame to deallocate.
     fn dealloc_frame(paddr: HostPhysAddr);
 
+    /// Allocates physically contiguous frames and returns the host physical
+    /// address of the first one.
+    ///
+    /// The default implementation does not support contiguous allocation and
+    /// always returns `None`.
+    ///
+    /// # Contract
+    ///
+    /// The huge pages backed by these frames are split when part of them is
+    /// unmapped or protected, and the frames are then released in pieces:
+    /// each 4K frame by [`AxMmHal::dealloc_frame`] (and the `dealloc_frame`
+    /// of the [`PagingHandler`](page_table_multiarch::PagingHandler)
+    /// implementation of the same type), and each aligned 2M block of a 1G
+    /// allocation by [`AxMmHal::dealloc_contiguous_frames`]. The allocator
+    /// must accept these partial releases of a block, in any order. Nothing
+    /// checks it: an allocator that only frees whole blocks must not
+    /// implement this method, so that 4K pages are used instead.
+    ///
+    /// # Parameters
+    ///
+    /// * `num_frames` - The number of 4K frames to allocate.
+    /// * `frame_align` - The alignment of the first frame in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<HostPhysAddr>` - Some containing the physical address of the first frame, or None if allocation fails.
+    fn alloc_contiguous_frames(num_frames: usize, frame_align: usize) -> Option<HostPhysAddr> {
+        let _ = (num_frames, frame_align);
+        None
+    }
+
+    /// Deallocates physically contiguous frames allocated by
+    /// [`AxMmHal::alloc_contiguous_frames`].
+    ///
+    /// The frames may be only a part of an allocated block, see the contract
+    /// of [`AxMmHal::alloc_contiguous_frames`]. The default implementation
+    /// deallocates the frames one by one.
+    ///
+    /// # Parameters
+    ///
+    /// * `paddr` - The physical address of the first frame to deallocate.
+    /// * `num_frames` - The number of 4K frames to deallocate.
+    fn dealloc_contiguous_frames(paddr: HostPhysAddr, num_frames: usize) {
+        for i in 0..num_frames {
+            Self::dealloc_frame(paddr + i * PAGE_SIZE);
+        }
+    }
+
     /// Converts a host physical address to a host virtual address.
     ///
     /// # Parameters
@@ -37,4 +85,38 @@ pub trait AxMmHal {
     ///
     /// * `HostPhysAddr` - The corresponding physical address.
     fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr;
+
+    /// Invalidates the TLB entries caching nested translations.
+    ///
+    /// It is called by the address spaces created with
+    /// [`AddrSpace::new_with_hal`](crate::AddrSpace::new_with_hal) once per
+    /// operation changing present mappings. The default implementation
+    /// executes the invalidation instruction on the current CPU, i.e., INVEPT
+    /// on x86_64, or records the invalidation in the host-side software mode.
+    ///
+    /// Invalidations of all the nested translations are also issued when the
+    /// VMIDs are recycled, they must reach all the CPUs.
+    ///
+    /// # Parameters
+    ///
+    /// * `flush` - The translations to invalidate.
+    fn flush_nested_tlb(flush: NestedTlbFlush) {
+        crate::npt::flush_nested_tlb(flush)
+    }
+
+    /// Returns the width in bits of the VMIDs tagging the nested translations
+    /// in the TLB, or `0` if they are not tagged.
+    ///
+    /// It is called each time an address space is created with
+    /// [`AddrSpace::new_with_hal`](crate::AddrSpace::new_with_hal) or
+    /// [`AddrSpace::new_with_config`](crate::AddrSpace::new_with_config), and
+    /// must always return the same width, since the VMIDs are allocated from
+    /// a single pool. The
+    /// default implementation returns 8 or 16 on AArch64 as reported by
+    /// `ID_AA64MMFR1_EL1`, and `0` on the other architectures. RISC-V HALs
+    /// should return the VMIDLEN of the harts, probed by writing all ones to
+    /// the VMID field of `hgatp` and reading it back.
+    fn vmid_bits() -> usize {
+        crate::npt::vmid_bits()
+    }
 }
@@ -0,0 +1,158 @@
+//! Host-side software mode, enabled in the unit tests of the crate and by
+//! `--cfg axaddrspace_host` for the integration tests.
+//!
+//! It is not a feature, so that no crate depending on this one can replace
+//! the TLB maintenance of the hypervisor by enabling it.
+//!
+//! The TLB invalidations of the nested page tables are recorded instead of
+//! executed, and the hardware capabilities are replaced by fixed ones, so that
+//! the address spaces can be built and exercised by `cargo test` on a
+//! development machine. [`HostHal`] provides their frames from a heap arena,
+//! whose host physical addresses are their virtual addresses.
+
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use std::alloc::{Layout, alloc_zeroed, handle_alloc_error};
+use std::sync::{Mutex, PoisonError};
+
+use memory_addr::is_aligned_4k;
+use page_table_multiarch::PagingHandler;
+
+use crate::frame::PAGE_SIZE;
+use crate::{AxMmHal, HostPhysAddr, HostVirtAddr, NestedTlbFlush};
+
+/// The number of frames in the arena (64 MiB).
+const ARENA_FRAMES: usize = 0x4000;
+/// The alignment of the arena, so that the frames of 2M huge pages can be
+/// allocated from it.
+const ARENA_ALIGN: usize = 0x20_0000;
+
+std::thread_local! {
+    /// The TLB invalidations issued by the current thread.
+    static TLB_FLUSHES: RefCell<Vec<NestedTlbFlush>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Records the TLB invalidation `flush` issued by the current thread.
+pub(crate) fn record_tlb_flush(flush: NestedTlbFlush) {
+    TLB_FLUSHES.with_borrow_mut(|flushes| flushes.push(flush));
+}
+
+/// Returns the TLB invalidations issued by the current thread since the last
+/// call, in order.
+///
+/// They are recorded per thread, so that the tests running in parallel do not
+/// observe the invalidations of each other.
+pub fn take_tlb_flushes() -> Vec<NestedTlbFlush> {
+    TLB_FLUSHES.take()
+}
+
+/// The frames shared by all the [`HostHal`] users, allocated once and never
+/// released.
+struct Arena {
+    base: usize,
+    used: Vec<bool>,
+    allocated: usize,
+}
+
+static ARENA: Mutex<Option<Arena>> = Mutex::new(None);
+
+impl Arena {
+    fn new() -> Self {
+        let layout = Layout::from_size_align(ARENA_FRAMES * PAGE_SIZE, ARENA_ALIGN).unwrap();
+        // SAFETY: the layout has a non-zero size.
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            handle_alloc_error(layout);
+        }
+        Self {
+            base: base as usize,
+            used: alloc::vec![false; ARENA_FRAMES],
+            allocated: 0,
+        }
+    }
+
+    /// Runs `f` on the arena, which is created on the first use.
+    fn with<R>(f: impl FnOnce(&mut Self) -> R) -> R {
+        let mut arena = ARENA.lock().unwrap_or_else(PoisonError::into_inner);
+        f(arena.get_or_insert_with(Self::new))
+    }
+
+    fn alloc(&mut self, num_frames: usize, frame_align: usize) -> Option<usize> {
+        if num_frames == 0 || frame_align > ARENA_ALIGN {
+            return None;
+        }
+        let step = frame_align.max(PAGE_SIZE) / PAGE_SIZE;
+        let start = (0..ARENA_FRAMES.saturating_sub(num_frames - 1))
+            .step_by(step)
+            .find(|&i| self.used[i..i + num_frames].iter().all(|used| !used))?;
+        self.used[start..start + num_frames].fill(true);
+        self.allocated += num_frames;
+
+        let addr = self.base + start * PAGE_SIZE;
+        // SAFETY: the frames belong to the arena and are not used by anyone.
+        unsafe { core::ptr::write_bytes(addr as *mut u8, 0, num_frames * PAGE_SIZE) };
+        Some(addr)
+    }
+
+    fn dealloc(&mut self, addr: usize) {
+        let index = addr.wrapping_sub(self.base) / PAGE_SIZE;
+        assert!(
+            is_aligned_4k(addr) && index < ARENA_FRAMES && self.used[index],
+            "frame {addr:#x} is not allocated"
+        );
+        self.used[index] = false;
+        self.allocated -= 1;
+    }
+}
+
+/// A HAL for the host-side software mode, which provides frames from a heap
+/// arena of 64 MiB.
+///
+/// The host physical address of a frame is its virtual address. Contiguous
+/// frames can be allocated up to an alignment of 2M, and freeing a frame that
+/// is not allocated panics.
+#[derive(Debug, Clone, Copy)]
+pub struct HostHal;
+
+impl HostHal {
+    /// Returns the number of frames currently allocated from the arena.
+    pub fn allocated_frames() -> usize {
+        Arena::with(|arena| arena.allocated)
+    }
+}
+
+impl AxMmHal for HostHal {
+    fn alloc_frame() -> Option<HostPhysAddr> {
+        Self::alloc_contiguous_frames(1, PAGE_SIZE)
+    }
+
+    fn dealloc_frame(paddr: HostPhysAddr) {
+        Arena::with(|arena| arena.dealloc(paddr.as_usize()));
+    }
+
+    fn alloc_contiguous_frames(num_frames: usize, frame_align: usize) -> Option<HostPhysAddr> {
+        Arena::with(|arena| arena.alloc(num_frames, frame_align)).map(HostPhysAddr::from)
+    }
+
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        HostVirtAddr::from(paddr.as_usize())
+    }
+
+    fn virt_to_phys(vaddr: HostVirtAddr) -> HostPhysAddr {
+        HostPhysAddr::from(vaddr.as_usize())
+    }
+}
+
+impl PagingHandler for HostHal {
+    fn alloc_frame() -> Option<HostPhysAddr> {
+        <Self as AxMmHal>::alloc_frame()
+    }
+
+    fn dealloc_frame(paddr: HostPhysAddr) {
+        <Self as AxMmHal>::dealloc_frame(paddr)
+    }
+
+    fn phys_to_virt(paddr: HostPhysAddr) -> HostVirtAddr {
+        <Self as AxMmHal>::phys_to_virt(paddr)
+    }
+}
@@ -6,30 +6,106 @@
 #[macro_use]
 extern crate log;
 extern crate alloc;
+#[cfg(any(test, axaddrspace_host))]
+extern crate std;
 
 mod addr;
 mod address_space;
 pub mod device;
+mod fault_info;
 mod frame;
+mod guest_memory;
+pub mod guest_paging;
 mod hal;
+#[cfg(any(test, axaddrspace_host))]
+pub mod host;
 mod npt;
 
 pub use addr::*;
 pub use address_space::*;
 
+pub use fault_info::FaultAccessReg;
 pub use frame::PhysFrame;
+pub use guest_memory::{ByteValued, GuestInt, GuestMemory};
 pub use hal::AxMmHal;
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+pub use npt::{Cacheability, Stage2Attr, Stage2Config, Stage2Exec, Stage2Format, Stage2MemAttr};
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+pub use npt::{EptConfig, EptFormat, EptMemAttr, EptMemType, EptWalkLength};
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+pub use npt::{GStageFormat, GStageMode};
+pub use npt::{NativeFormat, NestedConfig, NestedFormat, NestedMemAttr, NestedTlbFlush};
 
 use axerrno::AxError;
 use memory_set::MappingError;
 
 /// Information about nested page faults.
+///
+/// It can be decoded from the hardware state of the fault with the
+/// architecture-specific constructors.
 #[derive(Debug)]
 pub struct NestedPageFaultInfo {
     /// Access type that caused the nested page fault.
     pub access_flags: MappingFlags,
     /// Guest physical address that caused the nested page fault.
     pub fault_guest_paddr: GuestPhysAddr,
+    /// Guest virtual (linear) address of the access, if it is valid.
+    pub fault_guest_vaddr: Option<GuestVirtAddr>,
+    /// Width of the access, if reported by the hardware.
+    pub access_width: Option<device::AccessWidth>,
+    /// Register transferring the data of the access, if reported by the
+    /// hardware.
+    pub access_reg: Option<FaultAccessReg>,
+    /// Whether the fault occurred on an access to the guest page table during
+    /// a stage-1 walk, rather than on the access itself.
+    pub stage1_walk: bool,
+}
+
+/// The area of the address space involved in a nested page fault.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultAreaInfo {
+    /// The address range of the area.
+    pub range: GuestPhysAddrRange,
+    /// The mapping flags of the area.
+    pub flags: MappingFlags,
+}
+
+/// The outcome of handling a nested page fault with
+/// [`AddrSpace::handle_page_fault`].
+#[derive(Debug, Clone)]
+pub enum PageFaultOutcome {
+    /// The fault is resolved, the guest can retry the access.
+    Handled,
+    /// The address is not mapped by any area of the address space.
+    Unmapped,
+    /// The access is not permitted by the flags of the area.
+    PermissionDenied {
+        /// The area containing the address.
+        area: FaultAreaInfo,
+        /// The access type that caused the fault.
+        access_flags: MappingFlags,
+    },
+    /// The address is in an MMIO region, the access should be emulated by
+    /// its device.
+    Mmio(MmioAccess),
+    /// No physical frame could be allocated to back the page.
+    NoMemory {
+        /// The area containing the address.
+        area: FaultAreaInfo,
+    },
+    /// The area is not expected to fault at the address, such as a linear or
+    /// populated mapping.
+    Unexpected {
+        /// The area containing the address.
+        area: FaultAreaInfo,
+    },
+}
+
+impl PageFaultOutcome {
+    /// Returns whether the fault is resolved.
+    pub const fn is_handled(&self) -> bool {
+        matches!(self, Self::Handled)
+    }
 }
 
 fn mapping_err_to_ax_err(err: MappingError) -> AxError {
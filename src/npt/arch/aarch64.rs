@@ -1,9 +1,10 @@
+#[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
 use core::arch::asm;
 use core::fmt;
 use page_table_entry::{GenericPTE, MappingFlags};
-use page_table_multiarch::{PageTable64, PagingMetaData};
-// use memory_addr::HostPhysAddr;
-use crate::{GuestPhysAddr, HostPhysAddr};
+
+use crate::HostPhysAddr;
+use crate::npt::{DirtyState, MemAttrState, NestedFormat, NestedTlbFlush, TableGeometry};
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -16,12 +17,12 @@ bitflags::bitflags! {
         /// The descriptor gives the address of the next level of translation table or 4KB page.
         /// (not a 2M, 1G block)
         const NON_BLOCK =   1 << 1;
-        /// Memory attributes index field.
+        /// Stage-2 memory attributes field (MemAttr\[3:0\]).
         const ATTR =   0b1111 << 2;
-       /// Access permission: read-only.
-        const S2AP_RO =      1 << 6;
-        /// Access permission: write-only.
-        const S2AP_WO =       1 << 7;
+        /// Stage-2 access permission: read access (S2AP\[0\]).
+        const S2AP_R =      1 << 6;
+        /// Stage-2 access permission: write access (S2AP\[1\]).
+        const S2AP_W =      1 << 7;
         /// Shareability: Inner Shareable (otherwise Outer Shareable).
         const INNER =       1 << 8;
         /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
@@ -30,12 +31,16 @@ bitflags::bitflags! {
         const AF =          1 << 10;
         /// The not global bit.
         const NG =          1 << 11;
+        /// Dirty Bit Modifier. The hardware sets `S2AP_W` on the first write
+        /// instead of generating a permission fault (FEAT_HAFDBS).
+        const DBM =         1 << 51;
         /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
         const CONTIGUOUS =  1 <<  52;
-        /// The Privileged execute-never field.
-        // const PXN =         1 <<  53;
-        /// The Execute-never or Unprivileged execute-never field.
-        const XN =         1 <<  54;
+        /// The low bit of the stage-2 execute-never field (XN\[0\]), which
+        /// toggles the execution at EL1 (FEAT_XNX). RES0 without FEAT_XNX.
+        const XNX =         1 <<  53;
+        /// The stage-2 execute-never field (XN\[1\]).
+        const XN =          1 <<  54;
         /// Non-secure bit. For memory accesses from Secure state, specifies whether the output
         /// address is in Secure or Non-secure memory.
         const NS =          1 << 55;
@@ -55,92 +60,368 @@ bitflags::bitflags! {
     }
 }
 
-#[repr(u64)]
+/// The cacheability of Normal memory.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Cacheability {
+    /// Non-cacheable.
+    NonCacheable,
+    /// Write-Through cacheable.
+    WriteThrough,
+    /// Write-Back cacheable.
+    WriteBack,
+}
+
+impl Cacheability {
+    const fn bits(self) -> u64 {
+        match self {
+            Self::NonCacheable => 0b01,
+            Self::WriteThrough => 0b10,
+            Self::WriteBack => 0b11,
+        }
+    }
+
+    const fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0b01 => Some(Self::NonCacheable),
+            0b10 => Some(Self::WriteThrough),
+            0b11 => Some(Self::WriteBack),
+            _ => None,
+        }
+    }
+}
+
+/// The memory type and cacheability attributes of a stage-2 mapping, encoded
+/// in the MemAttr field of the descriptors. (ARM DDI 0487, Section D8.6.5)
+///
+/// They are combined with the stage-1 attributes, unless FEAT_S2FWB is
+/// enabled by `HCR_EL2.FWB`, in which case the `Forced*` and `Stage1`
+/// attributes are used instead of the `Normal` ones.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stage2MemAttr {
+    /// Device-nGnRnE memory.
+    DeviceNGnRnE,
+    /// Device-nGnRE memory.
+    DeviceNGnRE,
+    /// Device-nGRE memory.
+    DeviceNGRE,
+    /// Device-GRE memory.
+    DeviceGRE,
+    /// Normal memory with the given outer and inner cacheability.
+    Normal {
+        /// The outer cacheability.
+        outer: Cacheability,
+        /// The inner cacheability.
+        inner: Cacheability,
+    },
+    /// Normal Non-cacheable memory, whatever the stage-1 attributes
+    /// (FEAT_S2FWB).
+    ForcedNonCacheable,
+    /// Normal Write-Back memory, whatever the stage-1 attributes
+    /// (FEAT_S2FWB).
+    ForcedWriteBack,
+    /// The stage-1 attributes are used (FEAT_S2FWB).
+    Stage1,
+}
+
+impl Stage2MemAttr {
+    /// Normal Write-Back memory, the attribute of the regular guest memory.
+    pub const WRITE_BACK: Self = Self::Normal {
+        outer: Cacheability::WriteBack,
+        inner: Cacheability::WriteBack,
+    };
+    /// Normal Non-cacheable memory.
+    pub const NON_CACHEABLE: Self = Self::Normal {
+        outer: Cacheability::NonCacheable,
+        inner: Cacheability::NonCacheable,
+    };
+
+    /// Returns whether the attribute is a Device memory type.
+    pub const fn is_device(self) -> bool {
+        matches!(
+            self,
+            Self::DeviceNGnRnE | Self::DeviceNGnRE | Self::DeviceNGRE | Self::DeviceGRE
+        )
+    }
+
+    /// Returns whether the attribute can be encoded with FEAT_S2FWB enabled
+    /// or not, as given by `fwb`.
+    pub const fn is_supported(self, fwb: bool) -> bool {
+        self.encode(fwb).is_some()
+    }
+
+    /// Returns the MemAttr field encoding the attribute, if it can be encoded
+    /// with FEAT_S2FWB enabled or not, as given by `fwb`.
+    const fn encode(self, fwb: bool) -> Option<u64> {
+        Some(match self {
+            Self::DeviceNGnRnE => 0b0000,
+            Self::DeviceNGnRE => 0b0001,
+            Self::DeviceNGRE => 0b0010,
+            Self::DeviceGRE => 0b0011,
+            // The same encoding as the forced Non-cacheable one.
+            Self::Normal {
+                outer: Cacheability::NonCacheable,
+                inner: Cacheability::NonCacheable,
+            } => 0b0101,
+            Self::Normal { outer, inner } if !fwb => (outer.bits() << 2) | inner.bits(),
+            Self::ForcedNonCacheable if fwb => 0b0101,
+            Self::Stage1 if fwb => 0b0110,
+            Self::ForcedWriteBack if fwb => 0b0111,
+            _ => return None,
+        })
+    }
+
+    /// Decodes the MemAttr field `bits`, which are interpreted as with
+    /// FEAT_S2FWB enabled or not, as given by `fwb`.
+    ///
+    /// Returns `None` for the reserved encodings.
+    const fn decode(bits: u64, fwb: bool) -> Option<Self> {
+        match (bits >> 2, bits & 0b11) {
+            (0, 0b00) => Some(Self::DeviceNGnRnE),
+            (0, 0b01) => Some(Self::DeviceNGnRE),
+            (0, 0b10) => Some(Self::DeviceNGRE),
+            (0, _) => Some(Self::DeviceGRE),
+            (0b01, 0b01) if fwb => Some(Self::ForcedNonCacheable),
+            (0b01, 0b10) if fwb => Some(Self::Stage1),
+            (0b01, 0b11) if fwb => Some(Self::ForcedWriteBack),
+            (_, _) if fwb => None,
+            (outer, inner) => match (
+                Cacheability::from_bits(outer),
+                Cacheability::from_bits(inner),
+            ) {
+                (Some(outer), Some(inner)) => Some(Self::Normal { outer, inner }),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the attribute selected by the mapping `flags`.
+    fn from_flags(flags: MappingFlags, fwb: bool) -> Self {
+        if flags.contains(MappingFlags::UNCACHED) {
+            Self::NON_CACHEABLE
+        } else if flags.contains(MappingFlags::DEVICE) {
+            Self::DeviceNGnRnE
+        } else if fwb {
+            Self::ForcedWriteBack
+        } else {
+            Self::WRITE_BACK
+        }
+    }
+}
+
+/// Returns whether FEAT_S2FWB is enabled by `HCR_EL2.FWB`. It is never
+/// enabled without the hardware, in the host-side software mode or on other
+/// architectures.
+pub(crate) fn s2fwb_enabled() -> bool {
+    #[cfg(all(
+        target_arch = "aarch64",
+        feature = "arm-el2",
+        not(any(test, axaddrspace_host))
+    ))]
+    {
+        let hcr: u64;
+        // SAFETY: reading `HCR_EL2` has no side effect.
+        unsafe { asm!("mrs {}, hcr_el2", out(reg) hcr) };
+        hcr & (1 << 46) != 0
+    }
+    #[cfg(not(all(
+        target_arch = "aarch64",
+        feature = "arm-el2",
+        not(any(test, axaddrspace_host))
+    )))]
+    false
+}
+
+/// Returns whether the CPU supports FEAT_XNX, which is always reported
+/// without the hardware, in the host-side software mode or on other
+/// architectures.
+fn xnx_supported() -> bool {
+    #[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
+    {
+        let mmfr1: u64;
+        // SAFETY: reading an ID register has no side effect.
+        unsafe { asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1) };
+        (mmfr1 >> 28) & 0xf != 0
+    }
+    #[cfg(not(all(target_arch = "aarch64", not(any(test, axaddrspace_host)))))]
+    true
+}
+
+/// The exception levels from which a stage-2 mapping is executable, as
+/// encoded by the XN field. (ARM DDI 0487, Section D8.4.3)
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum MemType {
-    Device = 0,
-    Normal = 1,
-    NormalNonCache = 2,
+pub enum Stage2Exec {
+    /// Executable at EL1 and EL0.
+    All,
+    /// Executable at EL0 only (FEAT_XNX).
+    El0,
+    /// Executable at EL1 only (FEAT_XNX).
+    El1,
+    /// Not executable.
+    None,
+}
+
+impl Stage2Exec {
+    /// Returns the XN field encoding the exception levels.
+    const fn xn_bits(self) -> DescriptorAttr {
+        match self {
+            Self::All => DescriptorAttr::empty(),
+            Self::El0 => DescriptorAttr::XNX,
+            Self::El1 => DescriptorAttr::XN.union(DescriptorAttr::XNX),
+            Self::None => DescriptorAttr::XN,
+        }
+    }
+}
+
+/// The attributes of a stage-2 mapping selected independently from its
+/// [`MappingFlags`]: its memory attribute, and the exception levels from
+/// which it is executable when the flags have `EXECUTE`.
+///
+/// The exception levels cannot be read back from a mapping that is not
+/// executable, whose attributes report [`Stage2Exec::All`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Stage2Attr {
+    /// The memory attribute.
+    pub mem_attr: Stage2MemAttr,
+    /// The exception levels from which the mapping is executable.
+    pub exec: Stage2Exec,
+}
+
+impl Stage2Attr {
+    /// Creates the attributes of a mapping with `mem_attr`, executable from
+    /// the exception levels `exec`.
+    pub const fn new(mem_attr: Stage2MemAttr, exec: Stage2Exec) -> Self {
+        Self { mem_attr, exec }
+    }
+}
+
+impl From<Stage2MemAttr> for Stage2Attr {
+    /// Creates the attributes of a mapping with `mem_attr`, executable from
+    /// EL1 and EL0.
+    fn from(mem_attr: Stage2MemAttr) -> Self {
+        Self::new(mem_attr, Stage2Exec::All)
+    }
 }
 
 impl DescriptorAttr {
-    #[allow(clippy::unusual_byte_groupings)]
-    const ATTR_INDEX_MASK: u64 = 0b1111_00;
-    const PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE: u64 = 0b11 << 2;
-    const PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_CACHEABLE: u64 = 0b11 << 4;
-    const PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE: u64 = 0b1 << 4;
-    const NORMAL_BIT: u64 = Self::PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE
-        | Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_CACHEABLE;
-
-    const fn from_mem_type(mem_type: MemType) -> Self {
-        let bits = match mem_type {
-            MemType::Normal => Self::NORMAL_BIT | Self::SHAREABLE.bits(),
-            MemType::NormalNonCache => {
-                Self::PTE_S2_MEM_ATTR_NORMAL_INNER_WRITE_BACK_CACHEABLE
-                    | Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE
-                    | Self::SHAREABLE.bits()
-            }
-            MemType::Device => Self::SHAREABLE.bits(),
+    const MEM_ATTR_SHIFT: u32 = 2;
+
+    /// Returns the attributes of Normal memory with `mem_attr`, or of Device
+    /// memory, which is never executable.
+    const fn from_mem_attr(mem_attr: Stage2MemAttr, fwb: bool) -> Self {
+        let Some(bits) = mem_attr.encode(fwb) else {
+            // Unsupported attributes are checked before mapping.
+            return Self::empty();
         };
-        Self::from_bits_retain(bits)
+        let attr = Self::from_bits_retain(bits << Self::MEM_ATTR_SHIFT);
+        if mem_attr.is_device() {
+            attr.union(Self::XN)
+        } else {
+            attr.union(Self::SHAREABLE)
+        }
+    }
+
+    /// Converts the flags of a leaf descriptor with the memory attribute
+    /// `mem_attr`. The descriptor is left invalid if `flags` grants no
+    /// access.
+    fn from_flags(flags: MappingFlags, mem_attr: Stage2MemAttr, fwb: bool) -> Self {
+        let mut attr = Self::from_mem_attr(mem_attr, fwb);
+        if flags.contains(MappingFlags::READ) {
+            attr |= Self::S2AP_R;
+        }
+        if flags.contains(MappingFlags::WRITE) {
+            attr |= Self::S2AP_W;
+        }
+        if !flags.contains(MappingFlags::EXECUTE) {
+            attr |= Self::XN;
+        }
+        if flags.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            attr |= Self::VALID;
+        }
+        attr
     }
 
-    fn mem_type(&self) -> MemType {
-        let idx = self.bits() & Self::ATTR_INDEX_MASK;
-        match idx {
-            Self::NORMAL_BIT => MemType::Normal,
-            Self::PTE_S2_MEM_ATTR_NORMAL_OUTER_WRITE_BACK_NOCACHEABLE => MemType::NormalNonCache,
-            0 => MemType::Device,
-            _ => panic!("Invalid memory attribute index"),
+    fn exec(&self) -> Stage2Exec {
+        match (self.contains(Self::XN), self.contains(Self::XNX)) {
+            (false, false) => Stage2Exec::All,
+            (false, true) => Stage2Exec::El0,
+            (true, false) => Stage2Exec::None,
+            (true, true) => Stage2Exec::El1,
         }
     }
+
+    const fn mem_attr_bits(&self) -> u64 {
+        (self.bits() & Self::ATTR.bits()) >> Self::MEM_ATTR_SHIFT
+    }
+
+    fn mem_attr(&self) -> Option<Stage2MemAttr> {
+        Stage2MemAttr::decode(self.mem_attr_bits(), s2fwb_enabled())
+    }
 }
 
 impl From<DescriptorAttr> for MappingFlags {
+    /// Converts the attributes of a leaf descriptor.
+    ///
+    /// `EXECUTE` is reported for the descriptors executable at EL1 or EL0,
+    /// whose exception levels are reported by [`Stage2Attr::exec`]. `DEVICE`
+    /// is reported for Device memory, and `UNCACHED` for Normal Non-cacheable
+    /// memory.
     fn from(attr: DescriptorAttr) -> Self {
         let mut flags = Self::empty();
-        if attr.contains(DescriptorAttr::VALID) {
+        if !attr.contains(DescriptorAttr::VALID) {
+            return flags;
+        }
+        if attr.contains(DescriptorAttr::S2AP_R) {
             flags |= Self::READ;
         }
-        if !attr.contains(DescriptorAttr::S2AP_WO) {
+        if attr.contains(DescriptorAttr::S2AP_W) {
             flags |= Self::WRITE;
         }
-        if !attr.contains(DescriptorAttr::XN) {
+        if attr.exec() != Stage2Exec::None {
             flags |= Self::EXECUTE;
         }
-        if attr.mem_type() == MemType::Device {
-            flags |= Self::DEVICE;
+        // The encodings of these types do not depend on FEAT_S2FWB.
+        match attr.mem_attr_bits() {
+            0b0000..=0b0011 => flags |= Self::DEVICE,
+            0b0101 => flags |= Self::UNCACHED,
+            _ => {}
         }
         flags
     }
 }
 
 impl From<MappingFlags> for DescriptorAttr {
+    /// Converts the flags of a leaf descriptor, whose memory attribute is
+    /// selected by `flags`: Device-nGnRnE memory for `DEVICE`, Normal
+    /// Non-cacheable memory for `UNCACHED`, and Normal Write-Back memory
+    /// otherwise.
     fn from(flags: MappingFlags) -> Self {
-        let mut attr = if flags.contains(MappingFlags::DEVICE) {
-            if flags.contains(MappingFlags::UNCACHED) {
-                Self::from_mem_type(MemType::NormalNonCache)
-            } else {
-                Self::from_mem_type(MemType::Device)
-            }
-        } else {
-            Self::from_mem_type(MemType::Normal)
-        };
-        if flags.contains(MappingFlags::READ) {
-            attr |= Self::VALID | Self::S2AP_RO;
-        }
-        if flags.contains(MappingFlags::WRITE) {
-            attr |= Self::S2AP_WO;
-        }
-        attr
+        let fwb = s2fwb_enabled();
+        Self::from_flags(flags, Stage2MemAttr::from_flags(flags, fwb), fwb)
     }
 }
 
-/// A VMSAv8-64 translation table descriptor.
+/// Returns whether a stage-2 descriptor can encode `flags`, i.e., they do not
+/// make device memory executable.
+fn flags_supported(flags: MappingFlags) -> bool {
+    !flags.contains(MappingFlags::DEVICE | MappingFlags::EXECUTE)
+        || flags.contains(MappingFlags::UNCACHED)
+}
+
+/// Returns whether a stage-2 descriptor can encode the attributes `attr`
+/// with the mapping `flags`. The execution at only one of EL1 and EL0 needs
+/// FEAT_XNX.
+fn mem_attr_supported(attr: Stage2Attr, flags: MappingFlags) -> bool {
+    attr.mem_attr.is_supported(s2fwb_enabled())
+        && !(attr.mem_attr.is_device() && flags.contains(MappingFlags::EXECUTE))
+        && (matches!(attr.exec, Stage2Exec::All | Stage2Exec::None) || xnx_supported())
+}
+
+/// A VMSAv8-64 stage-2 translation table descriptor.
 ///
-/// Note that the **AttrIndx\[2:0\]** (bit\[4:2\]) field is set to `0` for device
-/// memory, and `1` for normal memory. The system must configure the MAIR_ELx
-/// system register accordingly.
+/// The memory attribute of a page is encoded directly in its **MemAttr\[3:0\]**
+/// (bit\[5:2\]) field, see [`Stage2MemAttr`]. The exception levels from which
+/// an executable page is executable are encoded in its **XN\[1:0\]** field,
+/// see [`Stage2Exec`].
 #[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct A64PTEHV(u64);
@@ -179,7 +460,13 @@ impl GenericPTE for A64PTEHV {
         self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK)
     }
     fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let exec = DescriptorAttr::from_bits_retain(self.0).exec();
         let mut attr = DescriptorAttr::from(flags) | DescriptorAttr::AF;
+        if attr.exec() == Stage2Exec::All && exec != Stage2Exec::None {
+            // An executable descriptor stays executable from the same
+            // exception levels.
+            attr |= exec.xn_bits();
+        }
         if !is_huge {
             attr |= DescriptorAttr::NON_BLOCK;
         }
@@ -199,54 +486,436 @@ impl GenericPTE for A64PTEHV {
     }
 }
 
+impl DirtyState for A64PTEHV {
+    // Requires hardware dirty state management to be enabled in VTCR_EL2.HD.
+    const HW_DIRTY: bool = true;
+
+    fn start_dirty_tracking(&mut self) {
+        let attr = DescriptorAttr::from_bits_retain(self.0);
+        if attr.contains(DescriptorAttr::S2AP_W) {
+            // A writable-clean descriptor is read-only with DBM set.
+            self.0 = (attr | DescriptorAttr::DBM).bits() & !DescriptorAttr::S2AP_W.bits();
+        }
+    }
+
+    fn stop_dirty_tracking(&mut self) {
+        let attr = DescriptorAttr::from_bits_retain(self.0);
+        if attr.contains(DescriptorAttr::DBM) {
+            self.0 = ((attr - DescriptorAttr::DBM) | DescriptorAttr::S2AP_W).bits();
+        }
+    }
+
+    fn is_dirty(&self) -> bool {
+        DescriptorAttr::from_bits_retain(self.0)
+            .contains(DescriptorAttr::DBM | DescriptorAttr::S2AP_W)
+    }
+
+    fn clear_dirty(&mut self) {
+        if DescriptorAttr::from_bits_retain(self.0).contains(DescriptorAttr::DBM) {
+            self.0 &= !DescriptorAttr::S2AP_W.bits();
+        }
+    }
+
+    fn set_dirty(&mut self) {
+        if DescriptorAttr::from_bits_retain(self.0).contains(DescriptorAttr::DBM) {
+            self.0 |= DescriptorAttr::S2AP_W.bits();
+        }
+    }
+}
+
+impl MemAttrState for A64PTEHV {
+    type MemAttr = Stage2Attr;
+
+    fn mem_attr(&self) -> Option<Stage2Attr> {
+        let attr = DescriptorAttr::from_bits_retain(self.0);
+        let exec = match attr.exec() {
+            Stage2Exec::None => Stage2Exec::All,
+            exec => exec,
+        };
+        Some(Stage2Attr::new(attr.mem_attr()?, exec))
+    }
+
+    /// Replaces the attributes of a leaf descriptor. The exception levels
+    /// only apply to an executable descriptor, and Device memory is never
+    /// executable.
+    fn set_mem_attr(&mut self, mem_attr: Stage2Attr) {
+        let old = DescriptorAttr::from_bits_retain(self.0);
+        let exec = if old.exec() == Stage2Exec::None || mem_attr.mem_attr.is_device() {
+            Stage2Exec::None
+        } else {
+            mem_attr.exec
+        };
+        let attr = old
+            - DescriptorAttr::ATTR
+            - DescriptorAttr::INNER
+            - DescriptorAttr::SHAREABLE
+            - DescriptorAttr::XN
+            - DescriptorAttr::XNX;
+        self.0 = (attr
+            | DescriptorAttr::from_mem_attr(mem_attr.mem_attr, s2fwb_enabled())
+            | exec.xn_bits())
+        .bits();
+    }
+}
+
 impl fmt::Debug for A64PTEHV {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut f = f.debug_struct("A64PTE");
         f.field("raw", &self.0)
             .field("paddr", &self.paddr())
             .field("attr", &DescriptorAttr::from_bits_truncate(self.0))
+            .field("exec", &DescriptorAttr::from_bits_retain(self.0).exec())
+            .field(
+                "mem_attr",
+                &DescriptorAttr::from_bits_retain(self.0).mem_attr(),
+            )
             .field("flags", &self.flags())
             .finish()
     }
 }
 
-/// Metadata of AArch64 hypervisor page tables (ipa to hpa).
-#[derive(Copy, Clone)]
-pub struct A64HVPagingMetaData;
+/// Invalidates the cached stage-2 translations with TLBI.
+///
+/// The TLBI instructions of the EL1&0 regime apply to the VMID of
+/// `VTTBR_EL2`, so the VMID of a targeted invalidation is installed there
+/// while it is executed. Translations without VMID are invalidated for all
+/// VMIDs.
+#[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
+pub(crate) fn tlbi(flush: NestedTlbFlush) {
+    // SAFETY: TLBI only invalidates cached translations, and `VTTBR_EL2` is
+    // restored before returning.
+    unsafe {
+        #[cfg(feature = "arm-el2")]
+        {
+            // Makes the updates of the tables visible to the table walks.
+            asm!("dsb ishst");
+            match flush {
+                NestedTlbFlush::Page {
+                    context: Some(vmid),
+                    gpa,
+                } => with_vmid(vmid, || {
+                    // The stage-1 translations combined with the stage-2 one
+                    // cannot be invalidated by IPA, all of them are.
+                    asm!(
+                        "tlbi ipas2e1is, {}; dsb ish; tlbi vmalle1is; dsb ish; isb",
+                        in(reg) gpa.as_usize() >> 12,
+                    )
+                }),
+                NestedTlbFlush::Context(vmid) => {
+                    with_vmid(vmid, || asm!("tlbi vmalls12e1is; dsb ish; isb"))
+                }
+                NestedTlbFlush::Page { context: None, .. } | NestedTlbFlush::All => {
+                    asm!("tlbi alle1is; dsb ish; isb")
+                }
+            }
+        }
+        #[cfg(not(feature = "arm-el2"))]
+        {
+            let _ = flush;
+            asm!("tlbi vmalle1; dsb sy; isb")
+        }
+    }
+}
+
+/// Executes `f` with `vmid` installed in `VTTBR_EL2`.
+#[cfg(all(
+    target_arch = "aarch64",
+    feature = "arm-el2",
+    not(any(test, axaddrspace_host))
+))]
+unsafe fn with_vmid(vmid: u64, f: impl FnOnce()) {
+    const VMID_MASK: u64 = 0xffff << 48;
+    let vttbr: u64;
+    unsafe {
+        asm!("mrs {}, vttbr_el2", out(reg) vttbr);
+        asm!("msr vttbr_el2, {}; isb", in(reg) (vttbr & !VMID_MASK) | (vmid << 48));
+        f();
+        asm!("msr vttbr_el2, {}; isb", in(reg) vttbr);
+    }
+}
 
-impl PagingMetaData for A64HVPagingMetaData {
-    const LEVELS: usize = 3;
-    // In Armv8.0-A, the maximum size for a physical address is 48 bits.
-    const PA_MAX_BITS: usize = 48;
-    // The size of the IPA space can be configured in the same way as the
-    const VA_MAX_BITS: usize = 40; //  virtual address space. VTCR_EL2.T0SZ controls the size.
+/// The nested page table format of the AArch64 stage-2 translation.
+#[derive(Debug, Clone, Copy)]
+pub struct Stage2Format;
 
-    type VirtAddr = GuestPhysAddr;
+impl NestedFormat for Stage2Format {
+    type PTE = A64PTEHV;
+    type Config = Stage2Config;
+    type MemAttr = Stage2Attr;
 
-    fn flush_tlb(vaddr: Option<Self::VirtAddr>) {
-        unsafe {
-            if let Some(vaddr) = vaddr {
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vaae1is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi vae2is, {}; dsb sy; isb", in(reg) vaddr.as_usize())
-                }
+    fn geometry(config: &Stage2Config) -> TableGeometry {
+        config.geometry()
+    }
+
+    fn hw_dirty_supported() -> bool {
+        hw_dirty_supported()
+    }
+
+    fn flags_supported(flags: MappingFlags) -> bool {
+        flags_supported(flags)
+    }
+
+    fn mem_attr_supported(attr: Stage2Attr, flags: MappingFlags) -> bool {
+        mem_attr_supported(attr, flags)
+    }
+
+    fn flush_tlb(flush: NestedTlbFlush) {
+        cfg_if::cfg_if! {
+            if #[cfg(any(test, axaddrspace_host))] {
+                crate::host::record_tlb_flush(flush);
+            } else if #[cfg(target_arch = "aarch64")] {
+                tlbi(flush);
             } else {
-                // flush the entire TLB
-                #[cfg(not(feature = "arm-el2"))]
-                {
-                    asm!("tlbi vmalle1; dsb sy; isb")
-                }
-                #[cfg(feature = "arm-el2")]
-                {
-                    asm!("tlbi alle2is; dsb sy; isb")
-                }
+                let _ = flush;
             }
         }
     }
 }
-/// According to rust shyper, AArch64 translation table.
-pub type NestedPageTable<H> = PageTable64<A64HVPagingMetaData, A64PTEHV, H>;
+
+/// The configuration of the AArch64 stage-2 translation of an address space,
+/// with the 4K translation granule.
+///
+/// The walk starts at `start_level`. When the IPA space is wider than what a
+/// single table of the start level resolves, up to 16 tables are concatenated
+/// at the start level. (ARM DDI 0487, Section D8.2.7)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stage2Config {
+    ipa_bits: usize,
+    start_level: usize,
+}
+
+impl Stage2Config {
+    /// Creates a configuration translating `ipa_bits`-bit IPAs with a walk
+    /// starting at `start_level`.
+    ///
+    /// Returns `None` if the combination is not supported by the hardware,
+    /// i.e., `start_level` is not from 0 to 2, `ipa_bits` is not from 25 to
+    /// 48, or the start level would need more than 16 concatenated tables or
+    /// resolve no bit of the IPA.
+    pub const fn new(ipa_bits: usize, start_level: usize) -> Option<Self> {
+        let config = Self {
+            ipa_bits,
+            start_level,
+        };
+        if start_level <= 2 && matches!(ipa_bits, 25..=48) && config.geometry().is_valid() {
+            Some(config)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the size of the IPA space in bits.
+    pub const fn ipa_bits(&self) -> usize {
+        self.ipa_bits
+    }
+
+    /// Returns the level of the first lookup.
+    pub const fn start_level(&self) -> usize {
+        self.start_level
+    }
+
+    /// Returns the number of concatenated tables at the start level.
+    pub const fn concatenated_tables(&self) -> usize {
+        self.geometry().root_tables()
+    }
+
+    /// Returns the value of `VTCR_EL2` for the stage-2 tables of the
+    /// configuration.
+    ///
+    /// The tables are walked with the write-back cacheable, inner shareable
+    /// attributes. The physical address size is the one supported by the CPU,
+    /// up to 48 bits, and 16-bit VMIDs are enabled if supported. The hardware
+    /// management of the access flag and dirty state is enabled if `hw_dirty`
+    /// is true and the CPU supports it (FEAT_HAFDBS).
+    ///
+    /// The IPA space must not be wider than the physical address size.
+    pub fn vtcr(&self, hw_dirty: bool) -> u64 {
+        const IRGN0_WBWA: u64 = 0b01 << 8;
+        const ORGN0_WBWA: u64 = 0b01 << 10;
+        const SH0_INNER: u64 = 0b11 << 12;
+        const VS_16BIT: u64 = 1 << 19;
+        const HA: u64 = 1 << 21;
+        const HD: u64 = 1 << 22;
+        const RES1: u64 = 1 << 31;
+
+        let t0sz = (64 - self.ipa_bits) as u64;
+        // SL0 encodes the start level as 2 - level with the 4K granule.
+        let sl0 = (2 - self.start_level) as u64;
+        let mut vtcr =
+            RES1 | t0sz | (sl0 << 6) | IRGN0_WBWA | ORGN0_WBWA | SH0_INNER | (pa_range() << 16);
+        if vmid_16bit_supported() {
+            vtcr |= VS_16BIT;
+        }
+        if hw_dirty && hw_dirty_supported() {
+            vtcr |= HA | HD;
+        }
+        vtcr
+    }
+
+    /// Returns the value of `VTTBR_EL2` selecting the stage-2 table rooted at
+    /// `root` for the virtual machine `vmid`.
+    ///
+    /// `vmid` must fit in 8 bits if 16-bit VMIDs are not supported.
+    pub fn vttbr(root: HostPhysAddr, vmid: u16) -> u64 {
+        const BADDR_MASK: u64 = 0x0000_ffff_ffff_fffe;
+        ((vmid as u64) << 48) | (root.as_usize() as u64 & BADDR_MASK)
+    }
+
+    /// Returns the geometry of the stage-2 tables of the configuration.
+    pub(crate) const fn geometry(&self) -> TableGeometry {
+        TableGeometry {
+            levels: 4 - self.start_level,
+            input_bits: self.ipa_bits,
+        }
+    }
+
+    /// Returns the configuration whose tables have `geometry`.
+    pub(crate) const fn from_geometry(geometry: TableGeometry) -> Self {
+        Self {
+            ipa_bits: geometry.input_bits,
+            start_level: 4 - geometry.levels,
+        }
+    }
+}
+
+impl Default for Stage2Config {
+    /// A 39-bit IPA space with a walk starting at level 1, whose root table
+    /// fits in one frame.
+    fn default() -> Self {
+        Self::from_geometry(STAGE2_GEOMETRY)
+    }
+}
+
+/// The geometry of the default AArch64 stage-2 translation tables, see
+/// [`Stage2Config::default`].
+pub(crate) const STAGE2_GEOMETRY: TableGeometry = TableGeometry {
+    levels: 3,
+    input_bits: 39,
+};
+
+/// Returns the `VTCR_EL2.PS` encoding of the physical address size supported
+/// by the CPU, limited to the 48 bits of the descriptors.
+#[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
+fn pa_range() -> u64 {
+    let mmfr0: u64;
+    // SAFETY: reading an ID register has no side effect.
+    unsafe { asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0) };
+    (mmfr0 & 0xf).min(0b101)
+}
+
+/// Returns whether the CPU supports 16-bit VMIDs.
+#[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
+pub(crate) fn vmid_16bit_supported() -> bool {
+    let mmfr1: u64;
+    // SAFETY: reading an ID register has no side effect.
+    unsafe { asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1) };
+    (mmfr1 >> 4) & 0xf == 0b0010
+}
+
+/// Returns whether the CPU supports the hardware management of the access
+/// flag and dirty state (FEAT_HAFDBS).
+#[cfg(all(target_arch = "aarch64", not(any(test, axaddrspace_host))))]
+pub(crate) fn hw_dirty_supported() -> bool {
+    let mmfr1: u64;
+    // SAFETY: reading an ID register has no side effect.
+    unsafe { asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1) };
+    mmfr1 & 0xf >= 0b0010
+}
+
+/// Returns the 48-bit physical address size reported without the hardware,
+/// in the host-side software mode or on other architectures.
+#[cfg(not(all(target_arch = "aarch64", not(any(test, axaddrspace_host)))))]
+fn pa_range() -> u64 {
+    0b101
+}
+
+/// Returns the support of 16-bit VMIDs reported without the hardware, in the
+/// host-side software mode or on other architectures.
+#[cfg(not(all(target_arch = "aarch64", not(any(test, axaddrspace_host)))))]
+pub(crate) fn vmid_16bit_supported() -> bool {
+    true
+}
+
+/// Returns the support of FEAT_HAFDBS reported without the hardware, in the
+/// host-side software mode or on other architectures.
+#[cfg(not(all(target_arch = "aarch64", not(any(test, axaddrspace_host)))))]
+pub(crate) fn hw_dirty_supported() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rwx() -> MappingFlags {
+        MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE
+    }
+
+    /// Returns the XN\[1:0\] field of `pte`.
+    fn xn_field(pte: A64PTEHV) -> u64 {
+        (pte.0 >> 53) & 0b11
+    }
+
+    fn page(flags: MappingFlags, exec: Stage2Exec) -> A64PTEHV {
+        let mut pte = A64PTEHV::new_page(HostPhysAddr::from(0x8000_0000), flags, false);
+        pte.set_mem_attr(Stage2Attr::new(Stage2MemAttr::WRITE_BACK, exec));
+        pte
+    }
+
+    #[test]
+    fn exec_encodings() {
+        for (exec, xn) in [
+            (Stage2Exec::All, 0b00),
+            (Stage2Exec::El0, 0b01),
+            (Stage2Exec::El1, 0b11),
+            (Stage2Exec::None, 0b10),
+        ] {
+            let pte = page(rwx(), exec);
+            assert_eq!(xn_field(pte), xn, "{exec:?}");
+            assert_eq!(DescriptorAttr::from_bits_retain(pte.0).exec(), exec);
+            assert_eq!(
+                pte.flags().contains(MappingFlags::EXECUTE),
+                exec != Stage2Exec::None
+            );
+            let decoded = if exec == Stage2Exec::None {
+                Stage2Exec::All
+            } else {
+                exec
+            };
+            assert_eq!(
+                pte.mem_attr(),
+                Some(Stage2Attr::new(Stage2MemAttr::WRITE_BACK, decoded))
+            );
+        }
+    }
+
+    #[test]
+    fn set_flags_keeps_exec() {
+        for exec in [Stage2Exec::El0, Stage2Exec::El1] {
+            let mut pte = page(rwx(), exec);
+            pte.set_flags(MappingFlags::READ | MappingFlags::EXECUTE, false);
+            assert_eq!(DescriptorAttr::from_bits_retain(pte.0).exec(), exec);
+            pte.set_flags(MappingFlags::READ, false);
+            assert_eq!(xn_field(pte), 0b10);
+        }
+    }
+
+    #[test]
+    fn exec_needs_executable_normal_memory() {
+        // The exception levels do not make a descriptor executable.
+        let pte = page(MappingFlags::READ, Stage2Exec::El1);
+        assert_eq!(xn_field(pte), 0b10);
+
+        let mut pte = page(rwx(), Stage2Exec::El0);
+        pte.set_mem_attr(Stage2Attr::new(Stage2MemAttr::DeviceGRE, Stage2Exec::El0));
+        assert_eq!(xn_field(pte), 0b10);
+        assert!(!mem_attr_supported(
+            Stage2Attr::new(Stage2MemAttr::DeviceGRE, Stage2Exec::El1),
+            MappingFlags::READ | MappingFlags::EXECUTE
+        ));
+        assert!(mem_attr_supported(
+            Stage2Attr::new(Stage2MemAttr::WRITE_BACK, Stage2Exec::El1),
+            rwx()
+        ));
+    }
+}
@@ -1,14 +1,19 @@
 //! Architecture dependent structures.
+//!
+//! The format of the current architecture is always compiled, the others are
+//! compiled with their features.
 
-cfg_if::cfg_if! {
-    if #[cfg(target_arch = "x86_64")] {
-        mod x86_64;
-        pub use self::x86_64::*;
-    } else if #[cfg(target_arch = "aarch64")] {
-        mod aarch64;
-        pub use self::aarch64::*;
-    } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
-        mod riscv;
-        pub use self::riscv::*;
-    }
-}
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+mod x86_64;
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+pub use self::x86_64::*;
+
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+mod aarch64;
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+pub use self::aarch64::*;
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+mod riscv;
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+pub use self::riscv::*;
@@ -1,6 +1,284 @@
-use page_table_entry::riscv::Rv64PTE;
-use page_table_multiarch::{PageTable64, riscv::Sv39MetaData};
+#[cfg(all(
+    any(target_arch = "riscv32", target_arch = "riscv64"),
+    not(any(test, axaddrspace_host))
+))]
+use core::arch::asm;
+use core::fmt;
 
-use crate::GuestPhysAddr;
+use page_table_entry::{GenericPTE, MappingFlags};
 
-pub type NestedPageTable<H> = PageTable64<Sv39MetaData<GuestPhysAddr>, Rv64PTE, H>;
+use crate::HostPhysAddr;
+use crate::npt::{DirtyState, MemAttrState, NestedFormat, NestedTlbFlush, TableGeometry};
+
+bitflags::bitflags! {
+    /// G-stage page table entry flags. (RISC-V Privileged Spec, Section 18.5.1)
+    #[derive(Debug, Clone, Copy)]
+    struct GStageFlags: u64 {
+        /// Whether the entry is valid.
+        const V = 1 << 0;
+        /// Read access.
+        const R = 1 << 1;
+        /// Write access.
+        const W = 1 << 2;
+        /// Execute access.
+        const X = 1 << 3;
+        /// User mode access, which is how all the G-stage accesses are
+        /// checked. Required on leaf entries.
+        const U = 1 << 4;
+        /// Global mapping.
+        const G = 1 << 5;
+        /// Accessed.
+        const A = 1 << 6;
+        /// Dirty.
+        const D = 1 << 7;
+    }
+}
+
+impl GStageFlags {
+    /// Returns the flags of a leaf entry with the permissions of `f`.
+    ///
+    /// Leaves are marked accessed and dirty, so that no fault is raised for
+    /// them when the hardware does not update these bits.
+    fn leaf(f: MappingFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(MappingFlags::READ) {
+            ret |= Self::R;
+        }
+        if f.contains(MappingFlags::WRITE) {
+            // Write-only pages are reserved.
+            ret |= Self::R | Self::W;
+        }
+        if f.contains(MappingFlags::EXECUTE) {
+            ret |= Self::X;
+        }
+        if ret.is_empty() {
+            // Entries without permissions are left invalid.
+            return ret;
+        }
+        ret | Self::V | Self::U | Self::A | Self::D
+    }
+}
+
+impl From<GStageFlags> for MappingFlags {
+    fn from(f: GStageFlags) -> Self {
+        let mut ret = MappingFlags::empty();
+        if f.contains(GStageFlags::R) {
+            ret |= Self::READ;
+        }
+        if f.contains(GStageFlags::W) {
+            ret |= Self::WRITE;
+        }
+        if f.contains(GStageFlags::X) {
+            ret |= Self::EXECUTE;
+        }
+        ret
+    }
+}
+
+/// A RISC-V G-stage page table entry.
+///
+/// It is laid out like an Sv39/Sv48 entry, but the U bit is set on all the
+/// leaf entries and cleared on the others, as the hypervisor extension
+/// requires.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct GStagePTE(u64);
+
+impl GStagePTE {
+    const PHYS_ADDR_MASK: u64 = 0x003f_ffff_ffff_fc00; // bits 10..54, PPN of bits 12..56
+    const PERM_MASK: u64 = 0b1110; // R, W, X
+}
+
+impl GenericPTE for GStagePTE {
+    fn new_page(paddr: HostPhysAddr, flags: MappingFlags, _is_huge: bool) -> Self {
+        let flags = GStageFlags::leaf(flags);
+        Self(flags.bits() | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: HostPhysAddr) -> Self {
+        Self(GStageFlags::V.bits() | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> HostPhysAddr {
+        HostPhysAddr::from(((self.0 & Self::PHYS_ADDR_MASK) << 2) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        GStageFlags::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: HostPhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK)
+            | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK)
+    }
+    fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | GStageFlags::leaf(flags).bits()
+    }
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        GStageFlags::from_bits_truncate(self.0).contains(GStageFlags::V)
+    }
+    fn is_huge(&self) -> bool {
+        // A valid entry with any of R, W or X set is a leaf.
+        self.0 & Self::PERM_MASK != 0
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+
+    fn bits(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// Hardware updates of the G-stage D bit (Svadu) are not used, dirty pages are
+// tracked by write protection.
+impl DirtyState for GStagePTE {}
+
+// G-stage entries have no memory attributes.
+impl MemAttrState for GStagePTE {
+    type MemAttr = ();
+
+    fn mem_attr(&self) -> Option<()> {
+        Some(())
+    }
+
+    fn set_mem_attr(&mut self, _mem_attr: ()) {}
+}
+
+impl fmt::Debug for GStagePTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GStagePTE")
+            .field("raw", &self.0)
+            .field("hpaddr", &self.paddr())
+            .field("flags", &GStageFlags::from_bits_truncate(self.0))
+            .finish()
+    }
+}
+
+/// The translation mode of the RISC-V G-stage.
+///
+/// The root table of the G-stage is 16 KiB, so that the guest physical
+/// address space is 2 bits wider than the virtual address space of the
+/// corresponding mode. (RISC-V Privileged Spec, Section 18.5.1)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GStageMode {
+    /// 3-level walk translating 41-bit guest physical addresses.
+    #[default]
+    Sv39x4,
+    /// 4-level walk translating 50-bit guest physical addresses.
+    Sv48x4,
+}
+
+impl GStageMode {
+    /// Returns the number of bits of the guest physical addresses.
+    pub const fn gpa_bits(self) -> usize {
+        self.geometry().input_bits
+    }
+
+    /// Returns the value of the `MODE` field of `hgatp`.
+    pub const fn hgatp_mode(self) -> u64 {
+        match self {
+            Self::Sv39x4 => 8,
+            Self::Sv48x4 => 9,
+        }
+    }
+
+    /// Returns the value of `hgatp` selecting the G-stage table rooted at
+    /// `root` for the virtual machine `vmid`.
+    ///
+    /// Only the low bits of `vmid` supported by the hart (VMIDLEN) are kept
+    /// by the hardware.
+    pub fn hgatp(self, root: HostPhysAddr, vmid: u16) -> u64 {
+        const VMID_MASK: u64 = (1 << 14) - 1;
+        const PPN_MASK: u64 = (1 << 44) - 1;
+        (self.hgatp_mode() << 60)
+            | ((vmid as u64 & VMID_MASK) << 44)
+            | ((root.as_usize() as u64 >> 12) & PPN_MASK)
+    }
+
+    /// Returns the mode whose table has `geometry`.
+    pub(crate) fn from_geometry(geometry: TableGeometry) -> Self {
+        if geometry.levels == 3 {
+            Self::Sv39x4
+        } else {
+            Self::Sv48x4
+        }
+    }
+
+    /// Returns the geometry of the G-stage table of the mode.
+    pub(crate) const fn geometry(self) -> TableGeometry {
+        match self {
+            Self::Sv39x4 => TableGeometry {
+                levels: 3,
+                input_bits: 41,
+            },
+            Self::Sv48x4 => TableGeometry {
+                levels: 4,
+                input_bits: 50,
+            },
+        }
+    }
+}
+
+/// The nested page table format of the RISC-V G-stage translation.
+#[derive(Debug, Clone, Copy)]
+pub struct GStageFormat;
+
+impl NestedFormat for GStageFormat {
+    type PTE = GStagePTE;
+    type Config = GStageMode;
+    type MemAttr = ();
+
+    fn geometry(config: &GStageMode) -> TableGeometry {
+        config.geometry()
+    }
+
+    fn flush_tlb(flush: NestedTlbFlush) {
+        cfg_if::cfg_if! {
+            if #[cfg(any(test, axaddrspace_host))] {
+                crate::host::record_tlb_flush(flush);
+            } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
+                hfence_gvma(flush);
+            } else {
+                let _ = flush;
+            }
+        }
+    }
+}
+
+/// Invalidates the cached G-stage translations with HFENCE.GVMA.
+///
+/// The instruction is encoded with `.insn`, so that the assembler does not
+/// need the hypervisor extension.
+#[cfg(all(
+    any(target_arch = "riscv32", target_arch = "riscv64"),
+    not(any(test, axaddrspace_host))
+))]
+pub(crate) fn hfence_gvma(flush: NestedTlbFlush) {
+    // SAFETY: HFENCE.GVMA only invalidates cached translations.
+    unsafe {
+        match flush {
+            // The guest physical address is shifted right by 2 bits, as
+            // required by the instruction.
+            NestedTlbFlush::Page {
+                context: Some(vmid),
+                gpa,
+            } => asm!(
+                ".insn r 0x73, 0, 0x31, x0, {}, {}",
+                in(reg) gpa.as_usize() >> 2,
+                in(reg) vmid as usize,
+                options(nostack),
+            ),
+            NestedTlbFlush::Page { context: None, gpa } => asm!(
+                ".insn r 0x73, 0, 0x31, x0, {}, x0",
+                in(reg) gpa.as_usize() >> 2,
+                options(nostack),
+            ),
+            NestedTlbFlush::Context(vmid) => asm!(
+                ".insn r 0x73, 0, 0x31, x0, x0, {}",
+                in(reg) vmid as usize,
+                options(nostack),
+            ),
+            NestedTlbFlush::All => asm!(".insn r 0x73, 0, 0x31, x0, x0, x0", options(nostack)),
+        }
+    }
+}
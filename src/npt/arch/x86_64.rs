@@ -1,10 +1,14 @@
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+use core::arch::asm;
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::{convert::TryFrom, fmt};
 
+use crate::HostPhysAddr;
+use crate::npt::{DirtyState, MemAttrState, NestedFormat, NestedTlbFlush, TableGeometry};
 use bit_field::BitField;
 use page_table_entry::{GenericPTE, MappingFlags};
-use page_table_multiarch::{PageTable64, PagingMetaData};
-
-use crate::{GuestPhysAddr, HostPhysAddr};
+use page_table_multiarch::PageSize;
 
 bitflags::bitflags! {
     /// EPT entry flags. (SDM Vol. 3C, Section 28.3.2)
@@ -33,25 +37,63 @@ bitflags::bitflags! {
 
 numeric_enum_macro::numeric_enum! {
     #[repr(u8)]
-    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     /// EPT memory typing. (SDM Vol. 3C, Section 28.3.7)
-    enum EPTMemType {
+    pub enum EptMemType {
+        /// Uncacheable (UC).
         Uncached = 0,
+        /// Write Combining (WC).
         WriteCombining = 1,
+        /// Write-through (WT).
         WriteThrough = 4,
+        /// Write-protected (WP).
         WriteProtected = 5,
+        /// Write-back (WB).
         WriteBack = 6,
     }
 }
 
+/// The memory attribute of an EPT mapping: its EPT memory type, and whether
+/// it overrides the guest PAT memory type. (SDM Vol. 3C, Section 29.3.7.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EptMemAttr {
+    /// The EPT memory type.
+    pub mem_type: EptMemType,
+    /// Whether the guest PAT memory type is ignored (IPAT), so that the EPT
+    /// memory type is the effective one. Otherwise the two are combined.
+    pub ignore_pat: bool,
+}
+
+impl EptMemAttr {
+    /// Write-back memory, the attribute of the regular guest memory.
+    pub const WRITE_BACK: Self = Self::new(EptMemType::WriteBack, false);
+    /// Uncacheable memory, whatever the guest PAT memory type.
+    pub const UNCACHED: Self = Self::new(EptMemType::Uncached, true);
+    /// Write Combining memory, like framebuffers.
+    pub const WRITE_COMBINING: Self = Self::new(EptMemType::WriteCombining, false);
+
+    /// Creates a memory attribute with `mem_type`, which overrides the guest
+    /// PAT memory type if `ignore_pat` is true.
+    pub const fn new(mem_type: EptMemType, ignore_pat: bool) -> Self {
+        Self {
+            mem_type,
+            ignore_pat,
+        }
+    }
+}
+
 impl EPTFlags {
-    fn set_mem_type(&mut self, mem_type: EPTMemType) {
+    fn set_mem_type(&mut self, mem_type: EptMemType) {
         let mut bits = self.bits();
         bits.set_bits(3..6, mem_type as u64);
         *self = Self::from_bits_truncate(bits)
     }
-    fn mem_type(&self) -> Result<EPTMemType, u8> {
-        EPTMemType::try_from(self.bits().get_bits(3..6) as u8)
+    fn mem_type(&self) -> Result<EptMemType, u8> {
+        EptMemType::try_from(self.bits().get_bits(3..6) as u8)
+    }
+    fn mem_attr(&self) -> Option<EptMemAttr> {
+        let mem_type = self.mem_type().ok()?;
+        Some(EptMemAttr::new(mem_type, self.contains(Self::IGNORE_PAT)))
     }
 }
 
@@ -71,7 +113,7 @@ impl From<MappingFlags> for EPTFlags {
             ret |= Self::EXECUTE;
         }
         if !f.contains(MappingFlags::DEVICE) {
-            ret.set_mem_type(EPTMemType::WriteBack);
+            ret.set_mem_type(EptMemType::WriteBack);
         }
         ret
     }
@@ -89,7 +131,7 @@ impl From<EPTFlags> for MappingFlags {
         if f.contains(EPTFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
-        if let Ok(EPTMemType::Uncached) = f.mem_type() {
+        if let Ok(EptMemType::Uncached) = f.mem_type() {
             ret |= Self::DEVICE;
         }
         ret
@@ -153,6 +195,40 @@ impl GenericPTE for EPTEntry {
     }
 }
 
+impl DirtyState for EPTEntry {
+    // Requires the accessed and dirty flags to be enabled in the EPTP.
+    const HW_DIRTY: bool = true;
+
+    fn start_dirty_tracking(&mut self) {
+        self.clear_dirty();
+    }
+
+    fn is_dirty(&self) -> bool {
+        EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::DIRTY)
+    }
+
+    fn clear_dirty(&mut self) {
+        self.0 &= !EPTFlags::DIRTY.bits();
+    }
+
+    fn set_dirty(&mut self) {
+        self.0 |= (EPTFlags::ACCESSED | EPTFlags::DIRTY).bits();
+    }
+}
+
+impl MemAttrState for EPTEntry {
+    type MemAttr = EptMemAttr;
+
+    fn mem_attr(&self) -> Option<EptMemAttr> {
+        EPTFlags::from_bits_truncate(self.0).mem_attr()
+    }
+
+    fn set_mem_attr(&mut self, mem_attr: EptMemAttr) {
+        self.0.set_bits(3..6, mem_attr.mem_type as u64);
+        self.0.set_bit(6, mem_attr.ignore_pat);
+    }
+}
+
 impl fmt::Debug for EPTEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("EPTEntry")
@@ -160,24 +236,199 @@ impl fmt::Debug for EPTEntry {
             .field("hpaddr", &self.paddr())
             .field("flags", &self.flags())
             .field("mem_type", &EPTFlags::from_bits_truncate(self.0).mem_type())
+            .field(
+                "ignore_pat",
+                &EPTFlags::from_bits_truncate(self.0).contains(EPTFlags::IGNORE_PAT),
+            )
             .finish()
     }
 }
 
-/// Metadata of VMX extended page tables.
-pub struct ExtendedPageTableMetadata;
+/// The length of the EPT page walk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EptWalkLength {
+    /// 4-level walk translating 48-bit guest physical addresses.
+    #[default]
+    FourLevel,
+    /// 5-level walk translating 57-bit guest physical addresses.
+    FiveLevel,
+}
 
-impl PagingMetaData for ExtendedPageTableMetadata {
-    const LEVELS: usize = 4;
-    const PA_MAX_BITS: usize = 52;
-    const VA_MAX_BITS: usize = 48;
+/// The configuration of the extended page table of an address space.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EptConfig {
+    /// The length of the page walk.
+    pub walk_length: EptWalkLength,
+    /// Whether the processor sets the accessed and dirty flags of the
+    /// entries, which are then used for dirty page logging.
+    pub accessed_dirty: bool,
+}
 
-    type VirtAddr = GuestPhysAddr;
+impl EptConfig {
+    /// Returns whether the processor supports the configuration, as reported
+    /// by `IA32_VMX_EPT_VPID_CAP`.
+    pub fn is_supported(&self) -> bool {
+        let cap = ept_vpid_cap();
+        let walk = match self.walk_length {
+            EptWalkLength::FourLevel => cap.get_bit(6),
+            EptWalkLength::FiveLevel => cap.get_bit(7),
+        };
+        walk && (!self.accessed_dirty || cap.get_bit(21))
+    }
 
-    fn flush_tlb(_vaddr: Option<GuestPhysAddr>) {
-        todo!()
+    /// Returns the geometry of the extended page table of the configuration.
+    pub(crate) const fn geometry(&self) -> TableGeometry {
+        match self.walk_length {
+            EptWalkLength::FourLevel => EPT_GEOMETRY,
+            EptWalkLength::FiveLevel => TableGeometry {
+                levels: 5,
+                input_bits: 57,
+            },
+        }
     }
 }
 
-/// The VMX extended page table. (SDM Vol. 3C, Section 29.3)
-pub type ExtendedPageTable<H> = PageTable64<ExtendedPageTableMetadata, EPTEntry, H>;
+/// The geometry of VMX extended page tables, with a 4-level walk translating
+/// 48-bit guest physical addresses. (SDM Vol. 3C, Section 29.3)
+pub(crate) const EPT_GEOMETRY: TableGeometry = TableGeometry {
+    levels: 4,
+    input_bits: 48,
+};
+
+/// Returns the EPTP of the extended page table rooted at `root`, with a walk
+/// of `levels`. (SDM Vol. 3C, Section 25.6.11)
+///
+/// The paging structures are accessed with the write-back memory type like
+/// the pages mapped by [`EPTEntry`], unless the processor only supports the
+/// uncacheable type for them.
+pub(crate) fn eptp(root: HostPhysAddr, levels: usize, accessed_dirty: bool) -> u64 {
+    let mem_type = if ept_vpid_cap().get_bit(14) {
+        EptMemType::WriteBack
+    } else {
+        EptMemType::Uncached
+    };
+    let mut eptp = root.as_usize() as u64 & EPTEntry::PHYS_ADDR_MASK;
+    eptp.set_bits(0..3, mem_type as u64);
+    eptp.set_bits(3..6, levels as u64 - 1);
+    eptp.set_bit(6, accessed_dirty);
+    eptp
+}
+
+/// The nested page table format of Intel VMX, the extended page tables.
+#[derive(Debug, Clone, Copy)]
+pub struct EptFormat;
+
+impl NestedFormat for EptFormat {
+    type PTE = EPTEntry;
+    type Config = EptConfig;
+    type MemAttr = EptMemAttr;
+
+    fn geometry(config: &EptConfig) -> TableGeometry {
+        config.geometry()
+    }
+
+    fn config_supported(config: &EptConfig) -> bool {
+        config.is_supported()
+    }
+
+    fn config_hw_dirty(config: &EptConfig) -> bool {
+        config.accessed_dirty
+    }
+
+    fn hw_dirty_supported() -> bool {
+        ept_vpid_cap().get_bit(21)
+    }
+
+    fn huge_page_supported(page_size: PageSize) -> bool {
+        match page_size {
+            PageSize::Size4K => true,
+            PageSize::Size2M => ept_vpid_cap().get_bit(16),
+            PageSize::Size1G => ept_vpid_cap().get_bit(17),
+        }
+    }
+
+    fn tlb_context(root: HostPhysAddr, geometry: TableGeometry, _vmid: Option<u16>) -> Option<u64> {
+        // EPT translations are tagged by the EPTP. INVEPT only uses the
+        // address of the root table, the flags are left clear to keep the
+        // EPTP valid.
+        Some(eptp(root, geometry.levels, false))
+    }
+
+    fn flush_tlb(flush: NestedTlbFlush) {
+        cfg_if::cfg_if! {
+            if #[cfg(any(test, axaddrspace_host))] {
+                crate::host::record_tlb_flush(flush);
+            } else if #[cfg(target_arch = "x86_64")] {
+                invept(flush);
+            } else {
+                let _ = flush;
+            }
+        }
+    }
+}
+
+/// The VMX capability MSR reporting the supported INVEPT types.
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+const IA32_VMX_EPT_VPID_CAP: u32 = 0x48c;
+
+/// INVEPT types. (SDM Vol. 3C, Section 29.4.3.1)
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+const INVEPT_SINGLE_CONTEXT: u64 = 1;
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+const INVEPT_ALL_CONTEXT: u64 = 2;
+
+/// Returns the capabilities reported without the hardware, in the host-side
+/// software mode or on other architectures: 4- and 5-level walks, write-back
+/// paging structures, 2M and 1G pages, accessed and dirty flags, and
+/// single-context INVEPT.
+#[cfg(not(all(target_arch = "x86_64", not(any(test, axaddrspace_host)))))]
+fn ept_vpid_cap() -> u64 {
+    (1 << 6) | (1 << 7) | (1 << 14) | (1 << 16) | (1 << 17) | (1 << 21) | (1 << 25)
+}
+
+/// Returns the value of `IA32_VMX_EPT_VPID_CAP`, which is read once.
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+fn ept_vpid_cap() -> u64 {
+    static CAP: AtomicU64 = AtomicU64::new(0);
+    let cap = CAP.load(Ordering::Relaxed);
+    if cap != 0 {
+        return cap;
+    }
+    let (low, high): (u32, u32);
+    // SAFETY: the MSR exists on all processors supporting EPT.
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") IA32_VMX_EPT_VPID_CAP,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack),
+        );
+    }
+    let cap = (high as u64) << 32 | low as u64;
+    CAP.store(cap, Ordering::Relaxed);
+    cap
+}
+
+/// Invalidates the cached EPT translations with INVEPT.
+///
+/// INVEPT has no invalidation by address, so a page is invalidated with its
+/// context. A single-context invalidation falls back to an all-context one if
+/// the processor does not support it.
+#[cfg(all(target_arch = "x86_64", not(any(test, axaddrspace_host))))]
+pub(crate) fn invept(flush: NestedTlbFlush) {
+    let context = match flush {
+        NestedTlbFlush::Page { context, .. } => context,
+        NestedTlbFlush::Context(eptp) => Some(eptp),
+        NestedTlbFlush::All => None,
+    };
+    let (kind, eptp) = match context {
+        Some(eptp) if ept_vpid_cap().get_bit(25) => (INVEPT_SINGLE_CONTEXT, eptp),
+        _ => (INVEPT_ALL_CONTEXT, 0),
+    };
+    let descriptor: [u64; 2] = [eptp, 0];
+    // SAFETY: INVEPT only invalidates cached translations.
+    unsafe {
+        asm!("invept {0}, [{1}]", in(reg) kind, in(reg) &descriptor, options(nostack));
+    }
+}
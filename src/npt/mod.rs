@@ -1,14 +1,207 @@
+use core::fmt;
+
+use page_table_entry::{GenericPTE, MappingFlags};
+use page_table_multiarch::PageSize;
+
+use crate::{GuestPhysAddr, HostPhysAddr};
+
+mod arch;
+mod table;
+mod vmid;
+
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+pub(crate) use arch::eptp;
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+pub use arch::{Cacheability, Stage2Attr, Stage2Config, Stage2Exec, Stage2Format, Stage2MemAttr};
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+pub use arch::{EptConfig, EptFormat, EptMemAttr, EptMemType, EptWalkLength};
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+pub use arch::{GStageFormat, GStageMode};
+
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
-        /// The architecture-specific nested page table for two-stage address translation.
-        pub type NestedPageTable<H> = arch::ExtendedPageTable<H>;
+        /// The nested page table format of the current architecture.
+        pub type NativeFormat = EptFormat;
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
-        /// The architecture-specific page table.
-        pub type NestedPageTable<H> = arch::NestedPageTable<H>;
-    } else if #[cfg(target_arch = "aarch64")]{
-        /// The architecture-specific nested page table for two-stage address translation.
-        pub type NestedPageTable<H> = arch::NestedPageTable<H>;
+        /// The nested page table format of the current architecture.
+        pub type NativeFormat = GStageFormat;
+    } else if #[cfg(target_arch = "aarch64")] {
+        /// The nested page table format of the current architecture.
+        pub type NativeFormat = Stage2Format;
     }
 }
 
-mod arch;
+/// The configuration of the nested page table of an address space.
+pub type NestedConfig = <NativeFormat as NestedFormat>::Config;
+/// The memory attribute of a mapping, selected independently from its
+/// [`MappingFlags`].
+pub type NestedMemAttr = <NativeFormat as NestedFormat>::MemAttr;
+
+pub(crate) use table::RootAllocator;
+pub use table::{NestedPageTable64, TableGeometry};
+pub(crate) use vmid::VMID_ALLOCATOR;
+
+/// The nested page table of the format `F` for two-stage address translation.
+pub type NestedPageTable<H, F = NativeFormat> = NestedPageTable64<<F as NestedFormat>::PTE, H>;
+
+/// An invalidation of the TLB entries caching nested translations, see
+/// [`AxMmHal::flush_nested_tlb`](crate::AxMmHal::flush_nested_tlb).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NestedTlbFlush {
+    /// Invalidates the translations of the page at `gpa`.
+    ///
+    /// Architectures without invalidation by address invalidate the whole
+    /// context instead.
+    Page {
+        /// The context of the nested page table, or `None` for all of them.
+        context: Option<u64>,
+        /// The guest physical address of the page.
+        gpa: GuestPhysAddr,
+    },
+    /// Invalidates the translations of one nested page table, identified by
+    /// its context (the EPTP on x86_64, the VMID on AArch64 and RISC-V).
+    Context(u64),
+    /// Invalidates the translations of all nested page tables.
+    All,
+}
+
+/// A format of nested page tables: the entries of the tables, their
+/// configuration, and the maintenance of the TLB entries caching them.
+///
+/// The format of the current architecture is [`NativeFormat`]. The formats of
+/// the other architectures are compiled with the `ept`, `stage2` and `gstage`
+/// features, so that their encodings can be tested on any host. The hardware
+/// they would query is then replaced by fixed capabilities, and their TLB
+/// invalidations do nothing unless recorded by the host-side software mode.
+pub trait NestedFormat: 'static {
+    /// The entries of the nested page tables.
+    type PTE: DirtyState + MemAttrState<MemAttr = Self::MemAttr>;
+    /// The configuration of the nested page table of an address space.
+    type Config: Copy + Default + fmt::Debug;
+    /// The memory attribute of a mapping, selected independently from its
+    /// [`MappingFlags`].
+    type MemAttr: Copy + fmt::Debug;
+
+    /// Returns the geometry of the nested page table of `config`.
+    fn geometry(config: &Self::Config) -> TableGeometry;
+
+    /// Returns whether the hardware supports `config`.
+    fn config_supported(config: &Self::Config) -> bool {
+        let _ = config;
+        true
+    }
+
+    /// Returns whether `config` enables the hardware dirty state, which is
+    /// then used for dirty page logging.
+    fn config_hw_dirty(config: &Self::Config) -> bool {
+        let _ = config;
+        false
+    }
+
+    /// Returns whether the CPU records writes in the entries, so that their
+    /// hardware dirty state can be used for dirty page logging.
+    fn hw_dirty_supported() -> bool {
+        Self::PTE::HW_DIRTY
+    }
+
+    /// Returns whether the CPU supports leaf entries mapping pages of
+    /// `page_size`, when the geometry of the table has a level for them.
+    fn huge_page_supported(page_size: PageSize) -> bool {
+        let _ = page_size;
+        true
+    }
+
+    /// Returns whether the entries can encode the mapping `flags`.
+    fn flags_supported(flags: MappingFlags) -> bool {
+        let _ = flags;
+        true
+    }
+
+    /// Returns whether the entries can encode the memory attribute
+    /// `mem_attr` with the mapping `flags`.
+    fn mem_attr_supported(mem_attr: Self::MemAttr, flags: MappingFlags) -> bool {
+        let _ = (mem_attr, flags);
+        true
+    }
+
+    /// Returns the context identifying the nested page table rooted at `root`
+    /// with `geometry`, tagged with `vmid`, in targeted invalidations, if the
+    /// format has one for it. The default implementation returns the VMID.
+    fn tlb_context(root: HostPhysAddr, geometry: TableGeometry, vmid: Option<u16>) -> Option<u64> {
+        let _ = (root, geometry);
+        vmid.map(u64::from)
+    }
+
+    /// Executes the TLB invalidation `flush` on the current CPU.
+    ///
+    /// It is recorded instead in the host-side software mode (the
+    /// `axaddrspace_host` cfg), and does nothing for the formats of other
+    /// architectures.
+    fn flush_tlb(flush: NestedTlbFlush);
+}
+
+/// Executes the TLB invalidation `flush` of the native format on the current
+/// CPU, see [`NestedFormat::flush_tlb`].
+pub(crate) fn flush_nested_tlb(flush: NestedTlbFlush) {
+    NativeFormat::flush_tlb(flush)
+}
+
+/// Returns the width of the VMIDs supported by the current CPU, or `0` if
+/// the nested translations are not tagged by VMIDs.
+///
+/// The width of the RISC-V VMIDs can only be probed by writing `hgatp`, it
+/// is left to [`AxMmHal::vmid_bits`](crate::AxMmHal::vmid_bits).
+pub(crate) fn vmid_bits() -> usize {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "aarch64")] {
+            if arch::vmid_16bit_supported() { 16 } else { 8 }
+        } else {
+            0
+        }
+    }
+}
+
+/// Hardware-managed dirty state of the nested page table entries, used for
+/// dirty page logging.
+///
+/// Formats without hardware dirty state keep the default implementation, and
+/// dirty pages are tracked by write-protecting them instead.
+pub trait DirtyState: GenericPTE {
+    /// Whether the hardware records writes in the entries.
+    const HW_DIRTY: bool = false;
+
+    /// Starts recording writes through a leaf entry, which is marked clean.
+    fn start_dirty_tracking(&mut self) {}
+
+    /// Stops recording writes through a leaf entry, restoring its permissions.
+    fn stop_dirty_tracking(&mut self) {}
+
+    /// Whether a write through the leaf entry has been recorded.
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    /// Marks a leaf entry clean.
+    fn clear_dirty(&mut self) {}
+
+    /// Marks a leaf entry dirty, for writes done by the hypervisor itself.
+    fn set_dirty(&mut self) {}
+}
+
+/// Memory attributes of the nested page table entries, which can be selected
+/// independently from the mapping flags.
+///
+/// The memory attributes of an entry are kept when its permissions are
+/// changed by [`NestedPageTable64::protect`].
+pub trait MemAttrState: GenericPTE {
+    /// The memory attribute of a leaf entry.
+    type MemAttr: Copy + fmt::Debug;
+
+    /// Returns the memory attribute of a leaf entry, or `None` if it is not
+    /// a valid encoding.
+    fn mem_attr(&self) -> Option<Self::MemAttr>;
+
+    /// Replaces the memory attribute of a leaf entry, which is derived from
+    /// the mapping flags when the entry is created.
+    fn set_mem_attr(&mut self, mem_attr: Self::MemAttr);
+}
@@ -0,0 +1,665 @@
+use core::marker::PhantomData;
+
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr};
+use page_table_entry::{GenericPTE, MappingFlags};
+use page_table_multiarch::{PageSize, PagingError, PagingHandler, PagingResult};
+
+use super::MemAttrState;
+use crate::{AxMmHal, GuestPhysAddr};
+
+/// The number of entries of a 4K table.
+const ENTRY_COUNT: usize = 512;
+
+/// The shape of a nested page table.
+///
+/// The root table may be smaller than a 4K table, or made of several
+/// concatenated 4K tables indexed by the extra bits of the guest physical
+/// address, as the RISC-V G-stage and the AArch64 stage-2 translation allow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableGeometry {
+    /// The number of levels of the table walk.
+    pub levels: usize,
+    /// The number of bits of the guest physical addresses translated by the
+    /// table.
+    pub input_bits: usize,
+}
+
+impl TableGeometry {
+    /// Returns the number of bits of the guest physical addresses translated
+    /// by the last `levels` levels of the walk.
+    const fn bits_of_levels(levels: usize) -> usize {
+        12 + 9 * levels
+    }
+
+    /// Returns whether the root table spans at most 16 concatenated 4K tables
+    /// and resolves at least one bit of the address.
+    pub const fn is_valid(&self) -> bool {
+        self.levels > 0
+            && self.input_bits > Self::bits_of_levels(self.levels - 1)
+            && self.input_bits <= Self::bits_of_levels(self.levels) + 4
+    }
+
+    /// Returns the number of concatenated 4K tables at the root level.
+    pub const fn root_tables(&self) -> usize {
+        1 << self
+            .input_bits
+            .saturating_sub(Self::bits_of_levels(self.levels))
+    }
+
+    /// Returns the number of entries of the root table.
+    const fn root_entries(&self) -> usize {
+        1 << (self.input_bits - Self::bits_of_levels(self.levels - 1))
+    }
+
+    /// Returns the level (`0` for the root) of the leaf entries mapping pages
+    /// of `page_size`, if the walk has such a level.
+    pub fn leaf_level(&self, page_size: PageSize) -> Option<usize> {
+        (0..self.levels)
+            .find(|&depth| level_page_size(depth) == Some(page_size))
+            .map(|depth| self.levels - 1 - depth)
+    }
+
+    /// Returns the index of the entry of `level` (`0` for the root) that
+    /// translates `vaddr`.
+    const fn index(&self, level: usize, vaddr: usize) -> usize {
+        let shift = Self::bits_of_levels(self.levels - 1 - level);
+        let entries = if level == 0 {
+            self.root_entries()
+        } else {
+            ENTRY_COUNT
+        };
+        (vaddr >> shift) & (entries - 1)
+    }
+}
+
+/// Returns the size of the page mapped by a leaf entry `depth` levels above
+/// the last level, if pages can be mapped there.
+const fn level_page_size(depth: usize) -> Option<PageSize> {
+    match depth {
+        0 => Some(PageSize::Size4K),
+        1 => Some(PageSize::Size2M),
+        2 => Some(PageSize::Size1G),
+        _ => None,
+    }
+}
+
+/// The allocator of the frames of the root table.
+#[derive(Clone, Copy)]
+pub(crate) struct RootAllocator {
+    alloc: fn(usize, usize) -> Option<PhysAddr>,
+    dealloc: fn(PhysAddr, usize),
+}
+
+impl RootAllocator {
+    /// Creates an allocator of single-frame root tables from the
+    /// [`PagingHandler`] implementation `H`, which cannot allocate
+    /// concatenated ones.
+    pub fn single<H: PagingHandler>() -> Self {
+        Self {
+            alloc: |num_frames, _| {
+                if num_frames == 1 {
+                    H::alloc_frame()
+                } else {
+                    None
+                }
+            },
+            dealloc: |paddr, _| H::dealloc_frame(paddr),
+        }
+    }
+
+    /// Creates an allocator of concatenated root tables from the
+    /// [`AxMmHal`] implementation `H`.
+    ///
+    /// Single-frame root tables are allocated with [`AxMmHal::alloc_frame`],
+    /// so that it also works with the HALs without contiguous allocation.
+    pub fn contiguous<H: AxMmHal>() -> Self {
+        Self {
+            alloc: |num_frames, align| {
+                if num_frames == 1 {
+                    H::alloc_frame()
+                } else {
+                    H::alloc_contiguous_frames(num_frames, align)
+                }
+            },
+            dealloc: |paddr, num_frames| {
+                if num_frames == 1 {
+                    H::dealloc_frame(paddr)
+                } else {
+                    H::dealloc_contiguous_frames(paddr, num_frames)
+                }
+            },
+        }
+    }
+}
+
+/// A nested page table with a geometry chosen at runtime.
+///
+/// Unlike [`PageTable64`](page_table_multiarch::PageTable64), it never
+/// invalidates the TLB entries itself, the owner of the table has to do it
+/// once its changes are done. All the intermediate tables are deallocated when
+/// it is dropped.
+pub struct NestedPageTable64<PTE: GenericPTE, H: PagingHandler> {
+    root_paddr: PhysAddr,
+    geometry: TableGeometry,
+    root_alloc: RootAllocator,
+    huge_pages: fn(PageSize) -> bool,
+    _phantom: PhantomData<(PTE, H)>,
+}
+
+impl<PTE: MemAttrState, H: PagingHandler> NestedPageTable64<PTE, H> {
+    /// Creates a new page table of `geometry`, whose root table is allocated
+    /// by `root_alloc`. `huge_pages` returns whether the CPU supports the huge
+    /// pages of a size, see
+    /// [`NestedFormat::huge_page_supported`](super::NestedFormat::huge_page_supported).
+    pub(crate) fn try_new(
+        geometry: TableGeometry,
+        root_alloc: RootAllocator,
+        huge_pages: fn(PageSize) -> bool,
+    ) -> PagingResult<Self> {
+        debug_assert!(geometry.is_valid());
+        let size = geometry.root_tables() * PAGE_SIZE_4K;
+        let root_paddr =
+            (root_alloc.alloc)(geometry.root_tables(), size).ok_or(PagingError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(H::phys_to_virt(root_paddr).as_mut_ptr(), 0, size) };
+        Ok(Self {
+            root_paddr,
+            geometry,
+            root_alloc,
+            huge_pages,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Returns the physical address of the root page table.
+    pub const fn root_paddr(&self) -> PhysAddr {
+        self.root_paddr
+    }
+
+    /// Returns the geometry of the page table.
+    pub const fn geometry(&self) -> TableGeometry {
+        self.geometry
+    }
+
+    /// Returns whether pages of `page_size` can be mapped by the table, i.e.,
+    /// its geometry has a level for them and the CPU supports them.
+    pub fn huge_page_supported(&self, page_size: PageSize) -> bool {
+        self.geometry.leaf_level(page_size).is_some() && (self.huge_pages)(page_size)
+    }
+
+    /// Returns the allocator of the root table, to create a table of the same
+    /// geometry.
+    pub(crate) const fn root_allocator(&self) -> RootAllocator {
+        self.root_alloc
+    }
+
+    /// Maps a page to a physical frame with the given `page_size` and mapping
+    /// `flags`.
+    ///
+    /// The page starts with `vaddr`, and the physical frame starts with
+    /// `target`. If the `target` is not aligned to the `page_size`, it will be
+    /// aligned down automatically.
+    ///
+    /// Returns [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped)
+    /// if the mapping is already present.
+    pub fn map(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        self.map_with_attr(vaddr, target, page_size, flags, None)
+    }
+
+    /// Maps a page like [`NestedPageTable64::map`], with the memory attribute
+    /// `mem_attr` instead of the one derived from `flags` if it is given.
+    pub fn map_with_attr(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+        mem_attr: Option<PTE::MemAttr>,
+    ) -> PagingResult {
+        let entry = self.get_entry_mut_or_create(vaddr, page_size)?;
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        let mut new_entry: PTE =
+            GenericPTE::new_page(target.align_down(page_size), flags, page_size.is_huge());
+        if let Some(mem_attr) = mem_attr {
+            new_entry.set_mem_attr(mem_attr);
+        }
+        *entry = new_entry;
+        Ok(())
+    }
+
+    /// Remaps the mapping starts with `vaddr`, updates both the physical
+    /// address and flags.
+    ///
+    /// Returns the page size of the mapping.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// intermediate level tables of the mapping is not present.
+    pub fn remap(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<PageSize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        entry.set_paddr(paddr);
+        entry.set_flags(flags, size.is_huge());
+        Ok(size)
+    }
+
+    /// Updates the flags of the mapping starts with `vaddr`.
+    ///
+    /// The memory attribute of the mapping is kept, only its permissions are
+    /// updated.
+    ///
+    /// Returns the page size of the mapping.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn protect(&mut self, vaddr: GuestPhysAddr, flags: MappingFlags) -> PagingResult<PageSize> {
+        self.protect_with_attr(vaddr, flags, None)
+    }
+
+    /// Updates the flags of the mapping starts with `vaddr`, which gets the
+    /// memory attribute `mem_attr` if it is given, or keeps its own one.
+    fn protect_with_attr(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        flags: MappingFlags,
+        mem_attr: Option<PTE::MemAttr>,
+    ) -> PagingResult<PageSize> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        let mem_attr = mem_attr.or_else(|| entry.mem_attr());
+        entry.set_flags(flags, size.is_huge());
+        if let Some(mem_attr) = mem_attr {
+            entry.set_mem_attr(mem_attr);
+        }
+        Ok(size)
+    }
+
+    /// Unmaps the mapping starts with `vaddr`.
+    ///
+    /// Returns the physical address of the target frame and the page size.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn unmap(&mut self, vaddr: GuestPhysAddr) -> PagingResult<(PhysAddr, PageSize)> {
+        let (entry, size) = self.get_entry_mut(vaddr)?;
+        if !entry.is_present() {
+            entry.clear();
+            return Err(PagingError::NotMapped);
+        }
+        let paddr = entry.paddr();
+        entry.clear();
+        Ok((paddr, size))
+    }
+
+    /// Queries the result of the mapping starts with `vaddr`.
+    ///
+    /// Returns the physical address of the target frame, mapping flags, and
+    /// the page size.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn query(&self, vaddr: GuestPhysAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        let (entry, size) = self.get_entry(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        let off = size.align_offset(vaddr.as_usize());
+        Ok((entry.paddr() + off, entry.flags(), size))
+    }
+
+    /// Returns the memory attribute of the mapping starts with `vaddr`, or
+    /// `None` if it is not a valid encoding.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn query_mem_attr(&self, vaddr: GuestPhysAddr) -> PagingResult<Option<PTE::MemAttr>> {
+        let (entry, _) = self.get_entry(vaddr)?;
+        if !entry.is_present() {
+            return Err(PagingError::NotMapped);
+        }
+        Ok(entry.mem_attr())
+    }
+
+    /// Maps a contiguous region of guest physical memory to a contiguous
+    /// region of host physical memory with the given mapping `flags`.
+    ///
+    /// The regions start with `vaddr` and `get_paddr(vaddr)` respectively, and
+    /// `size` must be aligned to 4K. When `allow_huge` is true, the region is
+    /// mapped with huge pages wherever both addresses are aligned, if the
+    /// table supports them (see [`NestedPageTable64::huge_page_supported`]),
+    /// and with smaller pages otherwise. The pages
+    /// have the memory attribute `mem_attr` if it is given, see
+    /// [`NestedPageTable64::map_with_attr`].
+    pub fn map_region(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        get_paddr: impl Fn(GuestPhysAddr) -> PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        allow_huge: bool,
+        mem_attr: Option<PTE::MemAttr>,
+    ) -> PagingResult {
+        if !vaddr.is_aligned_4k() || !PageSize::Size4K.is_aligned(size) {
+            return Err(PagingError::NotAligned);
+        }
+        trace!(
+            "map_region({:#x}): [{:#x}, {:#x}) {:?}",
+            self.root_paddr,
+            vaddr,
+            vaddr + size,
+            flags,
+        );
+        let end = vaddr + size;
+        let mut vaddr = vaddr;
+        while vaddr < end {
+            let paddr = get_paddr(vaddr);
+            let page_size = [PageSize::Size1G, PageSize::Size2M]
+                .into_iter()
+                .find(|&page_size| {
+                    allow_huge
+                        && self.huge_page_supported(page_size)
+                        && vaddr.is_aligned(page_size)
+                        && paddr.is_aligned(page_size)
+                        && end - vaddr >= page_size as usize
+                })
+                .unwrap_or(PageSize::Size4K);
+            self.map_with_attr(vaddr, paddr, page_size, flags, mem_attr)
+                .inspect_err(|e| {
+                    error!("failed to map page: {vaddr:#x?}({page_size:?}) -> {paddr:#x?}, {e:?}")
+                })?;
+            vaddr += page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Unmaps a contiguous region, which must be mapped and must not split
+    /// huge pages.
+    pub fn unmap_region(&mut self, vaddr: GuestPhysAddr, size: usize) -> PagingResult {
+        trace!(
+            "unmap_region({:#x}) [{:#x}, {:#x})",
+            self.root_paddr,
+            vaddr,
+            vaddr + size,
+        );
+        let end = vaddr + size;
+        let mut vaddr = vaddr;
+        while vaddr < end {
+            let (_, page_size) = self
+                .unmap(vaddr)
+                .inspect_err(|e| error!("failed to unmap page: {vaddr:#x?}, {e:?}"))?;
+            vaddr += page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Updates the mapping flags of a contiguous region, which must be mapped
+    /// and must not split huge pages. The pages keep their memory attribute,
+    /// or get `mem_attr` if it is given.
+    pub fn protect_region(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        mem_attr: Option<PTE::MemAttr>,
+    ) -> PagingResult {
+        trace!(
+            "protect_region({:#x}) [{:#x}, {:#x}) {:?}",
+            self.root_paddr,
+            vaddr,
+            vaddr + size,
+            flags,
+        );
+        let end = vaddr + size;
+        let mut vaddr = vaddr;
+        while vaddr < end {
+            let page_size = self
+                .protect_with_attr(vaddr, flags, mem_attr)
+                .inspect_err(|e| error!("failed to protect page: {vaddr:#x?}, {e:?}"))?;
+            vaddr += page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Returns the present leaf entry that maps `vaddr` and the size of its
+    /// page.
+    ///
+    /// Unlike [`NestedPageTable64::query`], the entry itself is returned so
+    /// that the bits not covered by [`GenericPTE`] can be updated.
+    pub(crate) fn leaf_entry_mut(&mut self, vaddr: GuestPhysAddr) -> Option<(&mut PTE, PageSize)> {
+        let (entry, size) = self.get_entry_mut(vaddr).ok()?;
+        entry.is_present().then_some((entry, size))
+    }
+}
+
+// Private implements.
+impl<PTE: GenericPTE, H: PagingHandler> NestedPageTable64<PTE, H> {
+    fn alloc_table() -> PagingResult<PhysAddr> {
+        let paddr = H::alloc_frame().ok_or(PagingError::NoMemory)?;
+        unsafe { core::ptr::write_bytes(H::phys_to_virt(paddr).as_mut_ptr(), 0, PAGE_SIZE_4K) };
+        Ok(paddr)
+    }
+
+    /// Returns the table of `entries` entries at `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// The table must belong to the page table and have at least `entries`
+    /// entries. The returned lifetime must be tied to a shared borrow of the
+    /// page table or of the entry pointing to the table.
+    unsafe fn table_of<'a>(paddr: PhysAddr, entries: usize) -> &'a [PTE] {
+        let ptr = H::phys_to_virt(paddr).as_ptr().cast();
+        unsafe { core::slice::from_raw_parts(ptr, entries) }
+    }
+
+    /// Returns the table of `entries` entries at `paddr` for writing.
+    ///
+    /// # Safety
+    ///
+    /// The table must belong to the page table and have at least `entries`
+    /// entries. The returned lifetime must be tied to a mutable borrow of the
+    /// page table or of the entry pointing to the table, so that the table is
+    /// not aliased.
+    unsafe fn table_of_mut<'a>(paddr: PhysAddr, entries: usize) -> &'a mut [PTE] {
+        let ptr = H::phys_to_virt(paddr).as_mut_ptr().cast();
+        unsafe { core::slice::from_raw_parts_mut(ptr, entries) }
+    }
+
+    fn root_table(&self) -> &[PTE] {
+        // SAFETY: the root table is owned by the page table, borrowed here.
+        unsafe { Self::table_of(self.root_paddr, self.geometry.root_entries()) }
+    }
+
+    fn root_table_mut(&mut self) -> &mut [PTE] {
+        // SAFETY: the root table is owned by the page table, mutably borrowed
+        // here.
+        unsafe { Self::table_of_mut(self.root_paddr, self.geometry.root_entries()) }
+    }
+
+    /// Returns the address of the table `entry` points to.
+    fn next_table_paddr(entry: &PTE) -> PagingResult<PhysAddr> {
+        if entry.paddr().as_usize() == 0 {
+            Err(PagingError::NotMapped)
+        } else if entry.is_huge() {
+            Err(PagingError::MappedToHugePage)
+        } else {
+            Ok(entry.paddr())
+        }
+    }
+
+    fn next_table(entry: &PTE) -> PagingResult<&[PTE]> {
+        let paddr = Self::next_table_paddr(entry)?;
+        // SAFETY: the entry of the page table points to one of its tables,
+        // borrowed with the entry.
+        Ok(unsafe { Self::table_of(paddr, ENTRY_COUNT) })
+    }
+
+    fn next_table_mut(entry: &mut PTE) -> PagingResult<&mut [PTE]> {
+        let paddr = Self::next_table_paddr(entry)?;
+        // SAFETY: the entry of the page table points to one of its tables,
+        // mutably borrowed with the entry.
+        Ok(unsafe { Self::table_of_mut(paddr, ENTRY_COUNT) })
+    }
+
+    fn next_table_or_create(entry: &mut PTE) -> PagingResult<&mut [PTE]> {
+        if entry.is_unused() {
+            let paddr = Self::alloc_table()?;
+            *entry = GenericPTE::new_table(paddr);
+            // SAFETY: the new table now belongs to the page table, mutably
+            // borrowed with the entry pointing to it.
+            Ok(unsafe { Self::table_of_mut(paddr, ENTRY_COUNT) })
+        } else {
+            Self::next_table_mut(entry)
+        }
+    }
+
+    fn check_range(&self, vaddr: usize) -> PagingResult {
+        if vaddr >> self.geometry.input_bits != 0 {
+            return Err(PagingError::NotMapped);
+        }
+        Ok(())
+    }
+
+    /// Returns the entry translating `vaddr`, which is either a leaf entry of
+    /// a huge page or an entry of the last level.
+    fn get_entry(&self, vaddr: GuestPhysAddr) -> PagingResult<(&PTE, PageSize)> {
+        let vaddr = vaddr.as_usize();
+        self.check_range(vaddr)?;
+        let geometry = self.geometry;
+        let mut table = self.root_table();
+        for level in 0..geometry.levels {
+            let entry = &table[geometry.index(level, vaddr)];
+            let depth = geometry.levels - 1 - level;
+            if depth == 0 {
+                return Ok((entry, PageSize::Size4K));
+            }
+            if entry.is_huge() {
+                return level_page_size(depth)
+                    .map(|size| (entry, size))
+                    .ok_or(PagingError::NotMapped);
+            }
+            table = Self::next_table(entry)?;
+        }
+        unreachable!()
+    }
+
+    /// Returns the entry translating `vaddr` like
+    /// [`NestedPageTable64::get_entry`], for updating it.
+    fn get_entry_mut(&mut self, vaddr: GuestPhysAddr) -> PagingResult<(&mut PTE, PageSize)> {
+        let vaddr = vaddr.as_usize();
+        self.check_range(vaddr)?;
+        let geometry = self.geometry;
+        let mut table = self.root_table_mut();
+        for level in 0..geometry.levels {
+            let entry = &mut table[geometry.index(level, vaddr)];
+            let depth = geometry.levels - 1 - level;
+            if depth == 0 {
+                return Ok((entry, PageSize::Size4K));
+            }
+            if entry.is_huge() {
+                return level_page_size(depth)
+                    .map(|size| (entry, size))
+                    .ok_or(PagingError::NotMapped);
+            }
+            table = Self::next_table_mut(entry)?;
+        }
+        unreachable!()
+    }
+
+    fn get_entry_mut_or_create(
+        &mut self,
+        vaddr: GuestPhysAddr,
+        page_size: PageSize,
+    ) -> PagingResult<&mut PTE> {
+        let vaddr = vaddr.as_usize();
+        self.check_range(vaddr)?;
+        let geometry = self.geometry;
+        // There may be no level for huge pages in short walks.
+        let leaf_level = geometry
+            .leaf_level(page_size)
+            .ok_or(PagingError::NotAligned)?;
+        let mut table = self.root_table_mut();
+        for level in 0..leaf_level {
+            table = Self::next_table_or_create(&mut table[geometry.index(level, vaddr)])?;
+        }
+        Ok(&mut table[geometry.index(leaf_level, vaddr)])
+    }
+
+    fn dealloc_tree(table: &[PTE], level: usize, levels: usize) {
+        // Entries of the last level are never tables.
+        if level == levels - 1 {
+            return;
+        }
+        for entry in table {
+            if let Ok(next) = Self::next_table(entry) {
+                Self::dealloc_tree(next, level + 1, levels);
+                H::dealloc_frame(entry.paddr());
+            }
+        }
+    }
+}
+
+impl<PTE: GenericPTE, H: PagingHandler> Drop for NestedPageTable64<PTE, H> {
+    fn drop(&mut self) {
+        Self::dealloc_tree(self.root_table(), 0, self.geometry.levels);
+        (self.root_alloc.dealloc)(self.root_paddr, self.geometry.root_tables());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use memory_addr::PhysAddr;
+    use page_table_entry::MappingFlags;
+    use page_table_multiarch::PageSize;
+
+    use super::RootAllocator;
+    use crate::GuestPhysAddr;
+    use crate::host::HostHal;
+    use crate::npt::{NativeFormat, NestedFormat, NestedPageTable};
+
+    fn map_1g(huge_pages: fn(PageSize) -> bool) -> PageSize {
+        let geometry = NativeFormat::geometry(&Default::default());
+        let mut pt = NestedPageTable::<HostHal>::try_new(
+            geometry,
+            RootAllocator::single::<HostHal>(),
+            huge_pages,
+        )
+        .unwrap();
+        let gpa = GuestPhysAddr::from_usize(0x4000_0000);
+        let flags = MappingFlags::READ | MappingFlags::WRITE;
+        pt.map_region(
+            gpa,
+            |gpa| PhysAddr::from(gpa.as_usize() * 2),
+            0x4000_0000,
+            flags,
+            true,
+            None,
+        )
+        .unwrap();
+        pt.query(gpa + 0x20_0000).unwrap().2
+    }
+
+    #[test]
+    fn unsupported_huge_pages_fall_back() {
+        assert_eq!(map_1g(|_| true), PageSize::Size1G);
+        assert_eq!(
+            map_1g(|page_size| page_size != PageSize::Size1G),
+            PageSize::Size2M
+        );
+        assert_eq!(
+            map_1g(|page_size| page_size == PageSize::Size4K),
+            PageSize::Size4K
+        );
+    }
+}
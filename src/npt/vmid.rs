@@ -0,0 +1,232 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use axerrno::{AxResult, ax_err};
+
+use super::NestedTlbFlush;
+
+/// The maximum width of the VMIDs, in bits.
+const MAX_VMID_BITS: usize = 16;
+const BITMAP_WORDS: usize = (1 << MAX_VMID_BITS) / 64;
+/// The width of the VMIDs before it is known.
+const UNKNOWN_BITS: usize = usize::MAX;
+
+/// The allocator of the VMIDs tagging the nested translations in the TLB.
+///
+/// VMIDs are allocated cyclically and are not reused as soon as they are
+/// freed, since the TLB may still cache translations tagged with them. They
+/// are only recycled when the allocation wraps around, which starts a new
+/// generation: the translations of all VMIDs are invalidated at once, and the
+/// VMIDs freed during the previous generation become available again.
+///
+/// VMID 0 is never allocated.
+pub(crate) struct VmidAllocator {
+    lock: AtomicBool,
+    bits: AtomicUsize,
+    next: AtomicUsize,
+    generation: AtomicU64,
+    /// VMIDs currently owned by an address space, or freed but not recycled.
+    used: [AtomicU64; BITMAP_WORDS],
+    /// VMIDs freed during the current generation.
+    retired: [AtomicU64; BITMAP_WORDS],
+}
+
+/// The VMID allocator shared by all the address spaces.
+pub(crate) static VMID_ALLOCATOR: VmidAllocator = VmidAllocator::new();
+
+impl VmidAllocator {
+    const fn new() -> Self {
+        Self {
+            lock: AtomicBool::new(false),
+            bits: AtomicUsize::new(UNKNOWN_BITS),
+            next: AtomicUsize::new(1),
+            generation: AtomicU64::new(0),
+            used: [const { AtomicU64::new(0) }; BITMAP_WORDS],
+            retired: [const { AtomicU64::new(0) }; BITMAP_WORDS],
+        }
+    }
+
+    /// Allocates a VMID.
+    ///
+    /// The width of the VMIDs is given by `bits`, and is capped at 16 bits.
+    /// It is fixed by the first allocation of a VMID, and the later requests
+    /// must give the same width. `flush` invalidates the translations of all
+    /// VMIDs when a new generation starts, it must do so on all the CPUs.
+    ///
+    /// Returns `None` if `bits` is `0`, i.e., the translations are not tagged
+    /// by VMIDs. Returns an error if the width differs from the one of the
+    /// VMIDs already allocated, or if all the VMIDs are owned by address
+    /// spaces.
+    pub fn alloc(
+        &self,
+        bits: impl FnOnce() -> usize,
+        flush: fn(NestedTlbFlush),
+    ) -> AxResult<Option<u16>> {
+        self.lock();
+        let ret = self.alloc_locked(bits, flush);
+        self.unlock();
+        ret
+    }
+
+    /// Frees a VMID allocated by [`VmidAllocator::alloc`].
+    ///
+    /// It can be allocated again from the next generation.
+    pub fn free(&self, vmid: u16) {
+        let (word, bit) = Self::position(vmid as usize);
+        self.retired[word].fetch_or(bit, Ordering::Release);
+    }
+
+    /// Returns the generation of the allocator, which is incremented each
+    /// time the VMIDs are recycled.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    fn alloc_locked(
+        &self,
+        bits: impl FnOnce() -> usize,
+        flush: fn(NestedTlbFlush),
+    ) -> AxResult<Option<u16>> {
+        let width = bits().min(MAX_VMID_BITS);
+        if width == 0 {
+            // Untagged translations do not fix the width.
+            return Ok(None);
+        }
+        match self.bits.load(Ordering::Relaxed) {
+            UNKNOWN_BITS => self.bits.store(width, Ordering::Relaxed),
+            bits if bits != width => {
+                return ax_err!(InvalidInput, "VMID width differs from the allocated VMIDs");
+            }
+            _ => {}
+        }
+
+        let count = 1 << width;
+        // Each VMID is visited once in each of the two generations at most.
+        for _ in 0..2 * count {
+            let vmid = self.next.load(Ordering::Relaxed);
+            if vmid == count {
+                self.rollover(flush);
+                continue;
+            }
+            self.next.store(vmid + 1, Ordering::Relaxed);
+            let (word, bit) = Self::position(vmid);
+            if self.used[word].fetch_or(bit, Ordering::Acquire) & bit == 0 {
+                return Ok(Some(vmid as u16));
+            }
+        }
+        ax_err!(NoMemory, "no VMID available")
+    }
+
+    /// Starts a new generation, recycling the VMIDs freed during the previous
+    /// one once their translations are invalidated.
+    fn rollover(&self, flush: fn(NestedTlbFlush)) {
+        flush(NestedTlbFlush::All);
+        for (used, retired) in self.used.iter().zip(&self.retired) {
+            let freed = retired.swap(0, Ordering::Acquire);
+            used.fetch_and(!freed, Ordering::Release);
+        }
+        self.next.store(1, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        debug!("VMID generation {} started", self.generation());
+    }
+
+    const fn position(vmid: usize) -> (usize, u64) {
+        (vmid / 64, 1 << (vmid % 64))
+    }
+
+    fn lock(&self) {
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.lock.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axerrno::AxError;
+
+    use super::*;
+    use crate::host::{record_tlb_flush, take_tlb_flushes};
+
+    fn alloc(allocator: &VmidAllocator, bits: usize) -> AxResult<Option<u16>> {
+        allocator.alloc(|| bits, record_tlb_flush)
+    }
+
+    #[test]
+    fn no_vmids() {
+        let allocator = VmidAllocator::new();
+        assert_eq!(alloc(&allocator, 0), Ok(None));
+        assert_eq!(alloc(&allocator, 0), Ok(None));
+    }
+
+    #[test]
+    fn width_is_fixed_by_the_first_vmid() {
+        let allocator = VmidAllocator::new();
+        // Untagged translations do not fix the width.
+        assert_eq!(alloc(&allocator, 0), Ok(None));
+        assert_eq!(alloc(&allocator, 8), Ok(Some(1)));
+        assert_eq!(alloc(&allocator, 0), Ok(None));
+        assert_eq!(alloc(&allocator, 8), Ok(Some(2)));
+        assert_eq!(alloc(&allocator, 16), Err(AxError::InvalidInput));
+        // The widths are compared once capped.
+        assert_eq!(alloc(&allocator, 20), Err(AxError::InvalidInput));
+    }
+
+    #[test]
+    fn width_is_capped() {
+        take_tlb_flushes();
+        let allocator = VmidAllocator::new();
+        for vmid in 1..=u16::MAX {
+            assert_eq!(alloc(&allocator, 20), Ok(Some(vmid)));
+        }
+        assert!(take_tlb_flushes().is_empty());
+        assert_eq!(alloc(&allocator, 20), Err(AxError::NoMemory));
+    }
+
+    #[test]
+    fn rollover_recycles_freed_vmids() {
+        take_tlb_flushes();
+        let allocator = VmidAllocator::new();
+        for vmid in 1..=3 {
+            assert_eq!(alloc(&allocator, 2), Ok(Some(vmid)));
+        }
+        assert_eq!(allocator.generation(), 0);
+        assert!(take_tlb_flushes().is_empty());
+
+        // Freed VMIDs are only reused in the next generation, once all the
+        // translations are invalidated.
+        allocator.free(2);
+        assert_eq!(alloc(&allocator, 2), Ok(Some(2)));
+        assert_eq!(allocator.generation(), 1);
+        assert_eq!(take_tlb_flushes(), [NestedTlbFlush::All]);
+
+        allocator.free(3);
+        allocator.free(1);
+        assert_eq!(alloc(&allocator, 2), Ok(Some(1)));
+        assert_eq!(allocator.generation(), 2);
+        assert_eq!(alloc(&allocator, 2), Ok(Some(3)));
+        assert_eq!(take_tlb_flushes(), [NestedTlbFlush::All]);
+    }
+
+    #[test]
+    fn exhausted_vmids() {
+        let allocator = VmidAllocator::new();
+        for vmid in 1..=3 {
+            assert_eq!(alloc(&allocator, 2), Ok(Some(vmid)));
+        }
+        assert_eq!(alloc(&allocator, 2), Err(AxError::NoMemory));
+        assert_eq!(alloc(&allocator, 2), Err(AxError::NoMemory));
+
+        allocator.free(2);
+        assert_eq!(alloc(&allocator, 2), Ok(Some(2)));
+        assert_eq!(alloc(&allocator, 2), Err(AxError::NoMemory));
+    }
+}
@@ -0,0 +1,356 @@
+//! Tests of the encodings of the nested page table formats in the host-side
+//! software mode, run with `RUSTFLAGS="--cfg axaddrspace_host" cargo test`.
+//! The formats of the other architectures are tested with the `ept`, `stage2`
+//! and `gstage` features.
+#![cfg(axaddrspace_host)]
+
+use axaddrspace::host::{HostHal, take_tlb_flushes};
+use axaddrspace::{
+    AddrSpace, GuestPhysAddr, MappingFlags, NestedFormat, PageFaultOutcome, PageSize,
+};
+use memory_addr::PhysAddr;
+
+const BASE: usize = 0;
+const SIZE: usize = 0x40_0000_0000;
+
+fn gpa(addr: usize) -> GuestPhysAddr {
+    GuestPhysAddr::from(addr)
+}
+
+/// Maps, protects and unmaps pages, checking the flags read back from the
+/// entries of the format `F`.
+fn map_protect_unmap<F: NestedFormat>() {
+    let mut aspace = AddrSpace::<HostHal, F>::new_with_hal(gpa(BASE), SIZE).unwrap();
+    let rw = MappingFlags::READ | MappingFlags::WRITE;
+    let rwx = rw | MappingFlags::EXECUTE;
+
+    aspace
+        .map_linear(
+            gpa(0x20_0000),
+            PhysAddr::from(0x8020_0000),
+            0x20_0000,
+            rwx,
+            None,
+        )
+        .unwrap();
+    let pt = aspace.page_table();
+    assert_eq!(
+        pt.query(gpa(0x20_1000)).unwrap(),
+        (PhysAddr::from(0x8020_1000), rwx, PageSize::Size2M)
+    );
+
+    take_tlb_flushes();
+    aspace.protect(gpa(0x20_0000), 0x1000, rw).unwrap();
+    assert!(!take_tlb_flushes().is_empty());
+    let pt = aspace.page_table();
+    assert_eq!(
+        pt.query(gpa(0x20_0000)).unwrap(),
+        (PhysAddr::from(0x8020_0000), rw, PageSize::Size4K)
+    );
+    assert_eq!(pt.query(gpa(0x20_1000)).unwrap().1, rwx);
+
+    aspace.unmap(gpa(0x20_0000), 0x20_0000).unwrap();
+    assert!(!take_tlb_flushes().is_empty());
+    assert_eq!(aspace.translate(gpa(0x20_1000)), None);
+
+    aspace
+        .map_alloc(gpa(0x1000_0000), 0x2000, rw, false)
+        .unwrap();
+    assert!(matches!(
+        aspace.handle_page_fault(gpa(0x1000_0000), MappingFlags::READ),
+        PageFaultOutcome::Handled
+    ));
+    assert_eq!(aspace.page_table().query(gpa(0x1000_0000)).unwrap().1, rw);
+    assert_eq!(aspace.translate(gpa(0x1000_1000)), None);
+}
+
+#[cfg(any(target_arch = "x86_64", feature = "ept"))]
+mod ept {
+    use super::*;
+    use axaddrspace::{EptConfig, EptFormat, EptMemAttr, EptMemType, EptWalkLength};
+
+    #[test]
+    fn map_protect_unmap() {
+        super::map_protect_unmap::<EptFormat>();
+    }
+
+    #[test]
+    fn eptp_of_config() {
+        let config = EptConfig {
+            walk_length: EptWalkLength::FiveLevel,
+            accessed_dirty: true,
+        };
+        let aspace =
+            AddrSpace::<HostHal, EptFormat>::new_with_config(gpa(BASE), SIZE, config).unwrap();
+        let eptp = aspace.eptp();
+        assert_eq!(eptp & !0xfff, aspace.page_table_root().as_usize() as u64);
+        // Write-back paging structures, a 5-level walk, and A/D flags.
+        assert_eq!(eptp & 0xfff, 6 | (4 << 3) | (1 << 6));
+    }
+
+    #[test]
+    fn memory_types() {
+        let mut aspace = AddrSpace::<HostHal, EptFormat>::new_with_hal(gpa(BASE), SIZE).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let wt = EptMemAttr::new(EptMemType::WriteThrough, true);
+        aspace
+            .map_linear(
+                gpa(0x1000),
+                PhysAddr::from(0xc000_0000),
+                0x1000,
+                rw,
+                Some(wt),
+            )
+            .unwrap();
+        aspace
+            .map_linear(
+                gpa(0x2000),
+                PhysAddr::from(0xc000_1000),
+                0x1000,
+                rw | MappingFlags::DEVICE,
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(aspace.translate_mem_attr(gpa(0x1000)), Some(wt));
+        assert_eq!(
+            aspace.translate_mem_attr(gpa(0x2000)),
+            Some(EptMemAttr::new(EptMemType::Uncached, false))
+        );
+        let flags = aspace.page_table().query(gpa(0x2000)).unwrap().1;
+        assert!(flags.contains(MappingFlags::DEVICE));
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", feature = "stage2"))]
+mod stage2 {
+    use super::*;
+    use axaddrspace::{
+        Cacheability, Stage2Attr, Stage2Config, Stage2Exec, Stage2Format, Stage2MemAttr,
+    };
+
+    /// The addresses of a 39-bit IPA space.
+    const SIZE: usize = 1 << 39;
+
+    #[test]
+    fn map_protect_unmap() {
+        super::map_protect_unmap::<Stage2Format>();
+    }
+
+    #[test]
+    fn device_memory() {
+        let mut aspace = AddrSpace::<HostHal, Stage2Format>::new_with_hal(gpa(BASE), SIZE).unwrap();
+        let device = MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE;
+        aspace
+            .map_linear(
+                gpa(0x1000),
+                PhysAddr::from(0x900_0000),
+                0x1000,
+                device,
+                None,
+            )
+            .unwrap();
+        assert_eq!(aspace.page_table().query(gpa(0x1000)).unwrap().1, device);
+        assert_eq!(
+            aspace.translate_mem_attr(gpa(0x1000)),
+            Some(Stage2MemAttr::DeviceNGnRnE.into())
+        );
+
+        // Device memory is never executable.
+        let exec = device | MappingFlags::EXECUTE;
+        assert!(
+            aspace
+                .map_linear(gpa(0x2000), PhysAddr::from(0x900_1000), 0x1000, exec, None)
+                .is_err()
+        );
+        assert!(
+            aspace
+                .map_linear(
+                    gpa(0x2000),
+                    PhysAddr::from(0x900_1000),
+                    0x1000,
+                    MappingFlags::READ | MappingFlags::EXECUTE,
+                    Some(Stage2MemAttr::DeviceGRE.into()),
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn protect_keeps_memory_attribute() {
+        let mut aspace = AddrSpace::<HostHal, Stage2Format>::new_with_hal(gpa(BASE), SIZE).unwrap();
+        let write_through = Stage2MemAttr::Normal {
+            outer: Cacheability::WriteThrough,
+            inner: Cacheability::WriteBack,
+        };
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace
+            .map_linear(
+                gpa(0x1000),
+                PhysAddr::from(0x8000_0000),
+                0x1000,
+                rw,
+                Some(write_through.into()),
+            )
+            .unwrap();
+        aspace
+            .map_linear(
+                gpa(0x2000),
+                PhysAddr::from(0x8000_1000),
+                0x1000,
+                rw | MappingFlags::UNCACHED,
+                None,
+            )
+            .unwrap();
+
+        aspace
+            .protect(gpa(0x1000), 0x2000, MappingFlags::READ)
+            .unwrap();
+        assert_eq!(
+            aspace.translate_mem_attr(gpa(0x1000)),
+            Some(write_through.into())
+        );
+        assert_eq!(
+            aspace.translate_mem_attr(gpa(0x2000)),
+            Some(Stage2MemAttr::NON_CACHEABLE.into())
+        );
+    }
+
+    #[test]
+    fn protect_keeps_execute_levels() {
+        let mut aspace = AddrSpace::<HostHal, Stage2Format>::new_with_hal(gpa(BASE), SIZE).unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        let rx = MappingFlags::READ | MappingFlags::EXECUTE;
+        let el0 = Stage2Attr::new(Stage2MemAttr::WRITE_BACK, Stage2Exec::El0);
+        aspace
+            .map_linear(
+                gpa(0x20_0000),
+                PhysAddr::from(0x8020_0000),
+                0x20_0000,
+                rw | MappingFlags::EXECUTE,
+                Some(el0),
+            )
+            .unwrap();
+        assert_eq!(aspace.translate_mem_attr(gpa(0x20_0000)), Some(el0));
+
+        // The 2M block is split, and all its pages stay executable at EL0 only.
+        aspace.protect(gpa(0x20_0000), 0x1000, rx).unwrap();
+        assert_eq!(
+            aspace.page_table().query(gpa(0x20_0000)).unwrap(),
+            (PhysAddr::from(0x8020_0000), rx, PageSize::Size4K)
+        );
+        assert_eq!(aspace.translate_mem_attr(gpa(0x20_0000)), Some(el0));
+        assert_eq!(aspace.translate_mem_attr(gpa(0x3f_f000)), Some(el0));
+
+        // The pages become executable again at EL0 only.
+        aspace.protect(gpa(0x20_0000), 0x20_0000, rw).unwrap();
+        assert_eq!(
+            aspace.translate_mem_attr(gpa(0x20_0000)),
+            Some(Stage2MemAttr::WRITE_BACK.into())
+        );
+        aspace.protect(gpa(0x20_0000), 0x20_0000, rx).unwrap();
+        assert_eq!(aspace.translate_mem_attr(gpa(0x20_0000)), Some(el0));
+        assert_eq!(aspace.translate_mem_attr(gpa(0x3f_f000)), Some(el0));
+    }
+
+    #[test]
+    fn two_level_walk() {
+        // A 34-bit IPA space walked from level 2, with 16 concatenated root
+        // tables and no level for 1G blocks.
+        let config = Stage2Config::new(34, 2).unwrap();
+        let mut aspace =
+            AddrSpace::<HostHal, Stage2Format>::new_with_config(gpa(BASE), 1 << 34, config)
+                .unwrap();
+        let rw = MappingFlags::READ | MappingFlags::WRITE;
+        aspace
+            .map_linear(
+                gpa(0x3_c000_0000),
+                PhysAddr::from(0x4000_0000),
+                0x4000_0000,
+                rw,
+                None,
+            )
+            .unwrap();
+        let pt = aspace.page_table();
+        assert_eq!(
+            pt.query(gpa(0x3_c020_1234)).unwrap(),
+            (PhysAddr::from(0x4020_1234), rw, PageSize::Size2M)
+        );
+        assert_eq!(
+            pt.query(gpa(0x3_ffff_f000)).unwrap(),
+            (PhysAddr::from(0x7fff_f000), rw, PageSize::Size2M)
+        );
+
+        aspace
+            .protect(gpa(0x3_c000_0000), 0x1000, MappingFlags::READ)
+            .unwrap();
+        assert_eq!(
+            aspace.page_table().query(gpa(0x3_c000_0000)).unwrap().2,
+            PageSize::Size4K
+        );
+        aspace.unmap(gpa(0x3_c000_0000), 0x4000_0000).unwrap();
+        assert_eq!(aspace.translate(gpa(0x3_c020_0000)), None);
+    }
+
+    #[test]
+    fn vttbr_selects_the_root() {
+        let aspace = AddrSpace::<HostHal, Stage2Format>::new_with_hal(gpa(BASE), SIZE).unwrap();
+        let vmid = aspace.vmid().unwrap_or(0) as u64;
+        assert_eq!(
+            aspace.vttbr(),
+            aspace.page_table_root().as_usize() as u64 | (vmid << 48)
+        );
+    }
+}
+
+#[cfg(any(target_arch = "riscv32", target_arch = "riscv64", feature = "gstage"))]
+mod gstage {
+    use super::*;
+    use axaddrspace::{GStageFormat, GStageMode};
+
+    #[test]
+    fn map_protect_unmap() {
+        super::map_protect_unmap::<GStageFormat>();
+    }
+
+    #[test]
+    fn root_table_is_contiguous() {
+        // The 16K root table cannot be allocated without the HAL.
+        assert!(AddrSpace::<HostHal, GStageFormat>::new_empty(gpa(BASE), 1 << 41).is_err());
+
+        // The Sv39x4 root table is made of four concatenated 4K tables, the
+        // last of which translates the top 512G of the 41-bit space.
+        let mut aspace =
+            AddrSpace::<HostHal, GStageFormat>::new_with_hal(gpa(BASE), 1 << 41).unwrap();
+        let root = aspace.page_table_root().as_usize();
+        assert_eq!(root % 0x4000, 0);
+        aspace
+            .map_linear(
+                gpa(0x1ff_c000_0000),
+                PhysAddr::from(0x8000_0000),
+                0x1000,
+                MappingFlags::READ,
+                None,
+            )
+            .unwrap();
+        // SAFETY: the host physical addresses of `HostHal` are virtual ones.
+        let last_entry = unsafe { ((root + 0x3ff8) as *const u64).read() };
+        assert_ne!(last_entry, 0);
+        let vmid = aspace.vmid().unwrap_or(0) as u64;
+        assert_eq!(
+            aspace.hgatp(),
+            (8 << 60) | (vmid << 44) | (root as u64 >> 12)
+        );
+
+        let aspace = AddrSpace::<HostHal, GStageFormat>::new_with_config(
+            gpa(BASE),
+            1 << 50,
+            GStageMode::Sv48x4,
+        )
+        .unwrap();
+        let root = aspace.page_table_root().as_usize() as u64;
+        assert_eq!(root % 0x4000, 0);
+        let vmid = aspace.vmid().unwrap_or(0) as u64;
+        assert_eq!(aspace.hgatp(), (9 << 60) | (vmid << 44) | (root >> 12));
+    }
+}
@@ -0,0 +1,181 @@
+//! Tests of the address spaces in the host-side software mode, run with
+//! `RUSTFLAGS="--cfg axaddrspace_host" cargo test`.
+#![cfg(axaddrspace_host)]
+
+use axaddrspace::host::{HostHal, take_tlb_flushes};
+use axaddrspace::{AddrSpace, GuestPhysAddr, MappingFlags, PageFaultOutcome};
+use memory_addr::PhysAddr;
+
+const BASE: usize = 0;
+const SIZE: usize = 0x1_0000_0000;
+
+fn new_addr_space() -> AddrSpace<HostHal> {
+    AddrSpace::new_with_hal(GuestPhysAddr::from(BASE), SIZE).unwrap()
+}
+
+fn gpa(addr: usize) -> GuestPhysAddr {
+    GuestPhysAddr::from(addr)
+}
+
+#[test]
+fn map_linear_and_translate() {
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_linear(
+            gpa(0x40_0000),
+            PhysAddr::from(0x8000_0000),
+            0x40_0000,
+            flags,
+            None,
+        )
+        .unwrap();
+
+    assert_eq!(
+        aspace.translate(gpa(0x40_1234)),
+        Some(PhysAddr::from(0x8000_1234))
+    );
+    assert_eq!(
+        aspace.translate(gpa(0x7f_ffff)),
+        Some(PhysAddr::from(0x803f_ffff))
+    );
+    assert_eq!(aspace.translate(gpa(0x80_0000)), None);
+    // Overlapping mappings are rejected.
+    assert!(
+        aspace
+            .map_linear(
+                gpa(0x60_0000),
+                PhysAddr::from(0x9000_0000),
+                0x1000,
+                flags,
+                None
+            )
+            .is_err()
+    );
+}
+
+#[test]
+fn lazy_alloc_page_fault() {
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_alloc(gpa(0x1000_0000), 0x4000, flags, false)
+        .unwrap();
+    assert_eq!(aspace.translate(gpa(0x1000_1000)), None);
+
+    assert!(matches!(
+        aspace.handle_page_fault(gpa(0x1000_1000), MappingFlags::WRITE),
+        PageFaultOutcome::Handled
+    ));
+    assert!(aspace.translate(gpa(0x1000_1000)).is_some());
+    assert_eq!(aspace.translate(gpa(0x1000_2000)), None);
+
+    assert!(matches!(
+        aspace.handle_page_fault(gpa(0x1000_1000), MappingFlags::EXECUTE),
+        PageFaultOutcome::PermissionDenied { .. }
+    ));
+    assert!(matches!(
+        aspace.handle_page_fault(gpa(0x2000_0000), MappingFlags::READ),
+        PageFaultOutcome::Unmapped
+    ));
+}
+
+#[test]
+fn read_write_populated_memory() {
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_alloc(gpa(0x1000_0000), 0x2000, flags, true)
+        .unwrap();
+
+    // The buffer crosses the boundary between the two pages.
+    let data = [0x5au8; 0x100];
+    aspace.write_bytes(gpa(0x1000_0f80), &data).unwrap();
+    let mut buf = [0u8; 0x100];
+    aspace.read_bytes(gpa(0x1000_0f80), &mut buf).unwrap();
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn unmap_flushes_tlb() {
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_alloc(gpa(0x1000_0000), 0x4000, flags, true)
+        .unwrap();
+    take_tlb_flushes();
+
+    aspace.unmap(gpa(0x1000_1000), 0x1000).unwrap();
+    assert!(!take_tlb_flushes().is_empty());
+    assert_eq!(aspace.translate(gpa(0x1000_1000)), None);
+    assert!(aspace.translate(gpa(0x1000_2000)).is_some());
+    assert!(matches!(
+        aspace.handle_page_fault(gpa(0x1000_1000), MappingFlags::READ),
+        PageFaultOutcome::Unmapped
+    ));
+}
+
+#[test]
+fn unmap_splits_huge_pages() {
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_linear(
+            gpa(0x20_0000),
+            PhysAddr::from(0x8020_0000),
+            0x20_0000,
+            flags,
+            None,
+        )
+        .unwrap();
+
+    aspace.unmap(gpa(0x30_0000), 0x1000).unwrap();
+    assert_eq!(aspace.translate(gpa(0x30_0000)), None);
+    assert_eq!(
+        aspace.translate(gpa(0x2f_f000)),
+        Some(PhysAddr::from(0x802f_f000))
+    );
+    assert_eq!(
+        aspace.translate(gpa(0x30_1000)),
+        Some(PhysAddr::from(0x8030_1000))
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn protect_keeps_ept_memory_type() {
+    use axaddrspace::EptMemAttr;
+
+    let mut aspace = new_addr_space();
+    let flags = MappingFlags::READ | MappingFlags::WRITE;
+    aspace
+        .map_linear(
+            gpa(0x40_0000),
+            PhysAddr::from(0xc000_0000),
+            0x1000,
+            flags,
+            Some(EptMemAttr::WRITE_COMBINING),
+        )
+        .unwrap();
+    aspace
+        .map_linear(
+            gpa(0x50_0000),
+            PhysAddr::from(0xc010_0000),
+            0x1000,
+            flags,
+            None,
+        )
+        .unwrap();
+
+    aspace
+        .protect(gpa(0x40_0000), 0x1000, MappingFlags::READ)
+        .unwrap();
+    assert_eq!(
+        aspace.translate_mem_attr(gpa(0x40_0000)),
+        Some(EptMemAttr::WRITE_COMBINING)
+    );
+    assert_eq!(
+        aspace.translate_mem_attr(gpa(0x50_0000)),
+        Some(EptMemAttr::WRITE_BACK)
+    );
+}